@@ -0,0 +1,33 @@
+use criterion::{criterion_group, criterion_main, Criterion, ParameterizedBenchmark, Throughput};
+use oni::crypto::{ChaCha20, Poly1305, Aead, ChaCha20Poly1305, keygen};
+
+const SIZES: &[usize] = &[64, 256, 512, 1200];
+
+fn chacha20(c: &mut Criterion) {
+    let key = keygen();
+    let nonce = [0u8; 12];
+    c.bench("chacha20", ParameterizedBenchmark::new("ietf", move |b, &size| {
+        let mut m = vec![0u8; size];
+        b.iter(|| ChaCha20::ietf(&mut m, &nonce, 1, &key))
+    }, SIZES.to_vec()).throughput(|&size| Throughput::Bytes(size as u32)));
+}
+
+fn poly1305(c: &mut Criterion) {
+    let key = keygen();
+    c.bench("poly1305", ParameterizedBenchmark::new("sum", move |b, &size| {
+        let m = vec![0u8; size];
+        b.iter(|| Poly1305::sum(&m, &key))
+    }, SIZES.to_vec()).throughput(|&size| Throughput::Bytes(size as u32)));
+}
+
+fn aead(c: &mut Criterion) {
+    let key = keygen();
+    let nonce = [0u8; 12];
+    c.bench("chacha20poly1305", ParameterizedBenchmark::new("seal", move |b, &size| {
+        let mut m = vec![0u8; size];
+        b.iter(|| ChaCha20Poly1305::seal(&mut m, Some(&[0u8; 13]), &nonce, &key))
+    }, SIZES.to_vec()).throughput(|&size| Throughput::Bytes(size as u32)));
+}
+
+criterion_group!(benches, chacha20, poly1305, aead);
+criterion_main!(benches);
@@ -38,6 +38,7 @@ pub fn new_client(
     world.add_resource(Sequence::<u16>::default());
 
     world.add_resource(socket);
+    world.add_resource(oni::Fragments::default());
     world.add_resource(server);
     world.add_resource(Reconciliation::new());
     world.add_resource(NetNode::new(1..0xFF00));
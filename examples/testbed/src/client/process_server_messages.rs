@@ -19,6 +19,7 @@ pub struct ProcessServerMessagesData<'a> {
     entities: Entities<'a>,
     reconciliation: WriteExpect<'a, Reconciliation>,
     socket: WriteExpect<'a, oni::Client<Socket>>,
+    fragments: WriteExpect<'a, oni::Fragments>,
     actors: WriteStorage<'a, Actor>,
     states: WriteStorage<'a, StateBuffer>,
     lazy: ReadExpect<'a, LazyUpdate>,
@@ -36,7 +37,7 @@ impl<'a> System<'a> for ProcessServerMessages {
         decelerator!();
 
         let now = Instant::now();
-        while let Some(message) = data.socket.recv_server() {
+        while let Some(message) = data.socket.recv_server(&mut data.fragments) {
             match message {
                 Server::Snapshot { ack, frame_seq, states } => {
                     let last_processed_input = ack.0;
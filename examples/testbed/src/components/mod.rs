@@ -15,7 +15,8 @@ use specs::prelude::*;
 
 #[derive(Component)]
 #[storage(DenseVecStorage)]
-pub struct Conn(pub oni::Connection);
+/// Connection of a client and fragmenter of its snapshots.
+pub struct Conn(pub oni::Connection, pub oni::Fragments);
 
 #[derive(Component, Default)]
 #[storage(DenseVecStorage)]
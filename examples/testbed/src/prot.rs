@@ -1,13 +1,14 @@
 use bincode::{serialize, deserialize};
 use nalgebra::{wrap, UnitComplex, Point2, Vector2};
 use oni_reliable::Sequence;
-use crate::components::Acks;
+use crate::components::{Acks, Conn};
 use crate::consts::*;
 use serde::{
     Serialize, Deserialize,
     Serializer, Deserializer,
 };
 use arrayvec::ArrayVec;
+use std::time::Instant;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Client {
@@ -179,12 +180,17 @@ impl<'de> Deserialize<'de> for Position16 {
 
 pub trait ClientEndpoint {
     fn send_client(&mut self, msg: Client);
-    fn recv_server(&mut self) -> Option<Server>;
+    /// Reassembles the next server message from fragments.
+    fn recv_server(&mut self, fragments: &mut oni::Fragments) -> Option<Server>;
 }
 
 pub trait ServerEndpoint {
-    fn recv_client(&self) -> Option<Client>;
-    fn send_server(&self, msg: Server);
+    /// Splits the message into fragments that fit the path MTU.
+    fn send_server(&mut self, msg: Server);
+}
+
+pub fn deserialize_client(buf: &[u8]) -> Client {
+    deserialize(buf).unwrap()
 }
 
 impl ClientEndpoint for oni::Client<oni::SimulatedSocket> {
@@ -192,23 +198,30 @@ impl ClientEndpoint for oni::Client<oni::SimulatedSocket> {
         let mut buf: Vec<u8> = serialize(&msg).unwrap();
         self.send(&mut buf).map(|_| ()).unwrap();
     }
-    fn recv_server(&mut self) -> Option<Server> {
-        let (len, buf) = self.recv()?;
-        Some(deserialize(&buf[..len]).unwrap())
+    fn recv_server(&mut self, fragments: &mut oni::Fragments) -> Option<Server> {
+        loop {
+            if let Some(buf) = fragments.recv() {
+                return Some(deserialize(&buf).unwrap());
+            }
+            let buf = self.recv()?;
+            if let Err(err) = fragments.read(Instant::now(), &buf) {
+                warn!("drop invalid fragment: {:?}", err);
+            }
+        }
     }
 }
 
-impl ServerEndpoint for oni::Connection {
-    fn recv_client(&self) -> Option<Client> {
-        let mut buf = [0u8; oni::protocol::MAX_PAYLOAD];
-        match self.recv(&mut buf) {
-            Ok(0) => None,
-            Err(_) => None,
-            Ok(len) => Some(deserialize(&buf[..len as usize]).unwrap()),
-        }
-    }
-    fn send_server(&self, msg: Server) {
+impl ServerEndpoint for Conn {
+    fn send_server(&mut self, msg: Server) {
         let buf: Vec<u8> = serialize(&msg).unwrap();
-        self.send(&buf).map(|_| ()).unwrap();
+        let Conn(conn, fragments) = self;
+        fragments.set_payload_size(conn.usable_payload());
+        let sent = fragments.send(&buf, |fragment| {
+            // fails only if the connection is already closed
+            let _ = conn.send(fragment);
+        });
+        if let Err(err) = sent {
+            warn!("drop snapshot of {} bytes: {:?}", buf.len(), err);
+        }
     }
 }
@@ -90,6 +90,7 @@ pub struct DDOSer {
     turn_speed: f32,
 
     socket: oni::Client<oni::SimulatedSocket>,
+    fragments: oni::Fragments,
 
     input_sequence: oni_reliable::Sequence<u8>,
     input_sender: InputSender,
@@ -126,6 +127,7 @@ impl DDOSer {
         let s: f32 = rand::random();
         Self {
             socket,
+            fragments: oni::Fragments::default(),
 
             position: Point2::new(0.0, 0.0),
 
@@ -149,7 +151,7 @@ impl DDOSer {
             return;
         }
 
-        while let Some(message) = self.socket.recv_server() {
+        while let Some(message) = self.socket.recv_server(&mut self.fragments) {
             match message {
                 Server::Snapshot { frame_seq, states, .. } => {
                     self.last_frame = Some(frame_seq);
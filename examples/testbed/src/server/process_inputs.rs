@@ -45,6 +45,7 @@ impl<'a> System<'a> for ProcessInputs {
 
         let now = Instant::now();
 
+        let mut messages = Vec::new();
         {
             oni_trace::scope![update sock];
 
@@ -57,41 +58,46 @@ impl<'a> System<'a> for ProcessInputs {
             let mut connections = &mut data.conn;
             let mut marker = &mut data.marker;
 
-        socket.update(|conn, _user_data| {
-            //let id = conn.id();
-            let addr = conn.addr();
-            //println!("connected[{}] {:?}", id, addr);
-
-            let e = entities.build_entity()
-                .with(InputBuffer::new(), &mut inputs)
-                .with(StateBuffer::new(), &mut states)
-                .with(Conn(conn), &mut connections)
-                .with(LastSequence::default(), &mut seq)
-                .marked(&mut marker, &mut node)
-                .build();
-            node.by_addr.insert(addr, e);
-            debug!("register client: {} {:?}", addr, e);
-
-            /*
-            let user = unsafe { std::ffi::CStr::from_ptr(user.as_ptr() as *const _) };
-            connected.push(c);
-            */
-        });
-
+            socket.update();
+            while let Some(event) = socket.poll_event() {
+                match event {
+                    oni::ServerEvent::Accepted { connection: conn, .. } => {
+                        let addr = conn.addr();
+
+                        let e = entities.build_entity()
+                            .with(InputBuffer::new(), &mut inputs)
+                            .with(StateBuffer::new(), &mut states)
+                            .with(Conn(conn, oni::Fragments::default()), &mut connections)
+                            .with(LastSequence::default(), &mut seq)
+                            .marked(&mut marker, &mut node)
+                            .build();
+                        node.by_addr.insert(addr, e);
+                        debug!("register client: {} {:?}", addr, e);
+                    }
+                    oni::ServerEvent::Payload { addr, data, .. } => {
+                        messages.push((addr, deserialize_client(&data)));
+                    }
+                    oni::ServerEvent::Migrated { from, to, .. } => {
+                        if let Some(e) = node.by_addr.remove(&from) {
+                            node.by_addr.insert(to, e);
+                        }
+                    }
+                    oni::ServerEvent::Rejected { addr, error } => {
+                        debug!("rejected {}: {}", addr, error.as_str());
+                    }
+                    _ => (),
+                }
+            }
         }
 
         // Process all pending messages from clients.
-        let conns = &mut data.conn;
         let node = &mut data.node;
         let actors = &mut data.actors;
         let seq = &data.seq;
         let entities = &*data.entities;
         let states = &data.states;
 
-        for client in conns.join() {
-            let addr = client.0.addr();
-
-        while let Some(message) = client.0.recv_client() {
+        for (addr, message) in messages {
             match message {
             Client::Input(message) => {
                 let by_addr = node.by_addr.get(&addr).cloned();
@@ -157,7 +163,6 @@ impl<'a> System<'a> for ProcessInputs {
             }
             }
         }
-        }
     }
 }
 
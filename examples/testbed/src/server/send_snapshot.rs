@@ -52,7 +52,7 @@ impl<'a> System<'a> for SendWorldState {
                 .collect();
 
             let current_frame = seq.0.fetch_next();
-            conn.0.send_server(Server::Snapshot {
+            conn.send_server(Server::Snapshot {
                 frame_seq: current_frame,
                 states,
                 ack: lpi.generate_ack(),
@@ -139,23 +139,15 @@ impl<T, S, L> Buffer<T, S, L>
     }
 
     pub fn find_or_with<F: FnOnce() -> T>(&mut self, seq: Sequence<S>, f: F) -> &T {
-        let index = Self::seq2index(seq);
-        match unsafe { self.entries.get_unchecked_mut(index) } {
-            Some((s, e)) if *s == seq => e,
-            e => {
-                replace(e, Some((seq, f())));
-                e.as_ref()
-                    .map(|(_, e)| e)
-                    .unwrap_or_else(|| unsafe { unreachable_unchecked() })
-            }
-        }
+        self.create_if(seq, f);
+        self.find(seq).unwrap_or_else(|| unsafe { unreachable_unchecked() })
     }
 
     pub fn create_if<F: FnOnce() -> T>(&mut self, seq: Sequence<S>, f: F) {
         let index = Self::seq2index(seq);
         match unsafe { self.entries.get_unchecked_mut(index) } {
             Some(e) if e.0 == seq => (),
-            e => { replace(e, Some((seq, f()))); }
+            e => { *e = Some((seq, f())); }
         }
     }
 
@@ -5,6 +5,7 @@ mod sequence;
 mod bitset;
 
 pub mod sequenced;
+pub mod reliable;
 
 pub use self::{
     buffer::{Buffer, Entry},
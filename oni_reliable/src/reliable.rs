@@ -0,0 +1,330 @@
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use std::time::{Instant, Duration};
+use super::{Buffer, Sequence, SequenceOps};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Message is larger than `Reliable::MAX_SEND`.
+    TooLarge,
+    /// Too many unacked messages.
+    Full,
+    /// Packet is too small or malformed.
+    Invalid,
+    /// Packet is too old or already received.
+    Stale(Sequence<u16>),
+}
+
+struct Message {
+    data: Vec<u8>,
+    last_send: Option<Instant>,
+}
+
+/// Reliable ordered channel.
+///
+/// Packet format:
+///
+/// ```txt
+/// [sequence] u16
+/// [ack] u16
+/// [ack bits] u32
+/// [message id] u16, [length] u16, [data] - zero or more messages
+/// ```
+///
+/// Every packet acknowledges last 32 received packets.
+/// Messages are resent until the packet which contains them is acked.
+/// Packets with messages beyond the receive window are not acked.
+pub struct Reliable {
+    sequence: Sequence<u16>,
+    sent: Buffer<Vec<Sequence<u16>>>,
+    received: Buffer<()>,
+
+    send_queue: Buffer<Message>,
+    next_send: Sequence<u16>,
+    oldest_unacked: Sequence<u16>,
+
+    recv_queue: Buffer<Vec<u8>>,
+    next_recv: Sequence<u16>,
+
+    resend: Duration,
+}
+
+impl Default for Reliable {
+    fn default() -> Self {
+        Self::new(Self::RESEND)
+    }
+}
+
+impl Reliable {
+    pub const HEADER: usize = 8;
+    pub const MESSAGE_HEADER: usize = 4;
+
+    pub const MAX_SEND: usize = 1024;
+    pub const MAX_MESSAGES: usize = 64;
+
+    pub const RESEND: Duration = Duration::from_millis(100);
+
+    pub fn new(resend: Duration) -> Self {
+        Self {
+            sequence: Sequence::default(),
+            sent: Buffer::default(),
+            received: Buffer::default(),
+
+            send_queue: Buffer::default(),
+            next_send: Sequence::default(),
+            oldest_unacked: Sequence::default(),
+
+            recv_queue: Buffer::default(),
+            next_recv: Sequence::default(),
+
+            resend,
+        }
+    }
+
+    /// Number of sent messages which are not acked yet.
+    pub fn pending(&self) -> usize {
+        let next: u16 = self.next_send.into();
+        let oldest: u16 = self.oldest_unacked.into();
+        next.wrapping_sub(oldest) as usize
+    }
+
+    /// Queues the message for sending.
+    pub fn send(&mut self, message: &[u8]) -> Result<(), Error> {
+        if message.len() > Self::MAX_SEND {
+            return Err(Error::TooLarge);
+        }
+        if self.pending() >= self.send_queue.capacity() {
+            return Err(Error::Full);
+        }
+        let id = self.next_send.fetch_next();
+        self.send_queue.insert(id, Message {
+            data: message.to_vec(),
+            last_send: None,
+        });
+        Ok(())
+    }
+
+    /// Returns the next message in order.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        if !self.recv_queue.exists(self.next_recv) {
+            return None;
+        }
+        let (_, message) = self.recv_queue.remove(self.next_recv)?;
+        self.next_recv = self.next_recv.next();
+        Some(message)
+    }
+
+    /// Writes the next packet into `buf` and returns its length.
+    ///
+    /// The packet always contains acks,
+    /// so it should be sent even if there are no messages.
+    pub fn write(&mut self, now: Instant, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < Self::HEADER {
+            return Err(Error::Invalid);
+        }
+
+        let seq = self.sequence.fetch_next();
+        let (ack, ack_bits) = self.received.generate_ack_bits_u32();
+
+        let start_len = buf.len();
+        let mut p = &mut buf[..];
+        p.write_u16::<LE>(seq.into()).unwrap();
+        p.write_u16::<LE>(ack).unwrap();
+        p.write_u32::<LE>(ack_bits).unwrap();
+
+        let resend = self.resend;
+        let mut ids = Vec::new();
+        let mut id = self.oldest_unacked;
+        while id != self.next_send && ids.len() < Self::MAX_MESSAGES {
+            if let Some(message) = self.send_queue.find_mut(id) {
+                let len = message.data.len();
+                let ready = message.last_send.map_or(true, |t| t + resend <= now);
+                if ready && Self::MESSAGE_HEADER + len <= p.len() {
+                    p.write_u16::<LE>(id.into()).unwrap();
+                    p.write_u16::<LE>(len as u16).unwrap();
+                    p[..len].copy_from_slice(&message.data);
+                    p = &mut p[len..];
+
+                    message.last_send = Some(now);
+                    ids.push(id);
+                }
+            }
+            id = id.next();
+        }
+
+        if !ids.is_empty() {
+            self.sent.insert(seq, ids);
+        }
+
+        Ok(start_len - p.len())
+    }
+
+    /// Processes received packet.
+    pub fn read(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        if buf.len() < Self::HEADER {
+            return Err(Error::Invalid);
+        }
+
+        let seq = Sequence::from(buf.read_u16::<LE>().unwrap());
+        let ack = Sequence::from(buf.read_u16::<LE>().unwrap());
+        let ack_bits = buf.read_u32::<LE>().unwrap();
+
+        // check the whole packet before changing any state,
+        // otherwise an invalid packet would be acked with partially queued messages
+        let mut messages = Vec::new();
+        while !buf.is_empty() {
+            let id = Sequence::from(buf.read_u16::<LE>().map_err(|_| Error::Invalid)?);
+            let len = buf.read_u16::<LE>().map_err(|_| Error::Invalid)? as usize;
+            if len > buf.len() {
+                return Err(Error::Invalid);
+            }
+            let (data, tail) = buf.split_at(len);
+            buf = tail;
+            messages.push((id, data));
+        }
+
+        if self.received.exists(seq) || !self.received.can_insert(seq) {
+            return Err(Error::Stale(seq));
+        }
+
+        // the packet with messages beyond the receive window isn't acked,
+        // so the sender resends them after `recv` frees the space
+        let window_end = self.next_recv.next_n(self.recv_queue.capacity());
+        if messages.iter().all(|&(id, _)| id < window_end) {
+            self.received.insert(seq, ());
+        }
+
+        for i in 0..32 {
+            if ack_bits & (1 << i) != 0 {
+                self.process_ack(ack.prev_n(i));
+            }
+        }
+        while self.oldest_unacked != self.next_send && !self.send_queue.exists(self.oldest_unacked) {
+            self.oldest_unacked = self.oldest_unacked.next();
+        }
+
+        for (id, data) in messages {
+            if id >= self.next_recv && id < window_end && !self.recv_queue.exists(id) {
+                self.recv_queue.insert(id, data.to_vec());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_ack(&mut self, seq: Sequence<u16>) {
+        if !self.sent.exists(seq) {
+            return;
+        }
+        if let Some((_, ids)) = self.sent.remove(seq) {
+            for id in ids {
+                if self.send_queue.exists(id) {
+                    self.send_queue.remove(id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn exchange(from: &mut Reliable, to: &mut Reliable, now: Instant, lost: bool) {
+    let mut buf = [0u8; 1200];
+    let len = from.write(now, &mut buf).unwrap();
+    if !lost {
+        to.read(&buf[..len]).unwrap();
+    }
+}
+
+#[test]
+fn reliable_ordered() {
+    let mut a = Reliable::new(Duration::from_millis(10));
+    let mut b = Reliable::default();
+
+    let mut now = Instant::now();
+    let messages: Vec<Vec<u8>> = (0..200u32)
+        .map(|i| vec![i as u8; (i as usize * 7) % 300])
+        .collect();
+
+    let mut sent = 0;
+    let mut received = Vec::new();
+    for step in 0..10_000 {
+        while sent < messages.len() && a.send(&messages[sent]).is_ok() {
+            sent += 1;
+        }
+
+        // drop every third packet from a to b and every fifth from b to a
+        exchange(&mut a, &mut b, now, step % 3 == 0);
+        exchange(&mut b, &mut a, now, step % 5 == 0);
+
+        while let Some(m) = b.recv() {
+            received.push(m);
+        }
+        if received.len() == messages.len() && a.pending() == 0 {
+            break;
+        }
+        now += Duration::from_millis(5);
+    }
+
+    assert_eq!(received, messages);
+    assert_eq!(a.pending(), 0);
+}
+
+#[test]
+fn reliable_errors() {
+    let mut a = Reliable::default();
+    assert_eq!(a.send(&[0; Reliable::MAX_SEND + 1]), Err(Error::TooLarge));
+    for _ in 0..a.send_queue.capacity() {
+        a.send(&[1, 2, 3]).unwrap();
+    }
+    assert_eq!(a.send(&[1, 2, 3]), Err(Error::Full));
+
+    let mut b = Reliable::default();
+    let mut buf = [0u8; 1200];
+    let len = a.write(Instant::now(), &mut buf).unwrap();
+    // truncated last message, nothing is received or acked
+    assert_eq!(b.read(&buf[..len - 1]), Err(Error::Invalid));
+    assert_eq!(b.recv(), None);
+    b.read(&buf[..len]).unwrap();
+    assert_eq!(b.read(&buf[..len]), Err(Error::Stale(0.into())));
+    assert_eq!(b.read(&buf[..4]), Err(Error::Invalid));
+    assert_eq!(b.recv(), Some(vec![1, 2, 3]));
+}
+
+#[test]
+fn reliable_window() {
+    let mut a = Reliable::new(Duration::from_millis(10));
+    let mut b = Reliable::default();
+
+    let mut now = Instant::now();
+    let count = a.send_queue.capacity() * 2;
+    let mut sent = 0;
+
+    // the receiver doesn't read until the sender is past its window
+    for _ in 0..100 {
+        while sent < count && a.send(&[sent as u8]).is_ok() {
+            sent += 1;
+        }
+        exchange(&mut a, &mut b, now, false);
+        exchange(&mut b, &mut a, now, false);
+        now += Duration::from_millis(5);
+    }
+    assert!(sent > b.recv_queue.capacity());
+
+    let mut received = Vec::new();
+    for _ in 0..1000 {
+        while sent < count && a.send(&[sent as u8]).is_ok() {
+            sent += 1;
+        }
+        exchange(&mut a, &mut b, now, false);
+        exchange(&mut b, &mut a, now, false);
+        while let Some(m) = b.recv() {
+            received.push(m);
+        }
+        if received.len() == count && a.pending() == 0 {
+            break;
+        }
+        now += Duration::from_millis(5);
+    }
+
+    let messages: Vec<Vec<u8>> = (0..count).map(|i| vec![i as u8]).collect();
+    assert_eq!(received, messages);
+}
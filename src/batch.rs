@@ -0,0 +1,308 @@
+//! Batched datagram I/O.
+//!
+//! On Linux `UdpSocket` uses `recvmmsg`/`sendmmsg`,
+//! other sockets fall back to a `recv_from`/`send_to` loop.
+
+use std::{
+    io,
+    net::{SocketAddr, Ipv4Addr},
+};
+use crate::{Socket, protocol::MTU};
+
+/// Maximum number of datagrams in a single batch.
+pub const BATCH: usize = 32;
+
+/// Buffers for `Socket::recv_batch`.
+pub struct RecvBatch {
+    bufs: Vec<[u8; MTU]>,
+    meta: Vec<(usize, SocketAddr)>,
+}
+
+impl Default for RecvBatch {
+    fn default() -> Self {
+        Self { bufs: Vec::new(), meta: Vec::new() }
+    }
+}
+
+impl RecvBatch {
+    pub fn new() -> Self {
+        Self {
+            bufs: (0..BATCH).map(|_| [0u8; MTU]).collect(),
+            meta: vec![(0, (Ipv4Addr::UNSPECIFIED, 0).into()); BATCH],
+        }
+    }
+
+    pub fn capacity(&self) -> usize { self.bufs.len() }
+
+    /// Receives datagrams, returns their number.
+    pub fn recv<S: Socket>(&mut self, socket: &S) -> io::Result<usize> {
+        socket.recv_batch(&mut self.bufs, &mut self.meta)
+    }
+
+    /// Returns buffer, length and origin of the received datagram.
+    pub fn get_mut(&mut self, i: usize) -> (&mut [u8; MTU], usize, SocketAddr) {
+        let (len, addr) = self.meta[i];
+        (&mut self.bufs[i], len, addr)
+    }
+}
+
+/// Queue of outgoing datagrams for `Socket::send_batch`.
+///
+/// The queue is flushed when it's full, call `flush` to send the rest.
+pub struct SendBatch {
+    bufs: Vec<[u8; MTU]>,
+    meta: Vec<(usize, SocketAddr)>,
+}
+
+impl Default for SendBatch {
+    fn default() -> Self {
+        Self { bufs: Vec::new(), meta: Vec::new() }
+    }
+}
+
+impl SendBatch {
+    pub fn new() -> Self {
+        Self {
+            bufs: (0..BATCH).map(|_| [0u8; MTU]).collect(),
+            meta: Vec::with_capacity(BATCH),
+        }
+    }
+
+    /// Queues a copy of `buf`.
+    pub fn push<S: Socket>(&mut self, socket: &S, buf: &[u8], addr: SocketAddr) {
+        self.encode(socket, addr, |dst| {
+            dst[..buf.len()].copy_from_slice(buf);
+            buf.len()
+        })
+    }
+
+    /// Queues datagram written by `f`, it returns the length of datagram.
+    pub fn encode<S, F>(&mut self, socket: &S, addr: SocketAddr, f: F)
+        where S: Socket, F: FnOnce(&mut [u8; MTU]) -> usize
+    {
+        if self.meta.len() == self.bufs.len() {
+            self.flush(socket);
+        }
+        let len = f(&mut self.bufs[self.meta.len()]);
+        self.meta.push((len, addr));
+    }
+
+    /// Sends all queued datagrams.
+    ///
+    /// Datagrams which can't be sent are dropped.
+    pub fn flush<S: Socket>(&mut self, socket: &S) {
+        let count = self.meta.len();
+        let mut start = 0;
+        while start < count {
+            match socket.send_batch(&self.bufs[start..count], &self.meta[start..]) {
+                Ok(sent) => start += sent.max(1),
+                Err(_) => start += 1,
+            }
+        }
+        self.meta.clear();
+    }
+}
+
+/// Default implementation of `Socket::recv_batch`.
+pub fn recv_loop<S: Socket>(socket: &S, bufs: &mut [[u8; MTU]], meta: &mut [(usize, SocketAddr)]) -> io::Result<usize> {
+    let mut count = 0;
+    for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()) {
+        match socket.recv_from(&mut buf[..]) {
+            Ok(m) => *meta = m,
+            Err(err) => if count == 0 { return Err(err) } else { break },
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Default implementation of `Socket::send_batch`.
+pub fn send_loop<S: Socket>(socket: &S, bufs: &[[u8; MTU]], meta: &[(usize, SocketAddr)]) -> io::Result<usize> {
+    let mut count = 0;
+    for (buf, &(len, addr)) in bufs.iter().zip(meta) {
+        if let Err(err) = socket.send_to(&buf[..len], addr) {
+            if count == 0 { return Err(err) } else { break }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(target_os = "linux")]
+pub use self::mmsg::{recvmmsg, sendmmsg};
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::{
+        io,
+        mem::{size_of, zeroed},
+        net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr, UdpSocket},
+        os::unix::io::AsRawFd,
+        ptr::null_mut,
+    };
+    use libc::{
+        mmsghdr, iovec, sockaddr_storage, sockaddr_in, sockaddr_in6, in_addr, in6_addr, socklen_t,
+        AF_INET, AF_INET6, MSG_WAITFORONE,
+    };
+    use crate::protocol::MTU;
+    use super::BATCH;
+
+    pub fn recvmmsg(socket: &UdpSocket, bufs: &mut [[u8; MTU]], meta: &mut [(usize, SocketAddr)]) -> io::Result<usize> {
+        let count = bufs.len().min(meta.len()).min(BATCH);
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let mut addrs: [sockaddr_storage; BATCH] = unsafe { zeroed() };
+        let mut iovecs: [iovec; BATCH] = unsafe { zeroed() };
+        let mut msgs: [mmsghdr; BATCH] = unsafe { zeroed() };
+        for i in 0..count {
+            iovecs[i].iov_base = bufs[i].as_mut_ptr() as *mut _;
+            iovecs[i].iov_len = MTU;
+            let name: *mut sockaddr_storage = &mut addrs[i];
+            msgs[i].msg_hdr.msg_name = name as *mut _;
+            msgs[i].msg_hdr.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+
+        let ret = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), count as _, MSG_WAITFORONE as _, null_mut())
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // datagram with unexpected address family is skipped as empty,
+        // the rest of the batch is already received
+        let count = ret as usize;
+        for i in 0..count {
+            meta[i] = match read_addr(&addrs[i]) {
+                Some(addr) => (msgs[i].msg_len as usize, addr),
+                None => (0, (Ipv4Addr::UNSPECIFIED, 0).into()),
+            };
+        }
+        Ok(count)
+    }
+
+    pub fn sendmmsg(socket: &UdpSocket, bufs: &[[u8; MTU]], meta: &[(usize, SocketAddr)]) -> io::Result<usize> {
+        let count = bufs.len().min(meta.len()).min(BATCH);
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let mut addrs: [sockaddr_storage; BATCH] = unsafe { zeroed() };
+        let mut iovecs: [iovec; BATCH] = unsafe { zeroed() };
+        let mut msgs: [mmsghdr; BATCH] = unsafe { zeroed() };
+        for i in 0..count {
+            let (len, addr) = meta[i];
+            iovecs[i].iov_base = bufs[i].as_ptr() as *mut _;
+            iovecs[i].iov_len = len;
+            msgs[i].msg_hdr.msg_namelen = write_addr(&addr, &mut addrs[i]);
+            let name: *mut sockaddr_storage = &mut addrs[i];
+            msgs[i].msg_hdr.msg_name = name as *mut _;
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+
+        let ret = unsafe { libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), count as _, 0) };
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret as usize)
+        }
+    }
+
+    fn read_addr(storage: &sockaddr_storage) -> Option<SocketAddr> {
+        let ptr: *const sockaddr_storage = storage;
+        match i32::from(storage.ss_family) {
+            AF_INET => {
+                let addr = unsafe { &*(ptr as *const sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Some(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+            }
+            AF_INET6 => {
+                let addr = unsafe { &*(ptr as *const sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                let port = u16::from_be(addr.sin6_port);
+                Some(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id).into())
+            }
+            _ => None,
+        }
+    }
+
+    fn write_addr(addr: &SocketAddr, storage: &mut sockaddr_storage) -> socklen_t {
+        let ptr: *mut sockaddr_storage = storage;
+        match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(ptr as *mut sockaddr_in) };
+                sin.sin_family = AF_INET as _;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = in_addr { s_addr: u32::from(*addr.ip()).to_be() };
+                size_of::<sockaddr_in>() as socklen_t
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(ptr as *mut sockaddr_in6) };
+                sin6.sin6_family = AF_INET6 as _;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr = in6_addr { s6_addr: addr.ip().octets() };
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                size_of::<sockaddr_in6>() as socklen_t
+            }
+        }
+    }
+}
+
+#[test]
+fn batch_udp() {
+    use std::{net::UdpSocket, time::Duration};
+
+    let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    b.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let addr = Socket::local_addr(&b).unwrap();
+
+    let mut send = SendBatch::new();
+    for i in 0..BATCH + 5 {
+        send.push(&a, &[i as u8; 100], addr);
+    }
+    send.flush(&a);
+
+    let mut recv = RecvBatch::new();
+    let mut received = Vec::new();
+    while received.len() < BATCH + 5 {
+        let count = recv.recv(&b).unwrap();
+        for i in 0..count {
+            let (buf, len, from) = recv.get_mut(i);
+            assert_eq!(len, 100);
+            assert_eq!(from, Socket::local_addr(&a).unwrap());
+            received.push(buf[0]);
+        }
+    }
+    let expected: Vec<u8> = (0..BATCH as u8 + 5).collect();
+    assert_eq!(received, expected);
+}
+
+#[test]
+fn batch_fallback() {
+    use crate::SimulatedSocket;
+
+    let a = SimulatedSocket::new();
+    let b = SimulatedSocket::new();
+
+    let mut send = SendBatch::new();
+    for i in 0..10 {
+        send.push(&a, &[i; 10], b.local_addr());
+    }
+    send.flush(&a);
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    let mut recv = RecvBatch::new();
+    let count = recv.recv(&b).unwrap();
+    assert_eq!(count, 10);
+    let mut received: Vec<u8> = (0..count).map(|i| recv.get_mut(i).0[0]).collect();
+    received.sort();
+    assert_eq!(received, (0..10).collect::<Vec<u8>>());
+    assert!(recv.recv(&b).is_err());
+}
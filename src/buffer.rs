@@ -0,0 +1,156 @@
+//! Pooled payload buffers.
+//!
+//! `Buffer` returns its memory to the pool on drop,
+//! so payloads move between threads without copies and allocations.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex},
+};
+use crate::protocol::MAX_PAYLOAD;
+
+type Chunk = Box<[u8; MAX_PAYLOAD]>;
+
+/// Pool of payload buffers, clones share the same pool.
+#[derive(Clone, Default)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<Chunk>>>,
+}
+
+impl BufferPool {
+    /// Maximum number of free buffers kept by the pool.
+    pub const MAX_FREE: usize = 1024;
+
+    pub fn new() -> Self { Self::default() }
+
+    /// Number of free buffers.
+    pub fn free(&self) -> usize { self.free.lock().unwrap().len() }
+
+    /// Returns an empty buffer.
+    pub fn get(&self) -> Buffer {
+        let chunk = self.free.lock().unwrap().pop()
+            .unwrap_or_else(|| Box::new([0u8; MAX_PAYLOAD]));
+        Buffer {
+            chunk: Some(chunk),
+            len: 0,
+            pool: self.clone(),
+        }
+    }
+
+    /// Returns a buffer with copy of `data`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` is longer than `MAX_PAYLOAD`.
+    pub fn copy_from(&self, data: &[u8]) -> Buffer {
+        let mut buf = self.get();
+        buf.set_len(data.len());
+        buf.copy_from_slice(data);
+        buf
+    }
+
+    fn put(&self, chunk: Chunk) {
+        let mut free = self.free.lock().unwrap();
+        if free.len() < Self::MAX_FREE {
+            free.push(chunk);
+        }
+    }
+}
+
+/// Payload up to `MAX_PAYLOAD` bytes.
+pub struct Buffer {
+    chunk: Option<Chunk>,
+    len: usize,
+    pool: BufferPool,
+}
+
+impl Buffer {
+    pub fn capacity(&self) -> usize { MAX_PAYLOAD }
+
+    /// Resizes the buffer, new bytes have unspecified values.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` is greater than `MAX_PAYLOAD`.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= MAX_PAYLOAD, "buffer length {} is greater than {}", len, MAX_PAYLOAD);
+        self.len = len;
+    }
+
+    pub fn clear(&mut self) { self.len = 0 }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(chunk) = self.chunk.take() {
+            self.pool.put(chunk);
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.chunk.as_ref().unwrap()[..self.len]
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.chunk.as_mut().unwrap()[..self.len]
+    }
+}
+
+impl AsRef<[u8]> for Buffer {
+    fn as_ref(&self) -> &[u8] { self }
+}
+
+impl AsMut<[u8]> for Buffer {
+    fn as_mut(&mut self) -> &mut [u8] { self }
+}
+
+impl fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Buffer")
+            .field("len", &self.len)
+            .field("data", &&self[..])
+            .finish()
+    }
+}
+
+impl PartialEq for Buffer {
+    fn eq(&self, other: &Self) -> bool { self[..] == other[..] }
+}
+
+impl Eq for Buffer {}
+
+#[test]
+fn buffer_pool() {
+    let pool = BufferPool::new();
+    assert_eq!(pool.free(), 0);
+
+    let a = pool.copy_from(b"hello");
+    let b = pool.get();
+    assert_eq!(&a[..], b"hello");
+    assert!(b.is_empty());
+    assert_eq!(b.capacity(), MAX_PAYLOAD);
+
+    // memory is reused
+    let ptr = a.as_ptr();
+    drop(a);
+    assert_eq!(pool.free(), 1);
+    let mut c = pool.get();
+    assert_eq!(c.as_ptr(), ptr);
+    assert_eq!(pool.free(), 0);
+    drop(b);
+    assert_eq!(pool.free(), 1);
+
+    c.set_len(MAX_PAYLOAD);
+    assert_eq!(c.len(), MAX_PAYLOAD);
+
+    // buffers can outlive the pool handle
+    drop(pool);
+    c.clear();
+    assert!(c.is_empty());
+}
@@ -2,34 +2,46 @@ use std::net::{SocketAddr, UdpSocket};
 use std::time::{Duration, Instant};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::collections::VecDeque;
+use std::marker::PhantomData;
 use crate::{
     Socket,
-    protocol::{Packet, Request, MTU, PACKET_SEND_DELTA, MAX_PAYLOAD, NUM_DISCONNECT_PACKETS},
-    token::{PublicToken, PRIVATE_LEN, CHALLENGE_LEN},
+    protocol::{Packet, Probe, Request, Resume, DenyReason, COOKIE_LEN, CloseReason, MTU, MIN_MTU, PACKET_SEND_DELTA, NUM_DISCONNECT_PACKETS, max_payload},
+    token::{PublicToken, Ticket, PRIVATE_LEN, CHALLENGE_LEN},
     replay_protection::ReplayProtection,
-    crypto::{KEY, XNONCE},
+    server_list::ServerList,
+    path_mtu::PathMtu,
+    buffer::{Buffer, BufferPool},
+    stats::{Stats, StatsTracker, encode_loss},
+    crypto::{KEY, HMAC, XNONCE, Aead, DefaultAead},
+    rekey::KeySchedule,
+    unix_time,
 };
 
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq)]
 pub enum ConnectingState {
     SendingRequest,
     SendingResponse,
+    /// Resuming the session with a ticket, see `Client::resume`.
+    SendingResume,
 }
 
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq)]
 pub enum Error {
     ConnectTokenExpired,
     InvalidConnectToken,
+    ResumeTicketExpired,
 
     ConnectionTimedOut,
     ConnectionResponseTimedOut,
     ConnectionRequestTimedOut,
-    ConnectionDenied,
+    /// Server sent the denied packet, reason is `None` if it's unknown.
+    ConnectionDenied(Option<DenyReason>),
 }
 
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq)]
 pub enum State {
-    Disconnected,
+    /// Not connected, reason is `Some` if the server closed the connection with it.
+    Disconnected(Option<CloseReason>),
     Connecting(ConnectingState),
     Connected,
     Failed(Error),
@@ -39,9 +51,17 @@ use self::Error::*;
 use self::State::*;
 use self::ConnectingState::*;
 
-pub struct Client<S: Socket = UdpSocket> {
+pub struct Client<S: Socket = UdpSocket, C: Aead = DefaultAead> {
     state: State,
     socket: S,
+    mtu: usize,
+    path_mtu: PathMtu,
+    stats: StatsTracker,
+
+    servers: ServerList,
+    next_server: usize,
+    failover: bool,
+    server: Option<SocketAddr>,
 
     protocol: u64,
     expire_timestamp: u64,
@@ -50,20 +70,29 @@ pub struct Client<S: Socket = UdpSocket> {
 
     nonce: [u8; XNONCE],
     token: [u8; PRIVATE_LEN],
+    cookie: [u8; COOKIE_LEN],
+    cookie_expected: bool,
 
     time: Instant,
     start_time: Instant,
     last_send: Instant,
     last_recv: Instant,
 
-    send_key: [u8; KEY],
-    recv_key: [u8; KEY],
+    send_key: KeySchedule,
+    recv_key: KeySchedule,
+    client_key: [u8; KEY],
+    server_key: [u8; KEY],
+    ticket: Option<Ticket>,
 
     sequence: AtomicU64,
     response: [u8; 8 + CHALLENGE_LEN],
 
     replay_protection: ReplayProtection,
-    recv_queue: VecDeque<(usize, [u8; MAX_PAYLOAD])>,
+    recv_queue: VecDeque<Buffer>,
+    pool: BufferPool,
+    close_message: Option<String>,
+
+    _aead: PhantomData<fn() -> C>,
 }
 
 impl Client<UdpSocket> {
@@ -82,6 +111,25 @@ impl Client<crate::SimulatedSocket> {
 
 impl<S: Socket> Client<S> {
     pub fn with_socket(protocol: u64, token: &PublicToken, socket: S) -> std::io::Result<Self> {
+        Self::with_aead(protocol, token, socket)
+    }
+}
+
+#[cfg(unix)]
+impl<S: Socket + std::os::unix::io::AsRawFd, C: Aead> std::os::unix::io::AsRawFd for Client<S, C> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd { self.socket.as_raw_fd() }
+}
+
+#[cfg(windows)]
+impl<S: Socket + std::os::windows::io::AsRawSocket, C: Aead> std::os::windows::io::AsRawSocket for Client<S, C> {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket { self.socket.as_raw_socket() }
+}
+
+impl<S: Socket, C: Aead> Client<S, C> {
+    /// Creates the client which uses `C` for encryption instead of `DefaultAead`.
+    ///
+    /// The server must use the same `Aead`, see `Server::with_aead`.
+    pub fn with_aead(protocol: u64, token: &PublicToken, socket: S) -> std::io::Result<Self> {
         socket.set_nonblocking(true)?;
 
         let now = Instant::now();
@@ -90,8 +138,16 @@ impl<S: Socket> Client<S> {
         let timeout = Duration::from_secs(token.timeout_seconds().into());
 
         Ok(Self {
-            state: Disconnected,
+            state: Disconnected(None),
             socket,
+            mtu: MTU,
+            path_mtu: PathMtu::new(MIN_MTU, MTU),
+            stats: StatsTracker::new(now),
+
+            servers: ServerList::deserialize(token.data()).unwrap_or_default(),
+            next_server: 0,
+            failover: false,
+            server: None,
 
             protocol,
             expire_timestamp: token.expire_timestamp(),
@@ -100,20 +156,29 @@ impl<S: Socket> Client<S> {
 
             nonce: token.nonce(),
             token: *token.token(),
+            cookie: [0u8; COOKIE_LEN],
+            cookie_expected: false,
 
             time: now,
             start_time: now,
             last_send: now - Duration::from_secs(1),
             last_recv: now,
 
-            send_key: token.client_key(),
-            recv_key: token.server_key(),
+            send_key: KeySchedule::new(token.client_key()),
+            recv_key: KeySchedule::new(token.server_key()),
+            client_key: token.client_key(),
+            server_key: token.server_key(),
+            ticket: None,
 
             sequence: AtomicU64::new(0),
             response: [0u8; 8 + CHALLENGE_LEN],
 
             replay_protection: ReplayProtection::new(),
             recv_queue: VecDeque::new(),
+            pool: BufferPool::new(),
+            close_message: None,
+
+            _aead: PhantomData,
         })
     }
 
@@ -125,31 +190,192 @@ impl<S: Socket> Client<S> {
 
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> { self.socket.local_addr() }
 
+    pub fn mtu(&self) -> usize { self.mtu }
+    /// Maximum length of payload accepted by `send`.
+    pub fn max_payload(&self) -> usize { max_payload(self.mtu) }
+
+    /// MTU confirmed by path MTU discovery.
+    pub fn path_mtu(&self) -> usize { self.path_mtu.mtu() }
+    /// Length of payload which gets through this path.
+    pub fn usable_payload(&self) -> usize { max_payload(self.path_mtu()) }
+
+    /// Network statistics, updated by `update`.
+    pub fn stats(&self) -> Stats { self.stats.stats() }
+
+    /// Sets MTU, it should be called before `connect`.
+    ///
+    /// The request packet is padded up to MTU,
+    /// so the server uses the same MTU for this connection.
+    ///
+    /// The lower bound is `MIN_MTU`, because the request
+    /// with the private token of `PRIVATE_LEN` bytes must fit into a single datagram.
+    /// The client can't connect over links with a smaller MTU,
+    /// e.g. some tunnels and VPNs, unless the network fragments IP packets.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mtu` is not in `MIN_MTU..=MTU`.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        assert!(mtu >= MIN_MTU && mtu <= MTU, "MTU must be in {}..={}", MIN_MTU, MTU);
+        self.mtu = mtu;
+        self.path_mtu = PathMtu::new(MIN_MTU, mtu);
+    }
+
+    /// Message sent by the server with the close reason.
+    pub fn close_message(&self) -> Option<&str> {
+        self.close_message.as_ref().map(String::as_str)
+    }
+
+    /// Address of the server which the client is connecting or connected to.
+    pub fn server_addr(&self) -> Option<SocketAddr> { self.server }
+
+    /// Connects to the single server.
     pub fn connect(&mut self, addr: SocketAddr) -> std::io::Result<()> {
+        self.failover = false;
+        self.connect_server(addr)
+    }
+
+    /// Connects to servers from the token's server list one by one.
+    ///
+    /// The client moves to the next server if the current one times out or denies the connection.
+    /// The state is `Failed(InvalidConnectToken)` if the list is empty.
+    pub fn connect_list(&mut self) -> std::io::Result<()> {
+        self.failover = true;
+        self.next_server = 0;
+        match self.next_server_addr() {
+            Some(addr) => self.connect_server(addr),
+            None => {
+                self.state = Failed(InvalidConnectToken);
+                Ok(())
+            }
+        }
+    }
+
+    fn next_server_addr(&mut self) -> Option<SocketAddr> {
+        let addr = self.servers.as_slice().get(self.next_server).cloned()?;
+        self.next_server += 1;
+        Some(addr)
+    }
+
+    fn connect_server(&mut self, addr: SocketAddr) -> std::io::Result<()> {
         self.socket.connect(addr)?;
+        self.server = Some(addr);
         self.state = Connecting(SendingRequest);
+        self.send_key = KeySchedule::new(self.client_key);
+        self.recv_key = KeySchedule::new(self.server_key);
+        self.ticket = None;
+        self.cookie = [0u8; COOKIE_LEN];
+        self.cookie_expected = false;
+        self.reset();
         Ok(())
     }
 
-    pub fn recv(&mut self) -> Option<(usize, [u8; MAX_PAYLOAD])> {
+    /// Returns `true` if the client has a valid resumption ticket.
+    pub fn can_resume(&self) -> bool {
+        self.server.is_some() && self.ticket.as_ref()
+            .map_or(false, |ticket| ticket.expire_timestamp() > unix_time())
+    }
+
+    /// Reconnects to the last server with the resumption ticket,
+    /// without the connect token.
+    ///
+    /// The server sends tickets during the session, see `Server::set_resume_window`.
+    /// The ticket can be used only once, the resumed session gets new keys.
+    pub fn resume(&mut self) -> std::io::Result<()> {
+        use std::io::{Error, ErrorKind};
+        if self.state == Connected {
+            return Err(Error::new(ErrorKind::Other, "already connected"));
+        }
+        if !self.can_resume() {
+            return Err(Error::new(ErrorKind::NotFound, "no valid resumption ticket"));
+        }
+        self.time = Instant::now();
+        self.state = Connecting(SendingResume);
+        if let Some(ticket) = self.ticket.as_ref() {
+            self.send_key = KeySchedule::new(*ticket.client_key());
+            self.recv_key = KeySchedule::new(*ticket.server_key());
+        }
+        self.reset();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.last_send = self.time - Duration::from_secs(1);
+        self.last_recv = self.time;
+        self.sequence = AtomicU64::new(0);
+        self.replay_protection = ReplayProtection::new();
+        self.path_mtu = PathMtu::new(MIN_MTU, self.mtu);
+        self.stats = StatsTracker::new(self.time);
+        self.recv_queue.clear();
+        self.close_message = None;
+    }
+
+    /// Replaces the socket, e.g. when the network has changed.
+    ///
+    /// The server moves the connection to the new address
+    /// after it has checked that the client answers there,
+    /// path MTU discovery starts again.
+    pub fn rebind(&mut self, socket: S) -> std::io::Result<()> {
+        socket.set_nonblocking(true)?;
+        if let Some(addr) = self.server {
+            socket.connect(addr)?;
+        }
+        self.socket = socket;
+        self.path_mtu = PathMtu::new(MIN_MTU, self.mtu);
+        Ok(())
+    }
+
+    /// Moves to the next server from the list or fails.
+    fn failover(&mut self, err: Error) {
+        if self.failover {
+            while let Some(addr) = self.next_server_addr() {
+                if self.connect_server(addr).is_ok() {
+                    return;
+                }
+            }
+        }
+        self.state = Failed(err);
+    }
+
+    pub fn recv(&mut self) -> Option<Buffer> {
         self.recv_queue.pop_front()
     }
 
     pub fn close(&mut self) {
         for _ in 0..NUM_DISCONNECT_PACKETS {
-            let seq = self.sequence.fetch_add(1, Ordering::Relaxed);
+            let seq = self.next_sequence();
             let mut buf = [0u8; MTU];
-            let len = Packet::encode_close(self.protocol, &mut buf, seq, &self.send_key)
+            let len = Packet::encode_close::<C>(self.protocol, &mut buf, seq, self.send_key.key(), &mut [])
                 .unwrap();
             self.send_packet(&buf[..len]);
         }
-        self.state = Disconnected;
+        self.state = Disconnected(None);
+    }
+
+    /// Returns time when `update` should be called next,
+    /// `None` if it isn't needed at all.
+    ///
+    /// Returned time can be in the past, `update` should be called immediately in this case.
+    /// Between deadlines `update` is needed only when the socket is readable.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let mut deadline = match self.state {
+            Disconnected(_) | Failed(_) => return None,
+            Connecting(SendingResume) => self.last_recv + self.timeout,
+            Connecting(_) => self.start_time + self.expire,
+            Connected => {
+                let ping = self.stats.ping_deadline(self.time);
+                self.path_mtu.deadline(self.time).map_or(ping, |probe| probe.min(ping))
+            }
+        };
+        deadline = deadline.min(self.last_send + PACKET_SEND_DELTA);
+        deadline = deadline.min(self.last_recv + self.timeout);
+        Some(deadline)
     }
 
     pub fn update(&mut self) {
         // early exit
         match self.state {
-            Disconnected | Failed(_) => return,
+            Disconnected(_) | Failed(_) => return,
             _ => (),
         }
 
@@ -157,28 +383,36 @@ impl<S: Socket> Client<S> {
         self.time = Instant::now();
 
         // check token
-        if let Connecting(_) = self.state {
-            if self.time - self.start_time >= self.expire {
+        match self.state {
+            Connecting(SendingResume) => if !self.can_resume() {
+                self.state = Failed(ResumeTicketExpired);
+                return;
+            }
+            Connecting(_) => if self.time - self.start_time >= self.expire {
                 self.state = Failed(ConnectTokenExpired);
                 return;
             }
+            _ => (),
         }
 
         // check for timeout
         if self.last_recv + self.timeout < self.time {
-            self.state = Failed(match self.state {
-                Connected => ConnectionTimedOut,
-                Connecting(SendingRequest) => ConnectionRequestTimedOut,
-                Connecting(SendingResponse) => ConnectionResponseTimedOut,
+            match self.state {
+                Connected => self.state = Failed(ConnectionTimedOut),
+                Connecting(SendingRequest) => self.failover(ConnectionRequestTimedOut),
+                Connecting(SendingResponse) => self.failover(ConnectionResponseTimedOut),
+                Connecting(SendingResume) => self.state = Failed(ConnectionRequestTimedOut),
                 _ => unreachable!(),
-            });
+            }
             return;
         }
 
         // recv packets
         let mut buf = [0u8; MTU];
         while let Ok(len) = self.socket.recv(&mut buf) {
-            self.process_packet(&mut buf[..len]);
+            if self.process_packet(&mut buf[..len]) {
+                self.stats.on_recv(len);
+            }
         }
 
         // send packets
@@ -188,84 +422,189 @@ impl<S: Socket> Client<S> {
                 Connected => self.send(&mut []).unwrap(),
                 Connecting(SendingRequest) => self.send_request(),
                 Connecting(SendingResponse) => self.send_response(),
+                Connecting(SendingResume) => self.send_resume(),
                 _ => unreachable!(),
             }
         }
+
+        // probe path MTU or ping
+        if self.state == Connected {
+            let size = match self.path_mtu.probe(self.time) {
+                Some(size) => Some(size as u16),
+                None if self.stats.need_ping(self.time) => Some(0),
+                None => None,
+            };
+            if let Some(size) = size {
+                let id = self.stats.ping(self.time);
+                self.send_probe(Probe { size, id, loss: 0 }, false);
+            }
+        }
+
+        self.stats.update(self.time, self.replay_protection.loss());
     }
 
     pub fn send(&mut self, m: &mut [u8]) -> std::io::Result<()> {
-        let seq = self.sequence.fetch_add(1, Ordering::Relaxed);
+        if m.len() > self.max_payload() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "payload is too large"));
+        }
+        let seq = self.next_sequence();
         let mut buf = [0u8; MTU];
-        let len = Packet::encode_payload(self.protocol, &mut buf, seq, &self.send_key, m)?;
+        let len = Packet::encode_payload::<C>(self.protocol, &mut buf, seq, self.send_key.key(), m)?;
         self.send_packet(&buf[..len]);
         Ok(())
     }
 
+    fn next_sequence(&mut self) -> u64 {
+        let seq = self.sequence.fetch_add(1, Ordering::Relaxed);
+        self.send_key.advance(seq);
+        seq
+    }
+
+    /// Decrypts the packet with the key of its epoch, replayed packets are dropped.
+    fn open(&mut self, prefix: u8, seq: u64, m: &mut [u8], tag: &[u8; HMAC]) -> bool {
+        if self.replay_protection.is_received(seq) {
+            return false;
+        }
+        let key = match self.recv_key.get(seq) {
+            Some(key) => key,
+            None => return false,
+        };
+        if Packet::open::<C>(self.protocol, m, seq, prefix, tag, &key).is_err() {
+            return false;
+        }
+        self.recv_key.confirm(seq);
+        self.replay_protection.insert(seq);
+        true
+    }
+
     fn send_packet(&mut self, data: &[u8]) {
         let _ = self.socket.send(&data);
+        self.stats.on_send(data.len());
         self.last_send = self.time;
     }
     fn send_request(&mut self) {
-        let req = Request::new(self.protocol, self.expire_timestamp, self.nonce, self.token);
-        self.send_packet(&req.write());
+        let mut req = Request::new(self.protocol, self.expire_timestamp, self.nonce, self.token);
+        req.set_cookie(self.cookie);
+        self.send_packet(&req.write()[..self.mtu]);
+        self.cookie_expected = true;
+    }
+    fn send_resume(&mut self) {
+        let token = match self.ticket.as_ref() {
+            Some(ticket) => *ticket.token(),
+            None => return,
+        };
+        let resume = Resume::new(self.protocol, token);
+        self.send_packet(&resume.write()[..self.mtu]);
+    }
+    fn send_probe(&mut self, probe: Probe, ack: bool) {
+        let seq = self.next_sequence();
+        let loss = encode_loss(self.replay_protection.loss());
+        let probe = Probe { loss, .. probe };
+        let mut buf = [0u8; MTU];
+        let len = Packet::encode_probe::<C>(self.protocol, &mut buf, seq, self.send_key.key(), probe, ack)
+            .unwrap();
+        self.send_packet(&buf[..len]);
     }
     fn send_response(&mut self) {
-        let seq = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let seq = self.next_sequence();
         let mut response = self.response;
         let mut buf = [0u8; MTU];
-        let len = Packet::encode_handshake(self.protocol, &mut buf, seq, &self.send_key, &mut response)
+        let len = Packet::encode_handshake::<C>(self.protocol, &mut buf, seq, self.send_key.key(), &mut response)
             .unwrap();
         self.send_packet(&buf[..len]);
     }
 
-    fn process_packet(&mut self, buf: &mut [u8]) {
+    /// Returns `true` if the packet is authenticated.
+    fn process_packet(&mut self, buf: &mut [u8]) -> bool {
         let packet = match Packet::decode(buf) {
-            Some(packet) => packet,
-            None => return,
+            Ok(packet) => packet,
+            Err(_) => return false,
         };
 
         match (self.state, packet) {
             (Connected, Packet::Payload { seq, buf, tag }) |
-            (Connecting(SendingResponse), Packet::Payload { seq, buf, tag }) => {
-                if self.replay_protection.already_received(seq) {
-                    return;
-                }
-                if Packet::open(self.protocol, buf, seq, 0, tag, &self.recv_key).is_err() {
-                    return;
+            (Connecting(SendingResponse), Packet::Payload { seq, buf, tag }) |
+            (Connecting(SendingResume), Packet::Payload { seq, buf, tag }) => {
+                if !self.open(0, seq, buf, tag) {
+                    return false;
                 }
                 self.last_recv = self.time;
                 if !buf.is_empty() {
-                    let mut packet = [0u8; MAX_PAYLOAD];
-                    packet[..buf.len()].copy_from_slice(buf);
-                    self.recv_queue.push_back((buf.len(), packet));
+                    self.recv_queue.push_back(self.pool.copy_from(buf));
+                }
+                if self.state == Connecting(SendingResume) {
+                    // tickets are single use
+                    self.ticket = None;
                 }
                 self.state = Connected;
+                true
             }
-            (Connected, Packet::Close { prefix, seq, tag }) => {
-                if self.replay_protection.already_received(seq) {
-                    return;
+            (Connected, Packet::Ticket { prefix, seq, buf, tag }) => {
+                if !self.open(prefix, seq, buf, tag) {
+                    return false;
                 }
-                if Packet::open(self.protocol, &mut [], seq, prefix, tag, &self.recv_key).is_err() {
-                    return;
+                self.last_recv = self.time;
+                self.ticket = Ticket::read(&buf[..]).ok();
+                true
+            }
+            (Connected, Packet::Probe { prefix, ack, seq, buf, tag }) => {
+                if !self.open(prefix, seq, buf, tag) {
+                    return false;
+                }
+                self.last_recv = self.time;
+                if let Some(probe) = Probe::read(buf) {
+                    self.stats.set_send_loss(probe.loss);
+                    if ack {
+                        self.path_mtu.ack(probe.size as usize);
+                        self.stats.pong(probe.id, self.time);
+                    } else {
+                        self.send_probe(probe, true);
+                    }
                 }
-                self.state = Disconnected;
+                true
             }
-            (Connecting(_), Packet::Close { prefix, seq, tag })  => {
-                if Packet::open(self.protocol, &mut [], seq, prefix, tag, &self.recv_key).is_err() {
+            (Connected, Packet::Close { prefix, seq, buf, tag }) => {
+                if !self.open(prefix, seq, buf, tag) {
+                    return false;
                 }
-                self.state = Failed(ConnectionDenied);
+                let reason = buf.first().and_then(|&code| CloseReason::from_code(code));
+                if reason.is_some() && buf.len() > 1 {
+                    self.close_message = Some(String::from_utf8_lossy(&buf[1..]).into_owned());
+                }
+                self.state = Disconnected(reason);
+                true
+            }
+            (Connecting(_), Packet::Close { prefix, seq, buf, tag })  => {
+                if Packet::open::<C>(self.protocol, buf, seq, prefix, tag, self.recv_key.key()).is_err() {
+                    return false;
+                }
+                let reason = buf.first().and_then(|&code| DenyReason::from_code(code));
+                self.failover(ConnectionDenied(reason));
+                true
             }
             (Connecting(SendingRequest), Packet::Handshake { prefix, seq, buf, tag }) => {
-                if Packet::open(self.protocol, buf, seq, prefix, tag, &self.recv_key).is_err() {
-                    return;
+                if Packet::open::<C>(self.protocol, buf, seq, prefix, tag, self.recv_key.key()).is_err() {
+                    return false;
                 }
                 self.response.copy_from_slice(buf);
                 self.state = Connecting(SendingResponse);
 
                 self.send_response();
+                true
+            }
+            (Connecting(SendingRequest), Packet::Cookie(cookie)) => {
+                // The server is under load, the request is repeated with the cookie by the timer.
+                // Cookies aren't authenticated, so only one cookie is taken per sent request,
+                // otherwise spoofed cookies could replace it or trigger a request for each of them.
+                // A spoofed or expired cookie is replaced by the answer to the next request.
+                if self.cookie_expected {
+                    self.cookie_expected = false;
+                    self.cookie = *cookie;
+                }
+                false
             }
             //_ => panic!("!!!!! bad: {} {:?}", buf.len(), buf),
-            _ => (),
+            _ => false,
         }
     }
 }
@@ -306,3 +645,39 @@ fn error_token_expired() {
 
     assert_eq!(client.state(), Failed(ConnectTokenExpired));
 }
+
+#[test]
+fn spoofed_cookie() {
+    use crate::{Server, crypto::keygen, token::USER};
+
+    const PROTOCOL: u64 = 0x1122334455667788;
+
+    let private_key = keygen();
+    let mut server = Server::simulated(PROTOCOL, private_key);
+    server.set_cookie_threshold(0);
+
+    let mut data = ServerList::new();
+    data.push(server.local_addr()).unwrap();
+    let token = PublicToken::generate(data.serialize().unwrap(), [0u8; USER], 30, 5, 1, PROTOCOL, &private_key);
+
+    let mut client = Client::simulated(PROTOCOL, &token);
+    client.connect(server.local_addr()).unwrap();
+    client.update();
+
+    // the spoofed cookie arrives before the one of the server
+    let mut buf = [0u8; 1 + COOKIE_LEN];
+    Packet::encode_cookie(&mut buf, &[9u8; COOKIE_LEN]);
+    client.process_packet(&mut buf);
+    assert_eq!(client.cookie, [9u8; COOKIE_LEN]);
+
+    for _ in 0..100 {
+        std::thread::sleep(Duration::from_millis(1000 / 60));
+        client.update();
+        server.update();
+        while server.poll_event().is_some() {}
+        if client.is_connected() {
+            break;
+        }
+    }
+    assert!(client.is_connected());
+}
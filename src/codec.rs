@@ -0,0 +1,104 @@
+//! Bounds-checked reading and writing of wire types.
+
+#![deny(unsafe_code)]
+
+/// Error of reading a wire type from bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Buffer ends before the type.
+    TooShort,
+    /// Buffer has more bytes than the type allows.
+    TooLong,
+    /// Prefix byte doesn't match the type.
+    BadPrefix,
+}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// Takes the next `n` bytes.
+    pub(crate) fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() < n {
+            return Err(DecodeError::TooShort);
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    /// Fills `out` with the next bytes.
+    pub(crate) fn read(&mut self, out: &mut [u8]) -> Result<(), DecodeError> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub(crate) fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Fails if there are bytes left.
+    pub(crate) fn finish(self) -> Result<(), DecodeError> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TooLong)
+        }
+    }
+}
+
+/// Reads `[u8; $n]` from `Reader`, returns from the function on error.
+pub(crate) macro read_array($r:expr, $n:expr) {{
+    let mut array = [0u8; $n];
+    $r.read(&mut array)?;
+    array
+}}
+
+pub(crate) struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Appends `bytes`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` don't fit into the buffer.
+    pub(crate) fn put(&mut self, bytes: &[u8]) -> &mut Self {
+        let end = self.pos + bytes.len();
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        self
+    }
+
+    /// Returns the number of written bytes.
+    pub(crate) fn len(&self) -> usize { self.pos }
+}
+
+#[test]
+fn reader_bounds() {
+    let buf = [1u8, 2, 3, 4, 5];
+    let mut r = Reader::new(&buf);
+    assert_eq!(r.read_u8(), Ok(1));
+    let a: Result<[u8; 2], DecodeError> = (|| Ok(read_array!(r, 2)))();
+    assert_eq!(a, Ok([2, 3]));
+    assert_eq!(r.take(3), Err(DecodeError::TooShort));
+    assert_eq!(r.take(1), Ok(&[4][..]));
+    assert_eq!(Reader::new(&buf).finish(), Err(DecodeError::TooLong));
+    assert_eq!(Reader::new(&[]).finish(), Ok(()));
+
+    let mut out = [0u8; 4];
+    let len = Writer::new(&mut out).put(&[1, 2]).put(&[3]).len();
+    assert_eq!(len, 3);
+    assert_eq!(out, [1, 2, 3, 0]);
+}
@@ -111,6 +111,15 @@ impl ChaCha20 {
         let mut ctarget: *mut u8 = ptr::null_mut();
         if 0 == bytes { return; }
 
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            let done = super::chacha20_simd::encrypt_blocks(&mut self.state, m, c, bytes);
+            m = m.add(done);
+            c = c.add(done);
+            bytes -= done as u64;
+            if 0 == bytes { return; }
+        }
+
         let      j0 = self.state[ 0];
         let      j1 = self.state[ 1];
         let      j2 = self.state[ 2];
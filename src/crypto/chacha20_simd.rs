@@ -0,0 +1,183 @@
+//! Multi-block ChaCha20 for x86 with SSE2 and AVX2.
+//!
+//! Each vector holds the same word of 4 (SSE2) or 8 (AVX2) consecutive blocks,
+//! so the rounds are the same as in the scalar version
+//! and the blocks are transposed back only for the final xor.
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// Encrypts as many chunks of 4 or 8 blocks as possible,
+/// returns the number of processed bytes.
+///
+/// `state[12]` is advanced by the number of processed blocks.
+/// Chunks which would overflow the block counter are left for the scalar version.
+pub unsafe fn encrypt_blocks(state: &mut [u32; 16], m: *const u8, c: *mut u8, bytes: u64) -> usize {
+    let mut done = 0u64;
+    if is_x86_feature_detected!("avx2") {
+        while bytes - done >= 512 && state[12].checked_add(8).is_some() {
+            avx2::blocks8(state, m.add(done as usize), c.add(done as usize));
+            state[12] += 8;
+            done += 512;
+        }
+    }
+    if is_x86_feature_detected!("sse2") {
+        while bytes - done >= 256 && state[12].checked_add(4).is_some() {
+            sse2::blocks4(state, m.add(done as usize), c.add(done as usize));
+            state[12] += 4;
+            done += 256;
+        }
+    }
+    done as usize
+}
+
+mod sse2 {
+    use super::*;
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn rotl(x: __m128i, n: i32) -> __m128i {
+        _mm_or_si128(
+            _mm_sll_epi32(x, _mm_cvtsi32_si128(n)),
+            _mm_srl_epi32(x, _mm_cvtsi32_si128(32 - n)),
+        )
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn qround(x: &mut [__m128i; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[a] = _mm_add_epi32(x[a], x[b]); x[d] = rotl(_mm_xor_si128(x[d], x[a]), 16);
+        x[c] = _mm_add_epi32(x[c], x[d]); x[b] = rotl(_mm_xor_si128(x[b], x[c]), 12);
+        x[a] = _mm_add_epi32(x[a], x[b]); x[d] = rotl(_mm_xor_si128(x[d], x[a]),  8);
+        x[c] = _mm_add_epi32(x[c], x[d]); x[b] = rotl(_mm_xor_si128(x[b], x[c]),  7);
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn xor_store(m: *const u8, c: *mut u8, v: __m128i) {
+        let p = _mm_loadu_si128(m as *const __m128i);
+        _mm_storeu_si128(c as *mut __m128i, _mm_xor_si128(p, v));
+    }
+
+    /// Encrypts 4 blocks, 256 bytes.
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn blocks4(state: &[u32; 16], m: *const u8, c: *mut u8) {
+        let mut j = [_mm_setzero_si128(); 16];
+        for (j, &s) in j.iter_mut().zip(state.iter()) {
+            *j = _mm_set1_epi32(s as i32);
+        }
+        j[12] = _mm_add_epi32(j[12], _mm_set_epi32(3, 2, 1, 0));
+
+        let mut x = j;
+        // 10 loops × 2 rounds/loop = 20 rounds
+        for _ in 0..10 {
+            qround(&mut x, 0, 4,  8, 12);
+            qround(&mut x, 1, 5,  9, 13);
+            qround(&mut x, 2, 6, 10, 14);
+            qround(&mut x, 3, 7, 11, 15);
+            qround(&mut x, 0, 5, 10, 15);
+            qround(&mut x, 1, 6, 11, 12);
+            qround(&mut x, 2, 7,  8, 13);
+            qround(&mut x, 3, 4,  9, 14);
+        }
+        for (x, j) in x.iter_mut().zip(j.iter()) {
+            *x = _mm_add_epi32(*x, *j);
+        }
+
+        // 4×4 transpose of every 4 words gives 16 bytes of each block
+        for g in 0..4 {
+            let t0 = _mm_unpacklo_epi32(x[4 * g], x[4 * g + 1]);
+            let t1 = _mm_unpacklo_epi32(x[4 * g + 2], x[4 * g + 3]);
+            let t2 = _mm_unpackhi_epi32(x[4 * g], x[4 * g + 1]);
+            let t3 = _mm_unpackhi_epi32(x[4 * g + 2], x[4 * g + 3]);
+            let blocks = [
+                _mm_unpacklo_epi64(t0, t1),
+                _mm_unpackhi_epi64(t0, t1),
+                _mm_unpacklo_epi64(t2, t3),
+                _mm_unpackhi_epi64(t2, t3),
+            ];
+            for (b, &v) in blocks.iter().enumerate() {
+                let offset = b * 64 + g * 16;
+                xor_store(m.add(offset), c.add(offset), v);
+            }
+        }
+    }
+}
+
+mod avx2 {
+    use super::*;
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn rotl(x: __m256i, n: i32) -> __m256i {
+        _mm256_or_si256(
+            _mm256_sll_epi32(x, _mm_cvtsi32_si128(n)),
+            _mm256_srl_epi32(x, _mm_cvtsi32_si128(32 - n)),
+        )
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn qround(x: &mut [__m256i; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[a] = _mm256_add_epi32(x[a], x[b]); x[d] = rotl(_mm256_xor_si256(x[d], x[a]), 16);
+        x[c] = _mm256_add_epi32(x[c], x[d]); x[b] = rotl(_mm256_xor_si256(x[b], x[c]), 12);
+        x[a] = _mm256_add_epi32(x[a], x[b]); x[d] = rotl(_mm256_xor_si256(x[d], x[a]),  8);
+        x[c] = _mm256_add_epi32(x[c], x[d]); x[b] = rotl(_mm256_xor_si256(x[b], x[c]),  7);
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn xor_store(m: *const u8, c: *mut u8, v: __m128i) {
+        let p = _mm_loadu_si128(m as *const __m128i);
+        _mm_storeu_si128(c as *mut __m128i, _mm_xor_si128(p, v));
+    }
+
+    /// Encrypts 8 blocks, 512 bytes.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn blocks8(state: &[u32; 16], m: *const u8, c: *mut u8) {
+        let mut j = [_mm256_setzero_si256(); 16];
+        for (j, &s) in j.iter_mut().zip(state.iter()) {
+            *j = _mm256_set1_epi32(s as i32);
+        }
+        j[12] = _mm256_add_epi32(j[12], _mm256_set_epi32(7, 6, 5, 4, 3, 2, 1, 0));
+
+        let mut x = j;
+        // 10 loops × 2 rounds/loop = 20 rounds
+        for _ in 0..10 {
+            qround(&mut x, 0, 4,  8, 12);
+            qround(&mut x, 1, 5,  9, 13);
+            qround(&mut x, 2, 6, 10, 14);
+            qround(&mut x, 3, 7, 11, 15);
+            qround(&mut x, 0, 5, 10, 15);
+            qround(&mut x, 1, 6, 11, 12);
+            qround(&mut x, 2, 7,  8, 13);
+            qround(&mut x, 3, 4,  9, 14);
+        }
+        for (x, j) in x.iter_mut().zip(j.iter()) {
+            *x = _mm256_add_epi32(*x, *j);
+        }
+
+        // unpack works inside of 128-bit lanes:
+        // the low lane gets blocks 0..4 and the high one gets blocks 4..8
+        for g in 0..4 {
+            let t0 = _mm256_unpacklo_epi32(x[4 * g], x[4 * g + 1]);
+            let t1 = _mm256_unpacklo_epi32(x[4 * g + 2], x[4 * g + 3]);
+            let t2 = _mm256_unpackhi_epi32(x[4 * g], x[4 * g + 1]);
+            let t3 = _mm256_unpackhi_epi32(x[4 * g + 2], x[4 * g + 3]);
+            let blocks = [
+                _mm256_unpacklo_epi64(t0, t1),
+                _mm256_unpackhi_epi64(t0, t1),
+                _mm256_unpacklo_epi64(t2, t3),
+                _mm256_unpackhi_epi64(t2, t3),
+            ];
+            for (b, &v) in blocks.iter().enumerate() {
+                let lo = b * 64 + g * 16;
+                let hi = lo + 4 * 64;
+                xor_store(m.add(lo), c.add(lo), _mm256_castsi256_si128(v));
+                xor_store(m.add(hi), c.add(hi), _mm256_extracti128_si256(v, 1));
+            }
+        }
+    }
+}
@@ -1,7 +1,10 @@
 pub mod aead;
 mod chacha20;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod chacha20_simd;
 mod poly1305;
 mod hchacha20;
+pub mod verify;
 
 #[inline(always)]
 fn memzero_slice(p: &mut [u8]) {
@@ -30,57 +33,113 @@ pub use self::chacha20::ChaCha20;
 pub use self::poly1305::Poly1305;
 pub use self::hchacha20::hchacha20;
 
-/// Performs inplace encryption using ChaCha20Poly1305 IETF.
-#[inline]
-#[cfg(not(feature = "sodium"))]
-pub fn seal(m: &mut [u8], ad: Option<&[u8]>, npub: &Nonce, key: &Key) -> Tag {
-    let ad = ad.unwrap_or(&[]);
-    let z = &mut [0u8; 64][..];
-    ChaCha20::new_ietf(key, npub, 0).inplace(z);
-    ChaCha20::new_ietf(key, npub, 1).inplace(m);
-    let mut poly1305 = Poly1305::with_key(&z[..32]);
-    poly1305.update_pad(ad);
-    poly1305.update_pad(m);
-    poly1305.update_u64(ad.len() as u64);
-    poly1305.update_u64(m.len() as u64);
-    poly1305.finish()
+/// Authenticated encryption used by packets and tokens.
+///
+/// Both sides of the connection must use the same implementation,
+/// see the type parameter of `Server` and `Client`.
+pub trait Aead {
+    /// Performs inplace encryption, returns the tag.
+    fn seal(m: &mut [u8], ad: Option<&[u8]>, n: &Nonce, k: &Key) -> Tag;
+
+    /// Performs inplace decryption, `c` is zeroed if the tag doesn't match.
+    fn open(c: &mut [u8], ad: Option<&[u8]>, t: &Tag, n: &Nonce, k: &Key) -> Result<(), ()>;
+
+    /// Performs inplace encryption with the extended nonce.
+    ///
+    /// The default one derives the subkey with `hchacha20`.
+    fn xseal(m: &mut [u8], ad: &[u8], n: &Xnonce, k: &Key) -> Tag {
+        let (n, k) = AutoNonce(*n).split(k);
+        Self::seal(m, Some(ad), &n, &k)
+    }
+
+    /// Performs inplace decryption with the extended nonce.
+    ///
+    /// The default one derives the subkey with `hchacha20`.
+    fn xopen(c: &mut [u8], ad: &[u8], t: &Tag, n: &Xnonce, k: &Key) -> Result<(), ()> {
+        let (n, k) = AutoNonce(*n).split(k);
+        Self::open(c, Some(ad), t, &n, &k)
+    }
 }
 
-/// Performs inplace decryption using ChaCha20Poly1305 IETF.
-#[inline]
+/// Implementation used by default: `Sodium` with the `sodium` feature, `ChaCha20Poly1305` otherwise.
 #[cfg(not(feature = "sodium"))]
-pub fn open(c: &mut [u8], ad: Option<&[u8]>, tag: &Tag, npub: &Nonce, key: &Key)
-    -> Result<(), ()>
-{
-    let ad = ad.unwrap_or(&[]);
-    let z = &mut [0u8; 64][..];
-    ChaCha20::new_ietf(key, npub, 0).inplace(z);
-    let mut poly1305 = Poly1305::with_key(&z[..32]);
-    poly1305.update_pad(ad);
-    poly1305.update_pad(c);
-    poly1305.update_u64(ad.len() as u64);
-    poly1305.update_u64(c.len() as u64);
-    if poly1305.finish_verify(tag) {
-        ChaCha20::new_ietf(key, npub, 1).inplace(c);
-        Ok(())
-    } else {
-        c.iter_mut().for_each(|v| *v = 0);
-        Err(())
+pub type DefaultAead = ChaCha20Poly1305;
+#[cfg(feature = "sodium")]
+pub type DefaultAead = Sodium;
+
+/// ChaCha20Poly1305 IETF in pure Rust.
+pub enum ChaCha20Poly1305 {}
+
+impl Aead for ChaCha20Poly1305 {
+    #[inline]
+    fn seal(m: &mut [u8], ad: Option<&[u8]>, npub: &Nonce, key: &Key) -> Tag {
+        let ad = ad.unwrap_or(&[]);
+        let z = &mut [0u8; 64][..];
+        ChaCha20::new_ietf(key, npub, 0).inplace(z);
+        ChaCha20::new_ietf(key, npub, 1).inplace(m);
+        let mut poly1305 = Poly1305::with_key(&z[..32]);
+        poly1305.update_pad(ad);
+        poly1305.update_pad(m);
+        poly1305.update_u64(ad.len() as u64);
+        poly1305.update_u64(m.len() as u64);
+        poly1305.finish()
+    }
+
+    #[inline]
+    fn open(c: &mut [u8], ad: Option<&[u8]>, tag: &Tag, npub: &Nonce, key: &Key) -> Result<(), ()> {
+        let ad = ad.unwrap_or(&[]);
+        let z = &mut [0u8; 64][..];
+        ChaCha20::new_ietf(key, npub, 0).inplace(z);
+        let mut poly1305 = Poly1305::with_key(&z[..32]);
+        poly1305.update_pad(ad);
+        poly1305.update_pad(c);
+        poly1305.update_u64(ad.len() as u64);
+        poly1305.update_u64(c.len() as u64);
+        if poly1305.finish_verify(tag) {
+            ChaCha20::new_ietf(key, npub, 1).inplace(c);
+            Ok(())
+        } else {
+            c.iter_mut().for_each(|v| *v = 0);
+            Err(())
+        }
     }
 }
 
-#[inline]
-#[cfg(not(feature = "sodium"))]
-pub fn xopen(c: &mut [u8], ad: &[u8], t: &Tag, n: &[u8; XNONCE], k: &[u8; KEY]) -> Result<(), ()> {
-    let (n, k) = AutoNonce(*n).split(k);
-    open(c, Some(ad), &t, &n, &k)
+/// No encryption and no authentication, the tag is the nonce.
+///
+/// Only for profiling in local builds, never use it over the network.
+pub enum NullCipher {}
+
+impl NullCipher {
+    fn verify(c: &mut [u8], t: &Tag, tag: &Tag) -> Result<(), ()> {
+        if verify::verify_16(t, tag) {
+            Ok(())
+        } else {
+            c.iter_mut().for_each(|v| *v = 0);
+            Err(())
+        }
+    }
 }
 
-#[inline]
-#[cfg(not(feature = "sodium"))]
-pub fn xseal(m: &mut [u8], ad: &[u8], n: &[u8; XNONCE], k: &[u8; KEY]) -> Tag {
-    let (n, k) = AutoNonce(*n).split(k);
-    seal(m, Some(ad), &n, &k)
+impl Aead for NullCipher {
+    fn seal(_m: &mut [u8], _ad: Option<&[u8]>, n: &Nonce, _k: &Key) -> Tag {
+        let mut tag = [0u8; HMAC];
+        tag[..NONCE].copy_from_slice(n);
+        tag
+    }
+    fn open(c: &mut [u8], ad: Option<&[u8]>, t: &Tag, n: &Nonce, k: &Key) -> Result<(), ()> {
+        let tag = Self::seal(c, ad, n, k);
+        Self::verify(c, t, &tag)
+    }
+    fn xseal(_m: &mut [u8], _ad: &[u8], n: &Xnonce, _k: &Key) -> Tag {
+        let mut tag = [0u8; HMAC];
+        tag.copy_from_slice(&n[..HMAC]);
+        tag
+    }
+    fn xopen(c: &mut [u8], ad: &[u8], t: &Tag, n: &Xnonce, k: &Key) -> Result<(), ()> {
+        let tag = Self::xseal(c, ad, n, k);
+        Self::verify(c, t, &tag)
+    }
 }
 
 #[inline]
@@ -119,8 +178,17 @@ impl AutoNonce {
     }
 }
 
+/// ChaCha20Poly1305 IETF from libsodium.
+#[cfg(feature = "sodium")]
+pub enum Sodium {}
+
 #[cfg(feature = "sodium")]
-pub use self::sodium::{open, xopen, seal, xseal};
+impl Aead for Sodium {
+    fn seal(m: &mut [u8], ad: Option<&[u8]>, n: &Nonce, k: &Key) -> Tag { sodium::seal(m, ad, n, k) }
+    fn open(c: &mut [u8], ad: Option<&[u8]>, t: &Tag, n: &Nonce, k: &Key) -> Result<(), ()> { sodium::open(c, ad, t, n, k) }
+    fn xseal(m: &mut [u8], ad: &[u8], n: &Xnonce, k: &Key) -> Tag { sodium::xseal(m, ad, n, k) }
+    fn xopen(c: &mut [u8], ad: &[u8], t: &Tag, n: &Xnonce, k: &Key) -> Result<(), ()> { sodium::xopen(c, ad, t, n, k) }
+}
 
 #[cfg(feature = "sodium")]
 mod sodium {
@@ -2,15 +2,7 @@
 
 use byteorder::{LE, ByteOrder};
 use std::mem::size_of;
-
-#[must_use]
-fn verify16(x: &[u8; 16], y: &[u8; 16]) -> bool {
-    let mut d = 0u16;
-    for i in 0..16 {
-        d |= u16::from(x[i] ^ y[i])
-    }
-    (1 & ((d.wrapping_sub(1)) >> 8)).wrapping_sub(1) == 0
-}
+use super::verify::verify_16;
 
 struct DonnaState64 {
     r: [u64; 3],
@@ -59,7 +51,7 @@ impl Poly1305 {
 
     #[must_use]
     pub fn verify(h: &[u8; Self::BYTES], m: &[u8], k: &[u8; Self::KEYBYTES]) -> bool {
-        verify16(h, &Self::sum(m, k))
+        verify_16(h, &Self::sum(m, k))
     }
 
     pub fn init(&mut self, key: &[u8; Self::KEYBYTES]) {
@@ -75,7 +67,7 @@ impl Poly1305 {
 
     #[must_use]
     pub fn finish_verify(self, mac: &[u8; Self::BYTES]) -> bool {
-        verify16(&self.finish(), &mac)
+        verify_16(&self.finish(), &mac)
     }
 
     pub fn update(&mut self, input: &[u8]) {
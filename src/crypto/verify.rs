@@ -0,0 +1,65 @@
+//! Constant-time comparison and selection.
+//!
+//! Time of these functions depends only on the length of the inputs, not on their contents.
+//! Volatile reads keep the compiler from turning the loops into an early-exit `memcmp`.
+
+use std::ptr::read_volatile;
+
+/// Returns bitwise or of `x[i] ^ y[i]`, zero if the slices are equal.
+#[inline(never)]
+fn diff(x: &[u8], y: &[u8]) -> u8 {
+    debug_assert_eq!(x.len(), y.len());
+    let mut d = 0u8;
+    for (x, y) in x.iter().zip(y.iter()) {
+        d |= unsafe { read_volatile(x) ^ read_volatile(y) };
+    }
+    d
+}
+
+/// Returns `1` if `d == 0` and `0` otherwise.
+#[inline]
+fn is_zero_u8(d: u8) -> u8 {
+    (1 & (u16::from(d).wrapping_sub(1) >> 8)) as u8
+}
+
+/// Compares two 16-byte tags.
+#[must_use]
+pub fn verify_16(x: &[u8; 16], y: &[u8; 16]) -> bool {
+    is_zero_u8(diff(x, y)) == 1
+}
+
+/// Compares two 32-byte keys or hashes.
+#[must_use]
+pub fn verify_32(x: &[u8; 32], y: &[u8; 32]) -> bool {
+    is_zero_u8(diff(x, y)) == 1
+}
+
+/// Compares slices, only their lengths can leak.
+#[must_use]
+pub fn verify(x: &[u8], y: &[u8]) -> bool {
+    x.len() == y.len() && is_zero_u8(diff(x, y)) == 1
+}
+
+/// Returns `a` if `choice == 1` and `b` if `choice == 0`.
+///
+/// Its behavior is undefined if `choice` takes any other value.
+#[inline]
+pub fn select_u64(choice: u8, a: u64, b: u64) -> u64 {
+    let mask = u64::from(choice).wrapping_neg();
+    a & mask | b & !mask
+}
+
+/// Copies `src` into `dst` if `choice == 1`, leaves `dst` unchanged if `choice == 0`.
+///
+/// Its behavior is undefined if `choice` takes any other value.
+///
+/// # Panics
+///
+/// Panics if the slices have different lengths.
+pub fn cmov(choice: u8, dst: &mut [u8], src: &[u8]) {
+    assert_eq!(dst.len(), src.len(), "slices have different lengths");
+    let mask = choice.wrapping_neg();
+    for (d, &s) in dst.iter_mut().zip(src.iter()) {
+        *d = *d & !mask | s & mask;
+    }
+}
@@ -0,0 +1,366 @@
+//! Ingress filter of the server: ban list and rate limits.
+//!
+//! Datagrams are checked by the source IP before any other processing,
+//! so flooding with garbage or handshakes doesn't cost the server any crypto.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Instant, Duration},
+    collections::{HashMap, BTreeMap},
+    cmp::Ordering,
+};
+
+/// Token bucket limit: `rate` datagrams per second with bursts up to `burst`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f32,
+    pub burst: f32,
+}
+
+impl RateLimit {
+    pub fn new(rate: f32, burst: f32) -> Self {
+        Self { rate, burst }
+    }
+}
+
+/// Counters of dropped datagrams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    /// Dropped because the source is banned.
+    pub banned: u64,
+    /// Dropped by the limit of the source address.
+    pub addr_limited: u64,
+    /// Dropped by the limit of the subnet of the source address.
+    pub subnet_limited: u64,
+    /// Buckets evicted for new sources because the table of buckets is full.
+    pub overflow: u64,
+}
+
+struct Bucket {
+    tokens: f32,
+    time: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit, time: Instant) -> Self {
+        Self { tokens: limit.burst, time }
+    }
+
+    fn refill(&mut self, limit: RateLimit, time: Instant) {
+        let elapsed = time.duration_since(self.time);
+        let elapsed = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 * 1e-9;
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.time = time;
+    }
+
+    fn take(&mut self, limit: RateLimit, time: Instant) -> bool {
+        self.refill(limit, time);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Returns `ip` with all bits after the first `prefix` cleared.
+fn mask(ip: IpAddr, prefix: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let bits = u32::from(ip);
+            let mask = if prefix == 0 { 0 } else { !0u32 << (32 - u32::from(prefix.min(32))) };
+            IpAddr::V4(Ipv4Addr::from(bits & mask))
+        }
+        IpAddr::V6(ip) => {
+            let bits = u128::from(ip);
+            let mask = if prefix == 0 { 0 } else { !0u128 << (128 - u32::from(prefix.min(128))) };
+            IpAddr::V6(Ipv6Addr::from(bits & mask))
+        }
+    }
+}
+
+pub struct Filter {
+    addr_limit: Option<RateLimit>,
+    subnet_limit: Option<RateLimit>,
+    subnet_v4: u8,
+    subnet_v6: u8,
+
+    max_buckets: usize,
+    addrs: HashMap<IpAddr, Bucket>,
+    subnets: HashMap<IpAddr, Bucket>,
+    /// Banned subnets by prefix length.
+    bans: BTreeMap<u8, HashMap<IpAddr, Option<Instant>>>,
+
+    stats: FilterStats,
+}
+
+impl Default for Filter {
+    fn default() -> Self { Self::new() }
+}
+
+impl Filter {
+    /// Default prefix length of IPv4 subnets.
+    pub const SUBNET_V4: u8 = 24;
+    /// Default prefix length of IPv6 subnets.
+    pub const SUBNET_V6: u8 = 48;
+    /// Default maximum number of tracked addresses and subnets.
+    pub const MAX_BUCKETS: usize = 64 * 1024;
+    /// Number of buckets looked at to pick the evicted one.
+    const EVICT_SAMPLES: usize = 8;
+
+    pub fn new() -> Self {
+        Self {
+            addr_limit: None,
+            subnet_limit: None,
+            subnet_v4: Self::SUBNET_V4,
+            subnet_v6: Self::SUBNET_V6,
+
+            max_buckets: Self::MAX_BUCKETS,
+            addrs: HashMap::new(),
+            subnets: HashMap::new(),
+            bans: BTreeMap::new(),
+
+            stats: FilterStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> FilterStats { self.stats }
+
+    pub fn addr_limit(&self) -> Option<RateLimit> { self.addr_limit }
+    pub fn subnet_limit(&self) -> Option<RateLimit> { self.subnet_limit }
+
+    /// Sets limit of datagrams from every source IP, `None` disables it.
+    pub fn set_addr_limit(&mut self, limit: Option<RateLimit>) {
+        self.addr_limit = limit;
+        self.addrs.clear();
+    }
+
+    /// Sets limit of datagrams from every subnet, `None` disables it.
+    pub fn set_subnet_limit(&mut self, limit: Option<RateLimit>) {
+        self.subnet_limit = limit;
+        self.subnets.clear();
+    }
+
+    /// Sets prefix lengths of subnets for `set_subnet_limit`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `v4` is greater than 32 or `v6` is greater than 128.
+    pub fn set_subnet_prefix(&mut self, v4: u8, v6: u8) {
+        assert!(v4 <= 32 && v6 <= 128, "bad subnet prefix: /{} /{}", v4, v6);
+        self.subnet_v4 = v4;
+        self.subnet_v6 = v6;
+        self.subnets.clear();
+    }
+
+    pub fn max_buckets(&self) -> usize { self.max_buckets }
+
+    /// Sets maximum number of tracked addresses and of tracked subnets.
+    ///
+    /// Sources are tracked while their buckets aren't full.
+    /// When the table is full a new source evicts the fullest of a few tracked buckets,
+    /// so flooding with spoofed sources doesn't take memory and doesn't lock out new sources,
+    /// while limited sources keep their empty buckets.
+    /// `update` forgets full buckets and frees the table.
+    pub fn set_max_buckets(&mut self, max: usize) {
+        self.max_buckets = max;
+    }
+
+    /// Bans the subnet `ip/prefix` from `time` for `duration`, `None` bans it forever.
+    ///
+    /// Use the full prefix length (32 or 128) to ban a single address.
+    pub fn ban(&mut self, ip: IpAddr, prefix: u8, time: Instant, duration: Option<Duration>) {
+        let prefix = prefix.min(if ip.is_ipv4() { 32 } else { 128 });
+        let expire = duration.map(|d| time + d);
+        self.bans.entry(prefix).or_insert_with(HashMap::new).insert(mask(ip, prefix), expire);
+    }
+
+    /// Removes the ban added by `ban` with the same `ip` and `prefix`.
+    pub fn unban(&mut self, ip: IpAddr, prefix: u8) -> bool {
+        let prefix = prefix.min(if ip.is_ipv4() { 32 } else { 128 });
+        let subnets = match self.bans.get_mut(&prefix) {
+            Some(subnets) => subnets,
+            None => return false,
+        };
+        let removed = subnets.remove(&mask(ip, prefix)).is_some();
+        if subnets.is_empty() {
+            self.bans.remove(&prefix);
+        }
+        removed
+    }
+
+    /// Returns `true` if `ip` is in any banned subnet.
+    ///
+    /// Costs a lookup for every prefix length in use.
+    pub fn is_banned(&self, ip: IpAddr, time: Instant) -> bool {
+        self.bans.iter().any(|(&prefix, subnets)| {
+            subnets.get(&mask(ip, prefix))
+                .map_or(false, |expire| expire.map_or(true, |expire| expire > time))
+        })
+    }
+
+    /// Returns the subnet of `ip` for `set_subnet_prefix` lengths.
+    pub fn subnet(&self, ip: IpAddr) -> IpAddr {
+        let prefix = if ip.is_ipv4() { self.subnet_v4 } else { self.subnet_v6 };
+        mask(ip, prefix)
+    }
+
+    /// Returns `true` if the datagram from `ip` should be processed.
+    pub fn check(&mut self, ip: IpAddr, time: Instant) -> bool {
+        if !self.check_connected(ip, time) {
+            return false;
+        }
+        if let Some(limit) = self.addr_limit {
+            let bucket = match take_bucket(&mut self.addrs, self.max_buckets, ip, limit, time, &mut self.stats) {
+                Some(bucket) => bucket,
+                None => return false,
+            };
+            if !bucket.take(limit, time) {
+                self.stats.addr_limited += 1;
+                return false;
+            }
+        }
+        if let Some(limit) = self.subnet_limit {
+            let subnet = self.subnet(ip);
+            let bucket = match take_bucket(&mut self.subnets, self.max_buckets, subnet, limit, time, &mut self.stats) {
+                Some(bucket) => bucket,
+                None => return false,
+            };
+            if !bucket.take(limit, time) {
+                self.stats.subnet_limited += 1;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns `true` if the datagram from the connected `ip` should be processed.
+    ///
+    /// Only bans apply to connected clients, they are authenticated by the server
+    /// and aren't cut off by rate limits or by the full table of buckets.
+    pub fn check_connected(&mut self, ip: IpAddr, time: Instant) -> bool {
+        if !self.bans.is_empty() && self.is_banned(ip, time) {
+            self.stats.banned += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Forgets full buckets and expired bans.
+    pub fn update(&mut self, time: Instant) {
+        if let Some(limit) = self.addr_limit {
+            self.addrs.retain(|_, b| { b.refill(limit, time); b.tokens < limit.burst });
+        }
+        if let Some(limit) = self.subnet_limit {
+            self.subnets.retain(|_, b| { b.refill(limit, time); b.tokens < limit.burst });
+        }
+        for subnets in self.bans.values_mut() {
+            subnets.retain(|_, expire| expire.map_or(true, |expire| expire > time));
+        }
+        self.bans.retain(|_, subnets| !subnets.is_empty());
+    }
+}
+
+/// Returns the bucket of `key`, a new one evicts the fullest of sampled buckets if there are `max` of them.
+///
+/// Spoofed sources send a datagram or two and leave nearly full buckets behind,
+/// while the buckets of limited sources are empty, so those are kept.
+fn take_bucket<'a>(
+    buckets: &'a mut HashMap<IpAddr, Bucket>, max: usize, key: IpAddr, limit: RateLimit, time: Instant,
+    stats: &mut FilterStats,
+) -> Option<&'a mut Bucket> {
+    if buckets.len() >= max && !buckets.contains_key(&key) {
+        let evicted = buckets.iter_mut()
+            .take(Filter::EVICT_SAMPLES)
+            .map(|(&key, bucket)| { bucket.refill(limit, time); (key, bucket.tokens) })
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))?
+            .0;
+        buckets.remove(&evicted);
+        stats.overflow += 1;
+    }
+    Some(buckets.entry(key).or_insert_with(|| Bucket::new(limit, time)))
+}
+
+#[test]
+fn subnet_mask() {
+    let ip: IpAddr = "192.168.1.77".parse().unwrap();
+    assert_eq!(mask(ip, 32), ip);
+    assert_eq!(mask(ip, 24), "192.168.1.0".parse::<IpAddr>().unwrap());
+    assert_eq!(mask(ip, 0), "0.0.0.0".parse::<IpAddr>().unwrap());
+    let ip: IpAddr = "2001:db8:1:2::7".parse().unwrap();
+    assert_eq!(mask(ip, 48), "2001:db8:1::".parse::<IpAddr>().unwrap());
+    assert_eq!(mask(ip, 128), ip);
+}
+
+#[test]
+fn rate_limit() {
+    let time = Instant::now();
+    let a: IpAddr = "10.0.0.1".parse().unwrap();
+    let b: IpAddr = "10.0.0.2".parse().unwrap();
+    let c: IpAddr = "10.0.1.1".parse().unwrap();
+
+    let mut filter = Filter::new();
+    filter.set_addr_limit(Some(RateLimit::new(10.0, 2.0)));
+    filter.set_subnet_limit(Some(RateLimit::new(10.0, 3.0)));
+
+    assert!(filter.check(a, time));
+    assert!(filter.check(a, time));
+    assert!(!filter.check(a, time));
+    assert!(filter.check(b, time));
+    assert!(!filter.check(b, time));
+    assert!(filter.check(c, time));
+    assert_eq!(filter.stats(), FilterStats { banned: 0, addr_limited: 1, subnet_limited: 1, overflow: 0 });
+
+    // refilled by rate
+    let time = time + Duration::from_millis(150);
+    assert!(filter.check(a, time));
+    assert!(!filter.check(a, time));
+
+    let time = time + Duration::from_secs(1);
+    filter.update(time);
+    assert!(filter.addrs.is_empty());
+    assert!(filter.subnets.is_empty());
+
+    // new sources evict the fullest buckets while the table is full
+    filter.set_max_buckets(2);
+    assert!(filter.check(a, time));
+    assert!(filter.check(a, time));
+    assert!(filter.check(c, time));
+    assert!(filter.check(b, time));
+    assert_eq!(filter.stats().overflow, 1);
+    assert_eq!(filter.addrs.len(), 2);
+    assert!(!filter.check(a, time));
+    assert_eq!(filter.stats().overflow, 1);
+
+    // connected clients are limited only by bans
+    assert!(filter.check_connected(a, time));
+    filter.ban(a, 32, time, None);
+    assert!(!filter.check_connected(a, time));
+}
+
+#[test]
+fn ban_list() {
+    let time = Instant::now();
+    let a: IpAddr = "10.0.0.1".parse().unwrap();
+    let b: IpAddr = "10.0.0.2".parse().unwrap();
+    let c: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+
+    let mut filter = Filter::new();
+    filter.ban(a, 32, time, None);
+    assert!(!filter.check(a, time));
+    assert!(filter.check(b, time));
+    assert!(filter.check(c, time));
+
+    filter.ban(b, 8, time, Some(Duration::from_secs(1)));
+    assert!(!filter.check(b, time));
+    assert_eq!(filter.stats().banned, 2);
+
+    filter.update(time + Duration::from_secs(2));
+    assert!(filter.check(b, time + Duration::from_secs(2)));
+    assert!(!filter.check(a, time + Duration::from_secs(2)));
+    assert!(filter.unban(a, 32));
+    assert!(!filter.unban(a, 32));
+    assert!(filter.check(a, time));
+    assert!(filter.bans.is_empty());
+}
@@ -0,0 +1,319 @@
+//! Fragmentation and reassembly of messages larger than payload.
+//!
+//! Every fragment is sent as an unreliable segment frame in separate packet.
+//! Lost fragments are not resent, so the whole message is dropped
+//! if some fragment doesn't arrive until timeout.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+use crate::{
+    frame::{self, Frame, Context},
+    protocol::MAX_PAYLOAD,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Message is larger than maximum size.
+    TooLarge,
+    /// Payload size is too small for fragment.
+    TooSmall,
+    /// Packet contains invalid frame.
+    Invalid,
+}
+
+struct Partial {
+    created: Instant,
+    data: Vec<u8>,
+    /// Byte ranges of received fragments, never overlap.
+    ranges: Vec<(usize, usize)>,
+    received: usize,
+    total: Option<usize>,
+}
+
+impl Partial {
+    fn new(created: Instant) -> Self {
+        Self {
+            created,
+            data: Vec::new(),
+            ranges: Vec::new(),
+            received: 0,
+            total: None,
+        }
+    }
+
+    /// Copies the fragment at `start` and returns `true` when the message is complete.
+    ///
+    /// Fragments overlapping already received ones are dropped,
+    /// so `received` is the number of covered bytes.
+    fn insert(&mut self, start: usize, last: bool, payload: &[u8]) -> bool {
+        let end = start + payload.len();
+        if self.ranges.iter().any(|&(s, e)| start < e && s < end) {
+            return false;
+        }
+        if self.total.map_or(false, |total| end > total) {
+            return false;
+        }
+        if last {
+            if self.total.is_some() || self.data.len() > end {
+                return false;
+            }
+            self.total = Some(end);
+        }
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[start..end].copy_from_slice(payload);
+        self.ranges.push((start, end));
+        self.received += payload.len();
+        self.total == Some(self.received)
+    }
+}
+
+/// Splits messages into fragments and reassembles them.
+pub struct Fragments {
+    payload: usize,
+    max_size: usize,
+    timeout: Duration,
+
+    next_message: u64,
+    highest: u64,
+
+    pending: HashMap<u64, Partial>,
+    finished: VecDeque<u64>,
+    complete: VecDeque<Vec<u8>>,
+}
+
+impl Default for Fragments {
+    fn default() -> Self {
+        Self::new(Self::MAX_SIZE, Self::TIMEOUT)
+    }
+}
+
+impl Fragments {
+    /// Prefix, 32-bit message number and offset.
+    pub const OVERHEAD: usize = 1 + 4 + 9;
+
+    pub const MAX_SIZE: usize = 256 * 1024;
+    pub const MAX_PENDING: usize = 16;
+    pub const MAX_FINISHED: usize = 64;
+    pub const TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn new(max_size: usize, timeout: Duration) -> Self {
+        Self {
+            payload: MAX_PAYLOAD,
+            max_size,
+            timeout,
+
+            next_message: 1,
+            highest: 0,
+
+            pending: HashMap::new(),
+            finished: VecDeque::new(),
+            complete: VecDeque::new(),
+        }
+    }
+
+    /// Sets maximum size of packet passed to `transmit`.
+    pub fn set_payload_size(&mut self, payload: usize) {
+        self.payload = payload.min(MAX_PAYLOAD);
+    }
+
+    pub fn payload_size(&self) -> usize { self.payload }
+
+    /// Number of partially received messages.
+    pub fn pending(&self) -> usize { self.pending.len() }
+
+    /// Splits the message and passes every fragment to `transmit`.
+    pub fn send<F>(&mut self, message: &[u8], mut transmit: F) -> Result<(), Error>
+        where F: FnMut(&mut [u8])
+    {
+        if message.len() > self.max_size {
+            return Err(Error::TooLarge);
+        }
+        if self.payload <= Self::OVERHEAD {
+            return Err(Error::TooSmall);
+        }
+
+        let message_num = self.next_message;
+        self.next_message += 1;
+
+        let ctx = Context { message_num: message_num - 1, .. Context::default() };
+        let size = self.payload - Self::OVERHEAD;
+        let mut buf = [0u8; MAX_PAYLOAD];
+
+        let mut offset = 0;
+        loop {
+            let end = (offset + size).min(message.len());
+            let frame = Frame::Unreliable {
+                message_num,
+                offset: offset as u64,
+                last: end == message.len(),
+                payload: &message[offset..end],
+            };
+            let len = frame::encode(ctx, &[frame], &mut buf[..self.payload])
+                .map_err(|_| Error::TooSmall)?;
+            transmit(&mut buf[..len]);
+
+            if end == message.len() {
+                return Ok(());
+            }
+            offset = end;
+        }
+    }
+
+    /// Processes received packet.
+    pub fn read(&mut self, now: Instant, packet: &[u8]) -> Result<(), Error> {
+        self.expire(now);
+
+        let ctx = Context { message_num: self.highest, .. Context::default() };
+        let mut frames = frame::decode(ctx, packet);
+        for frame in &mut frames {
+            match frame.map_err(|_| Error::Invalid)? {
+                Frame::Unreliable { message_num, offset, last, payload } => {
+                    let end = offset.checked_add(payload.len() as u64)
+                        .ok_or(Error::Invalid)?;
+                    if end > self.max_size as u64 {
+                        self.pending.remove(&message_num);
+                        return Err(Error::TooLarge);
+                    }
+                    if offset == 0 && last {
+                        self.complete.push_back(payload.to_vec());
+                        continue;
+                    }
+                    if self.finished.contains(&message_num) {
+                        continue;
+                    }
+
+                    if !self.pending.contains_key(&message_num) && self.pending.len() >= Self::MAX_PENDING {
+                        self.remove_oldest();
+                    }
+                    let done = self.pending.entry(message_num)
+                        .or_insert_with(|| Partial::new(now))
+                        .insert(offset as usize, last, payload);
+                    if done {
+                        let partial = self.pending.remove(&message_num).unwrap();
+                        self.complete.push_back(partial.data);
+                        if self.finished.len() >= Self::MAX_FINISHED {
+                            self.finished.pop_front();
+                        }
+                        self.finished.push_back(message_num);
+                    }
+                }
+                _ => return Err(Error::Invalid),
+            }
+        }
+        self.highest = frames.context().message_num;
+        Ok(())
+    }
+
+    /// Returns the next reassembled message.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        self.complete.pop_front()
+    }
+
+    /// Drops partially received messages after timeout.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.pending.retain(|_, p| p.created + timeout > now);
+    }
+
+    fn remove_oldest(&mut self) {
+        let oldest = self.pending.iter()
+            .min_by_key(|(_, p)| p.created)
+            .map(|(&id, _)| id);
+        if let Some(id) = oldest {
+            self.pending.remove(&id);
+        }
+    }
+}
+
+#[test]
+fn fragments_reassembly() {
+    let mut sender = Fragments::default();
+    let mut receiver = Fragments::default();
+    let now = Instant::now();
+
+    let small = b"small message".to_vec();
+    let large: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8).collect();
+
+    let mut packets = Vec::new();
+    sender.send(&large, |p| packets.push(p.to_vec())).unwrap();
+    assert_eq!(packets.len(), 50_000 / (MAX_PAYLOAD - Fragments::OVERHEAD) + 1);
+    assert!(packets.iter().all(|p| p.len() <= MAX_PAYLOAD));
+    sender.send(&small, |p| packets.push(p.to_vec())).unwrap();
+
+    // out of order and duplicated
+    packets.reverse();
+    let dup = packets[3].clone();
+    packets.push(dup);
+
+    for p in &packets {
+        receiver.read(now, p).unwrap();
+    }
+
+    assert_eq!(receiver.recv(), Some(small));
+    assert_eq!(receiver.recv(), Some(large));
+    assert_eq!(receiver.recv(), None);
+    assert_eq!(receiver.pending(), 0);
+}
+
+#[test]
+fn fragments_timeout_and_limits() {
+    let mut sender = Fragments::new(10_000, Duration::from_millis(100));
+    let mut receiver = Fragments::new(4_000, Duration::from_millis(100));
+    let now = Instant::now();
+
+    assert_eq!(sender.send(&[0; 10_001], |_| ()), Err(Error::TooLarge));
+
+    // lost fragment
+    let mut packets = Vec::new();
+    sender.send(&[1; 3000], |p| packets.push(p.to_vec())).unwrap();
+    for p in &packets[1..] {
+        receiver.read(now, p).unwrap();
+    }
+    assert_eq!(receiver.pending(), 1);
+    receiver.expire(now + Duration::from_millis(100));
+    assert_eq!(receiver.pending(), 0);
+    assert_eq!(receiver.recv(), None);
+
+    // larger than receiver limit
+    let mut packets = Vec::new();
+    sender.send(&[2; 5000], |p| packets.push(p.to_vec())).unwrap();
+    let errors = packets.iter().filter(|p| receiver.read(now, p).is_err()).count();
+    assert_eq!(errors, 2);
+    assert_eq!(receiver.recv(), None);
+    assert_eq!(receiver.pending(), 0);
+
+    sender.set_payload_size(Fragments::OVERHEAD);
+    assert_eq!(sender.send(&[3; 100], |_| ()), Err(Error::TooSmall));
+}
+
+#[test]
+fn fragments_overlap_and_overflow() {
+    let mut receiver = Fragments::default();
+    let now = Instant::now();
+
+    let packet = |offset: u64, last: bool, payload: &[u8]| {
+        let mut buf = [0u8; MAX_PAYLOAD];
+        let frame = Frame::Unreliable { message_num: 1, offset, last, payload };
+        let len = frame::encode(Context::default(), &[frame], &mut buf).unwrap();
+        buf[..len].to_vec()
+    };
+
+    // overlapping fragments don't count as received bytes
+    receiver.read(now, &packet(0, false, &[1; 5])).unwrap();
+    receiver.read(now, &packet(3, false, &[2; 5])).unwrap();
+    receiver.read(now, &packet(10, true, &[3; 5])).unwrap();
+    assert_eq!(receiver.recv(), None);
+    receiver.read(now, &packet(5, false, &[4; 5])).unwrap();
+    let mut expected = vec![1; 5];
+    expected.extend_from_slice(&[4; 5]);
+    expected.extend_from_slice(&[3; 5]);
+    assert_eq!(receiver.recv(), Some(expected));
+
+    assert_eq!(receiver.read(now, &packet(u64::max_value() - 2, true, &[5; 5])), Err(Error::Invalid));
+    assert_eq!(receiver.read(now, &packet(u64::max_value() - 10, true, &[5; 5])), Err(Error::TooLarge));
+    assert_eq!(receiver.pending(), 0);
+}
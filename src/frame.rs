@@ -1,615 +1,627 @@
-// see https://github.com/ValveSoftware/GameNetworkingSockets/blob/master/src/steamnetworkingsockets/clientlib/SNP_WIRE_FORMAT.md
-
-// ack, stop and ack_bits
-
-// ack, delay
-
-
-// 1001wnnn latest_received_pkt_num latest_received_delay [N] [ack_block_0 ... ack_block_N]
-//     w    size of latest_received_pkt_num
-//     0    16-bit
-//     1    32-bit
-//      nnn  number of blocks in this frame.
-//      000 -
-//      ... |
-//      110 - use this number (as n-1)
-//      111 - number of blocks is >6, explicit count byte N is present.  (Max 255 blocks)
-fn parse_ack(buf) {
-    let wnnn = buf.read_u8()?;
-    let w    = wnnn & 0b1000;
-    let  nnn = wnnn & 0b0111;
-
-    let latest_received_pkt_num = if w == 0 {
-        buf.read_u16()? as u32
-    } else {
-        buf.read_u32()?
-    };
-    let latest_received_delay = buf.read_u16()?;
-    let num_blocks = if nnn == 0b111 {
-        buf.read_u8()
-    } else {
-        nnn
-    };
-
-    for i in 0..num_blocks {
-        let p = buf.read_u8()?;
-        let a = p >> 8;
-        let n = p & 0b1111;
-
-        let a = if a & 0b1000 != 0 {
-        } else {
-        }
-    }
+//! Frames packed into the plaintext of payload packets.
+//!
+//! Port of the [SNP wire format][snp].
+//! Every payload packet contains zero or more frames, one after another.
+//!
+//! ```txt
+//! 00emosss [message_num] [offset] [size] data - unreliable segment
+//! 010mmsss [stream_pos] [size] data           - reliable segment
+//! 100000ss [offset]                           - stop_waiting
+//! 1001wnnn [latest] [delay] [N] [blocks]      - ack
+//! ```
+//!
+//! Unreliable segment:
+//!
+//! ```txt
+//! e: 0: There's more data after this in the unreliable message.
+//!    1: This is the last segment in the unreliable message.
+//! m: encoded size of message_num
+//!    First segment in packet: message_num is absolute.  Only bottom N bits are sent.
+//!        0: 16-bits
+//!        1: 32-bits
+//!    Subsequent segments: message number field is relative to previous
+//!        0: no message number field follows, assume 1 greater than previous segment
+//!        1: varint encoded offset from previous follows
+//!    NOTE: any reliable segment frames sent after unreliable data
+//!    will *also* increment the current message number.
+//! o: offset of this segment within message
+//!    0: Zero offset, segment is first in message.  No offset field follows.
+//!    1: varint encoded offset follows
+//! sss: Size of data
+//!    000-100: Append upper three bits to lower 8 bits in explicit size field,
+//!             which follows  (Max value is 0x4ff = 1279, which is larger than our MTU)
+//!    101,110: Reserved
+//!    111: This is the last frame, so message data extends to the end of the packet.
+//! ```
+//!
+//! Reliable segment:
+//!
+//! ```txt
+//! mm: encoded size of stream_pos
+//!     First reliable segment in packet: stream_pos is absolute.  Only bottom N bits are sent.
+//!         00: 24-bits
+//!         01: 32-bits
+//!         10: 48-bits
+//!         11: Reserved
+//!     Subsequent segments: stream_pos is relative to end of previous segment.
+//!         00: no field follows, segment is contiguous
+//!         01: 8-bit offset
+//!         10: 16-bit offset
+//!         11: 32-bit offset
+//! sss: Size of data, same as for unreliable segments.
+//! ```
+//!
+//! Stop waiting:
+//!
+//! ```txt
+//! ss: size of the offset
+//!     00: 8-bits
+//!     01: 16-bits
+//!     10: 24-bits
+//!     11: 64-bits
+//! offset: number of packet being decoded minus the lowest packet number
+//!         the receiver should send acks for, minus one.
+//! ```
+//!
+//! Ack:
+//!
+//! ```txt
+//! w: size of latest received packet number
+//!    0: 16-bits
+//!    1: 32-bits
+//! nnn: number of blocks in this frame.
+//!    000-110: use this number
+//!    111: number of blocks is >6, explicit count byte N is present.  (Max 255 blocks)
+//! delay: 16-bits, time since the latest packet was received.
+//! block: Aaaa Nnnn [upper acks] [upper nacks]
+//!    aaa: lower bits of the number of acks
+//!    A:   varint encoded upper bits of the number of acks follows
+//!    nnn: lower bits of the number of nacks
+//!    N:   varint encoded upper bits of the number of nacks follows
+//! ```
+//!
+//! Truncated numbers are restored using reference values from [`Context`].
+//!
+//! [snp]: https://github.com/ValveSoftware/GameNetworkingSockets/blob/master/src/steamnetworkingsockets/clientlib/SNP_WIRE_FORMAT.md
+
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use smallvec::SmallVec;
+use std::io::{self, Write, Error, ErrorKind::{InvalidData, InvalidInput}};
+use crate::prefix_varint::{ReadPrefixVarint, WritePrefixVarint};
+
+/// Maximum size of segment data which can be encoded with explicit size field.
+pub const MAX_SEGMENT: usize = 0x4FF;
+
+/// Maximum number of blocks in ack frame.
+pub const MAX_ACK_BLOCKS: usize = 0xFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AckBlock {
+    /// Number of acknowledged packets.
+    pub acks: u64,
+    /// Number of lost packets before acknowledged.
+    pub nacks: u64,
 }
 
+pub type AckBlocks = SmallVec<[AckBlock; 8]>;
 
-enum Frame<'a> {
-    StopWaiting(u64),
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame<'a> {
     Unreliable {
         message_num: u64,
-        offset: Option<std::num::NonZeroU64>,
+        /// Offset of this segment within message.
+        offset: u64,
+        /// It is the last segment in the message.
+        last: bool,
         payload: &'a [u8],
     },
+    Reliable {
+        stream_pos: u64,
+        payload: &'a [u8],
+    },
+    /// The lowest packet number the receiver should send acks for.
+    StopWaiting(u64),
+    Ack {
+        latest: u64,
+        delay: u16,
+        /// Blocks from newest to oldest.
+        blocks: AckBlocks,
+    },
 }
 
-/*
-fn decode_payload(buf: &[u8]) -> std::io::Result<Frame> {
-    use byteorder::{LE, ReadBytesExt};
-
-    use std::num::NonZeroU64;
-    use std::io::{Error, ErrorKind::{
-        InvalidData,
-    }};
-
-
-    let mut p = &buf[..];
+/// Reference values for truncated numbers.
+///
+/// The encoder should use its best guess of the values known to the decoder.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Context {
+    /// Sequence number of the packet.
+    pub pkt_num: u64,
+    /// Highest unreliable message number seen by the receiver.
+    pub message_num: u64,
+    /// Next position expected in the reliable stream.
+    pub stream_pos: u64,
+    /// Next sequence number of packet sent by the receiver of ack.
+    pub next_send: u64,
+}
 
-    let prefix = p.read_u8()?;
+#[inline]
+fn nearest_with_same_lower_bits(lower: u64, bits: u32, reference: u64) -> u64 {
+    let size = 1u64 << bits;
+    let diff = lower.wrapping_sub(reference) & (size - 1);
+    if diff < size >> 1 {
+        reference.wrapping_add(diff)
+    } else {
+        reference.wrapping_sub(size - diff)
+    }
+}
 
-    match prefix & 0b11000000 {
-        0b00000000 => {
-            let e = (prefix & 0b100000) != 0;
-            let m = (prefix & 0b010000) != 0;
-            let o = (prefix & 0b001000) != 0;
-            let sss = (prefix & 0b111) as usize;
+/// Decoder does not warn about values within quarter of range.
+#[inline]
+fn fits(value: u64, bits: u32, reference: u64) -> bool {
+    let quarter = 1i64 << (bits - 2);
+    let diff = value.wrapping_sub(reference) as i64;
+    diff > -quarter && diff < quarter
+}
 
-            let message_num = unimplemented!(); //buf.read_varint();
+#[inline]
+fn read_size(buf: &mut &[u8], sss: u8) -> io::Result<usize> {
+    match sss {
+        0b111 => Ok(buf.len()),
+        0b101 | 0b110 => Err(Error::new(InvalidData, "reserved size")),
+        _ => Ok(buf.read_u8()? as usize | (sss as usize) << 8),
+    }
+}
 
-            let offset = if o {
-                //Some(buf.read_varint())
-                NonZeroU64::new(unimplemented!())
-            } else {
-                None
-            };
+#[inline]
+fn read_segment<'a>(buf: &mut &'a [u8], size: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < size {
+        return Err(Error::new(InvalidData, "segment out of packet"));
+    }
+    let (data, tail) = buf.split_at(size);
+    *buf = tail;
+    Ok(data)
+}
 
-            let size = match sss {
-                0b111 => buf.len() - (buf.len() - p.len()),
-                0b101 | 0b110 =>
-                    return Err(Error::new(InvalidData, "reserved size")),
-                _ => {
-                    (p.read_u8()? as usize) | sss << 8
-                }
-            };
+#[inline]
+fn size_bits(size: usize, last: bool) -> io::Result<u8> {
+    if last {
+        Ok(0b111)
+    } else if size <= MAX_SEGMENT {
+        Ok((size >> 8) as u8)
+    } else {
+        Err(Error::new(InvalidInput, "segment too large"))
+    }
+}
 
-            let is_first = offset.is_none();
-            let is_last = e && offset.is_none();
+#[inline]
+fn write_segment<W: Write>(buf: &mut W, payload: &[u8], last: bool) -> io::Result<()> {
+    if !last {
+        buf.write_u8(payload.len() as u8)?;
+    }
+    buf.write_all(payload)
+}
 
-            // FIXME: may panic
-            let payload = &buf[p.len()..size];
+/// Encodes frames of one packet.
+pub struct Encoder {
+    ctx: Context,
+    message_num: Option<u64>,
+    stream_pos: Option<u64>,
+}
 
-            Ok(Frame::Unreliable { message_num, offset, payload })
-        }
-        0b01000000 => {
-            unimplemented!("reliable")
-        }
-        0b10000000 => {
-            // control
-
-            //if prefix & 0b1000_0000 {
-            //}
-            let size = match prefix & 0b11 {
-                0b00 => 1,
-                0b01 => 2,
-                0b10 => 3,
-                0b11 => 8,
-                _ => unsafe { std::hint::unreachable_unchecked() },
-            };
-            Ok(Frame::StopWaiting(p.read_uint::<LE>(size)?))
-        }
-        _ => Err(Error::new(InvalidData, "reserved prefix")),
+impl Encoder {
+    pub fn new(ctx: Context) -> Self {
+        Self { ctx, message_num: None, stream_pos: None }
     }
-}
-*/
-
-/*
-    00emosss [message_num] [offset] [size] data
-
-    e: 0: There's more data after ths in the unreliable message.
-          (Will be sent in another packet.)
-       1: This is the last segment in the unreliable message.
-    m: encoded size of message_num
-       First segment in packet: message_num is absolute.  Only bottom N bits are sent.
-           0: 16-bits
-           1: 32-bits
-       Subsequent segments: message number field is relative to previous
-           0: no message number field follows, assume 1 greater than previous segment
-           1: Var-int encoded offset from previous follows
-           (NOTE: while encoding/decoding a packet, any reliable segment frames sent after unreliable data
-           will *also* increment the current message number, even though the message number is *not*
-           guaranteed to match that reliable segment.  Since in practice the message number often will
-           match, making this encode/decode rule affords a small optimization.)
-    o:  offset of this segment within message
-        If first segment in packet, or message number differs from previous segment in packet:
-            0: Zero offset, segment is first in message.  No offset field follows.
-            1: varint-encoded offset follows
-    sss: Size of data
-        000-100: Append upper three bits to lower 8 bits in explicit size field,
-                 which follows  (Max value is 0x4ff = 1279, which is larger than our MTU)
-        101,110: Reserved
-        111: This is the last frame, so message data extends to the end of the packet.
-*/
-
-/*
-fn decode(mut buf: &[u8]) {
-    let kind = buf.read_u8()
-    let let mut nCurMsgNum = 0i64;
-    let mut nDecodeReliablePos = 0i64;
-
-    loop {
-        if kind & 0xC0 == 0x00 {
-            // Unreliable segment
-
-            // Decode message number
-            if nCurMsgNum == 0 {
-                // First unreliable frame.
-                // Message number is absolute, but only bottom N bits are sent
-                static const char szUnreliableMsgNumOffset[] = "unreliable msgnum";
-                int64 nLowerBits, nMask;
-                if nFrameType & 0x10 {
-                    READ_32BITU( nLowerBits, szUnreliableMsgNumOffset );
-                    nMask = 0xffffffff;
-                    nCurMsgNum = NearestWithSameLowerBits( (int32)nLowerBits, m_receiverState.m_nHighestSeenMsgNum );
-                } else {
-                    READ_16BITU( nLowerBits, szUnreliableMsgNumOffset );
-                    nMask = 0xffff;
-                    nCurMsgNum = NearestWithSameLowerBits( (int16)nLowerBits, m_receiverState.m_nHighestSeenMsgNum );
+
+    /// Writes the frame into `buf`.
+    ///
+    /// Data of segment extends to the end of the packet if `last` is true.
+    pub fn encode<W: Write>(&mut self, buf: &mut W, frame: &Frame, last: bool) -> io::Result<()> {
+        match *frame {
+            Frame::Unreliable { message_num, offset, last: e, payload } => {
+                let mut prefix = size_bits(payload.len(), last)?;
+                if e { prefix |= 0b0010_0000; }
+                if offset != 0 { prefix |= 0b0000_1000; }
+
+                match self.message_num {
+                    None => {
+                        let narrow = fits(message_num, 16, self.ctx.message_num);
+                        if !narrow && !fits(message_num, 32, self.ctx.message_num) {
+                            return Err(Error::new(InvalidInput, "message_num too far"));
+                        }
+                        if !narrow { prefix |= 0b0001_0000; }
+                        buf.write_u8(prefix)?;
+                        if narrow {
+                            buf.write_u16::<LE>(message_num as u16)?;
+                        } else {
+                            buf.write_u32::<LE>(message_num as u32)?;
+                        }
+                    }
+                    Some(current) if message_num == current.wrapping_add(1) => {
+                        buf.write_u8(prefix)?;
+                    }
+                    Some(current) if message_num >= current => {
+                        buf.write_u8(prefix | 0b0001_0000)?;
+                        buf.write_prefix_varint(message_num - current)?;
+                    }
+                    Some(_) => return Err(Error::new(InvalidInput, "message_num is decreased")),
                 }
-                assert!((nCurMsgNum & nMask) == nLowerBits);
+                self.message_num = Some(message_num);
 
-                if nCurMsgNum <= 0 {
-                    DECODE_ERROR("SNP decode unreliable msgnum underflow.  %llx mod %llx, highest seen %llx",
-                        (unsigned long long)nLowerBits, (unsigned long long)( nMask+1 ), (unsigned long long)m_receiverState.m_nHighestSeenMsgNum );
+                if offset != 0 {
+                    buf.write_prefix_varint(offset)?;
                 }
-                if std::abs(nCurMsgNum - m_receiverState.m_nHighestSeenMsgNum) > (nMask>>2) {
-                    // We really should never get close to this boundary.
-                    SpewWarningRateLimited( usecNow, "Sender sent abs unreliable message number using %llx mod %llx, highest seen %llx\n",
-                        (unsigned long long)nLowerBits, (unsigned long long)( nMask+1 ), (unsigned long long)m_receiverState.m_nHighestSeenMsgNum );
+                write_segment(buf, payload, last)
+            }
+            Frame::Reliable { stream_pos, payload } => {
+                let sss = size_bits(payload.len(), last)?;
+                match self.stream_pos {
+                    None => {
+                        let reference = self.ctx.stream_pos;
+                        if fits(stream_pos, 24, reference) {
+                            buf.write_u8(0b0100_0000 | sss)?;
+                            buf.write_u24::<LE>(stream_pos as u32 & 0xFF_FFFF)?;
+                        } else if fits(stream_pos, 32, reference) {
+                            buf.write_u8(0b0100_1000 | sss)?;
+                            buf.write_u32::<LE>(stream_pos as u32)?;
+                        } else {
+                            buf.write_u8(0b0101_0000 | sss)?;
+                            buf.write_u48::<LE>(stream_pos & 0xFFFF_FFFF_FFFF)?;
+                        }
+                    }
+                    Some(end) if stream_pos >= end => {
+                        let offset = stream_pos - end;
+                        if offset == 0 {
+                            buf.write_u8(0b0100_0000 | sss)?;
+                        } else if offset <= 0xFF {
+                            buf.write_u8(0b0100_1000 | sss)?;
+                            buf.write_u8(offset as u8)?;
+                        } else if offset <= 0xFFFF {
+                            buf.write_u8(0b0101_0000 | sss)?;
+                            buf.write_u16::<LE>(offset as u16)?;
+                        } else if offset <= 0xFFFF_FFFF {
+                            buf.write_u8(0b0101_1000 | sss)?;
+                            buf.write_u32::<LE>(offset as u32)?;
+                        } else {
+                            return Err(Error::new(InvalidInput, "stream_pos offset too large"));
+                        }
+                    }
+                    Some(_) => return Err(Error::new(InvalidInput, "stream_pos is decreased")),
                 }
-            } else {
-                if nFrameType & 0x10 {
-                    uint64 nMsgNumOffset;
-                    READ_VARINT( nMsgNumOffset, "unreliable msgnum offset" );
-                    nCurMsgNum += nMsgNumOffset;
-                } else {
-                    nCurMsgNum += 2;
-                    //++nCurMsgNum += 1;
-                    // wtf?
+                let end = stream_pos.checked_add(payload.len() as u64)
+                    .ok_or_else(|| Error::new(InvalidInput, "stream_pos overflow"))?;
+                self.stream_pos = Some(end);
+                if let Some(n) = self.message_num.as_mut() {
+                    *n = n.wrapping_add(1);
                 }
+                write_segment(buf, payload, last)
             }
-            if nCurMsgNum > m_receiverState.m_nHighestSeenMsgNum {
-                m_receiverState.m_nHighestSeenMsgNum = nCurMsgNum;
-            }
-
-            // Decode segment offset in message
-            let nOffset = 0u32;
-            if nFrameType & 0x08 {
-                READ_VARINT(nOffset, "unreliable data offset");
+            Frame::StopWaiting(min) => {
+                if min >= self.ctx.pkt_num {
+                    return Err(Error::new(InvalidInput, "stop_waiting is not less than pkt_num"));
+                }
+                let offset = self.ctx.pkt_num - min - 1;
+                if offset <= 0xFF {
+                    buf.write_u8(0b1000_0000)?;
+                    buf.write_u8(offset as u8)
+                } else if offset <= 0xFFFF {
+                    buf.write_u8(0b1000_0001)?;
+                    buf.write_u16::<LE>(offset as u16)
+                } else if offset <= 0xFF_FFFF {
+                    buf.write_u8(0b1000_0010)?;
+                    buf.write_u24::<LE>(offset as u32)
+                } else {
+                    buf.write_u8(0b1000_0011)?;
+                    buf.write_u64::<LE>(offset)
+                }
             }
-
-            // Decode size, locate segment data
-            READ_SEGMENT_DATA_SIZE(unreliable)
-            assert!( cbSegmentSize > 0 ); // !TEST! Bogus assert, zero byte messages are OK.  Remove after testing
-
-            // Receive the segment
-            bool bLastSegmentInMessage = ( nFrameType & 0x20 ) != 0;
-            SNP_ReceiveUnreliableSegment( nCurMsgNum, nOffset, pSegmentData, cbSegmentSize, bLastSegmentInMessage, usecNow );
-        } else if nFrameType & 0xE0 == 0x40 {
-            // Reliable segment
-
-            // First reliable segment?
-            if nDecodeReliablePos == 0 {
-                // Stream position is absolute.  How many bits?
-                static const char szFirstReliableStreamPos[] = "first reliable streampos";
-                int64 nOffset, nMask;
-                switch nFrameType & (3<<3) {
-                    case 0<<3: READ_24BITU( nOffset, szFirstReliableStreamPos ); nMask = (1ll<<24)-1; break;
-                    case 1<<3: READ_32BITU( nOffset, szFirstReliableStreamPos ); nMask = (1ll<<32)-1; break;
-                    case 2<<3: READ_48BITU( nOffset, szFirstReliableStreamPos ); nMask = (1ll<<48)-1; break;
-                    default: DECODE_ERROR( "Reserved reliable stream pos size" );
+            Frame::Ack { latest, delay, ref blocks } => {
+                if blocks.len() > MAX_ACK_BLOCKS {
+                    return Err(Error::new(InvalidInput, "too many ack blocks"));
                 }
+                let narrow = fits(latest, 16, self.ctx.next_send);
+                let mut prefix = 0b1001_0000 | blocks.len().min(7) as u8;
+                if !narrow { prefix |= 0b0000_1000; }
 
-                // What do we expect to receive next?
-                int64 nExpectNextStreamPos = m_receiverState.m_nReliableStreamPos + len( m_receiverState.m_bufReliableStream );
-
-                // Find the stream offset closest to that
-                nDecodeReliablePos = ( nExpectNextStreamPos & ~nMask ) + nOffset;
-                if nDecodeReliablePos + (nMask>>1) < nExpectNextStreamPos {
-                    nDecodeReliablePos += nMask+1;
-                    assert!( ( nDecodeReliablePos & nMask ) == nOffset );
-                    assert!( nExpectNextStreamPos < nDecodeReliablePos );
-                    assert!( nExpectNextStreamPos + (nMask>>1) >= nDecodeReliablePos );
-                }
-                if nDecodeReliablePos <= 0 {
-                    DECODE_ERROR( "SNP decode first reliable stream pos underflow.  %llx mod %llx, expected next %llx",
-                        (unsigned long long)nOffset, (unsigned long long)( nMask+1 ), (unsigned long long)nExpectNextStreamPos );
+                buf.write_u8(prefix)?;
+                if narrow {
+                    buf.write_u16::<LE>(latest as u16)?;
+                } else {
+                    buf.write_u32::<LE>(latest as u32)?;
                 }
-                if std::abs( nDecodeReliablePos - nExpectNextStreamPos ) > (nMask>>2) {
-                    // We really should never get close to this boundary.
-                    SpewWarningRateLimited( usecNow, "Sender sent reliable stream pos using %llx mod %llx, expected next %llx\n",
-                        (unsigned long long)nOffset, (unsigned long long)( nMask+1 ), (unsigned long long)nExpectNextStreamPos );
+                buf.write_u16::<LE>(delay)?;
+                if blocks.len() >= 7 {
+                    buf.write_u8(blocks.len() as u8)?;
                 }
-            } else {
-                // Subsequent reliable message encode the position as an offset from previous.
-                static const char szOtherReliableStreamPos[] = "reliable streampos offset";
-                int64 nOffset;
-                switch ( nFrameType & (3<<3) )
-                {
-                    case 0<<3: nOffset = 0; break;
-                    case 1<<3: READ_8BITU( nOffset, szOtherReliableStreamPos ); break;
-                    case 2<<3: READ_16BITU( nOffset, szOtherReliableStreamPos ); break;
-                    default: READ_32BITU( nOffset, szOtherReliableStreamPos ); break;
+
+                for block in blocks {
+                    let mut header = (block.acks & 7) << 4 | (block.nacks & 7);
+                    if block.acks > 7 { header |= 0x80; }
+                    if block.nacks > 7 { header |= 0x08; }
+                    buf.write_u8(header as u8)?;
+                    if block.acks > 7 {
+                        buf.write_prefix_varint(block.acks >> 3)?;
+                    }
+                    if block.nacks > 7 {
+                        buf.write_prefix_varint(block.nacks >> 3)?;
+                    }
                 }
-                nDecodeReliablePos += nOffset;
+                Ok(())
             }
+        }
+    }
+}
 
-            // Decode size, locate segment data
-            READ_SEGMENT_DATA_SIZE( reliable )
+/// Packs frames into `buf` and returns number of bytes written.
+///
+/// Data of the last segment extends to the end of the packet.
+pub fn encode(ctx: Context, frames: &[Frame], mut buf: &mut [u8]) -> io::Result<usize> {
+    let start_len = buf.len();
+    let mut encoder = Encoder::new(ctx);
+    for (i, frame) in frames.iter().enumerate() {
+        encoder.encode(&mut buf, frame, i + 1 == frames.len())?;
+    }
+    Ok(start_len - buf.len())
+}
 
-            // Ingest the segment.  If it seems fishy, abort processing of this packet
-            // and do not acknowledge to the sender.
-            if !SNP_ReceiveReliableSegment( nPktNum, nDecodeReliablePos, pSegmentData, cbSegmentSize, usecNow ) {
-                return false;
-            }
+/// Returns iterator over frames of the packet.
+pub fn decode<'a>(ctx: Context, buf: &'a [u8]) -> Decoder<'a> {
+    Decoder::new(ctx, buf)
+}
 
-            // Advance pointer for the next reliable segment, if any.
-            nDecodeReliablePos += cbSegmentSize;
+/// Decodes frames of one packet.
+///
+/// Stops after the first error.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    ctx: Context,
+    message_num: Option<u64>,
+    stream_pos: Option<u64>,
+}
 
-            // Decoding rules state that if we have established a message number,
-            // (from an earlier unreliable message), then we advance it.
-            if nCurMsgNum > 0 {
-                ++nCurMsgNum;
-            }
-        } else if kind & 0xFC == 0x80 {
-            // Stop waiting
-
-            int64 nOffset = 0;
-            static const char szStopWaitingOffset[] = "stop_waiting offset";
-            switch ( nFrameType & 3 )
-            {
-                case 0: READ_8BITU( nOffset, szStopWaitingOffset ); break;
-                case 1: READ_16BITU( nOffset, szStopWaitingOffset ); break;
-                case 2: READ_24BITU( nOffset, szStopWaitingOffset ); break;
-                case 3: READ_64BITU( nOffset, szStopWaitingOffset ); break;
-            }
-            if ( nOffset >= nPktNum )
-            {
-                DECODE_ERROR( "stop_waiting pktNum %llu offset %llu", nPktNum, nOffset );
-            }
-            ++nOffset;
-            int64 nMinPktNumToSendAcks = nPktNum-nOffset;
-            if ( nMinPktNumToSendAcks == m_receiverState.m_nMinPktNumToSendAcks )
-                continue;
-            if ( nMinPktNumToSendAcks < m_receiverState.m_nMinPktNumToSendAcks )
-            {
-                // Sender must never reduce this number!  Check for bugs or bogus sender
-                if ( nPktNum >= m_receiverState.m_nPktNumUpdatedMinPktNumToSendAcks )
-                {
-                    DECODE_ERROR( "SNP stop waiting reduced %lld (pkt %lld) -> %lld (pkt %lld)",
-                        (long long)m_receiverState.m_nMinPktNumToSendAcks,
-                        (long long)m_receiverState.m_nPktNumUpdatedMinPktNumToSendAcks,
-                        (long long)nMinPktNumToSendAcks,
-                        (long long)nPktNum
-                        );
-                }
-                continue;
-            }
-            SpewType( steamdatagram_snp_log_packet+1, "  %s decode pkt %lld stop waiting: %lld (was %lld)",
-                m_sName.c_str(),
-                (long long)nPktNum,
-                (long long)nMinPktNumToSendAcks, (long long)m_receiverState.m_nMinPktNumToSendAcks );
-            m_receiverState.m_nMinPktNumToSendAcks = nMinPktNumToSendAcks;
-            m_receiverState.m_nPktNumUpdatedMinPktNumToSendAcks = nPktNum;
-
-            // Trim from the front of the packet gap list,
-            // we can stop reporting these losses to the sender
-            while ( !m_receiverState.m_mapPacketGaps.empty() )
-            {
-                auto h = m_receiverState.m_mapPacketGaps.begin();
-                if ( h->first > m_receiverState.m_nMinPktNumToSendAcks )
-                    break;
-                if ( h->second.m_nEnd > m_receiverState.m_nMinPktNumToSendAcks )
-                {
-                    // Ug.  You're not supposed to modify the key in a map.
-                    // I suppose that's legit, since you could violate the ordering.
-                    // but in this case I know that this change is OK.
-                    const_cast<int64 &>( h->first ) = m_receiverState.m_nMinPktNumToSendAcks;
-                    break;
-                }
-                m_receiverState.m_mapPacketGaps.erase(h);
-            }
-        } else if kind & 0xF0 == 0x90 {
-            // Ack
-
-            // Parse latest received sequence number
-            int64 nLatestRecvSeqNum;
-            {
-                static const char szAckLatestPktNum[] = "ack latest pktnum";
-                int64 nLowerBits, nMask;
-                if nFrameType & 0x40 {
-                    READ_32BITU( nLowerBits, szAckLatestPktNum );
-                    nMask = 0xffffffff;
-                    nLatestRecvSeqNum = NearestWithSameLowerBits( (int32)nLowerBits, m_statsEndToEnd.m_nNextSendSequenceNumber );
-                } else {
-                    READ_16BITU( nLowerBits, szAckLatestPktNum );
-                    nMask = 0xffff;
-                    nLatestRecvSeqNum = NearestWithSameLowerBits( (int16)nLowerBits, m_statsEndToEnd.m_nNextSendSequenceNumber );
-                }
-                assert!( ( nLatestRecvSeqNum & nMask ) == nLowerBits );
+impl<'a> Decoder<'a> {
+    pub fn new(ctx: Context, buf: &'a [u8]) -> Self {
+        Self { buf, ctx, message_num: None, stream_pos: None }
+    }
 
-                // Find the message number that is closes to
-                if nLatestRecvSeqNum < 0 {
-                    DECODE_ERROR( "SNP decode ack latest pktnum underflow.  %llx mod %llx, next send %llx",
-                        (unsigned long long)nLowerBits, (unsigned long long)( nMask+1 ), (unsigned long long)m_statsEndToEnd.m_nNextSendSequenceNumber );
-                }
-                if std::abs( nLatestRecvSeqNum - m_statsEndToEnd.m_nNextSendSequenceNumber ) > (nMask>>2) {
-                    // We really should never get close to this boundary.
-                    SpewWarningRateLimited( usecNow, "Sender sent abs latest recv pkt number using %llx mod %llx, next send %llx\n",
-                        (unsigned long long)nLowerBits, (unsigned long long)( nMask+1 ), (unsigned long long)m_statsEndToEnd.m_nNextSendSequenceNumber );
-                }
-                if nLatestRecvSeqNum >= m_statsEndToEnd.m_nNextSendSequenceNumber {
-                    DECODE_ERROR( "SNP decode ack latest pktnum %lld (%llx mod %llx), but next outoing packet is %lld (%llx).",
-                        (long long)nLatestRecvSeqNum, (unsigned long long)nLowerBits, (unsigned long long)( nMask+1 ),
-                        (long long)m_statsEndToEnd.m_nNextSendSequenceNumber, (unsigned long long)m_statsEndToEnd.m_nNextSendSequenceNumber
-                    );
-                }
+    /// Returns context with updated highest message number.
+    pub fn context(&self) -> Context { self.ctx }
+
+    fn decode(&mut self) -> io::Result<Frame<'a>> {
+        let buf = &mut self.buf;
+        let prefix = buf.read_u8()?;
+
+        if prefix & 0b1100_0000 == 0b0000_0000 {
+            let m = prefix & 0b0001_0000 != 0;
+            let message_num = match self.message_num {
+                None if m => nearest_with_same_lower_bits(
+                    u64::from(buf.read_u32::<LE>()?), 32, self.ctx.message_num),
+                None => nearest_with_same_lower_bits(
+                    u64::from(buf.read_u16::<LE>()?), 16, self.ctx.message_num),
+                Some(current) if m => current.wrapping_add(buf.read_prefix_varint()?),
+                Some(current) => current.wrapping_add(1),
+            };
+            self.message_num = Some(message_num);
+            if message_num > self.ctx.message_num {
+                self.ctx.message_num = message_num;
             }
 
-            SpewType( steamdatagram_snp_log_packet+1, "  %s decode pkt %lld latest recv %lld\n",
-                m_sName.c_str(),
-                (long long)nPktNum, (long long)nLatestRecvSeqNum
-            );
-
-            // Locate our bookkeeping for this packet, or the latest one before it
-            // Remember, we have a sentinel with a low, invalid packet number
-            assert!( !m_senderState.m_mapInFlightPacketsByPktNum.empty() );
-            auto inFlightPkt = m_senderState.m_mapInFlightPacketsByPktNum.upper_bound( nLatestRecvSeqNum );
-            --inFlightPkt;
-            assert!( inFlightPkt->first <= nLatestRecvSeqNum );
-
-            // Parse out delay, and process the ping
-            {
-                uint16 nPackedDelay;
-                READ_16BITU( nPackedDelay, "ack delay" );
-                if ( nPackedDelay != 0xffff && inFlightPkt->first == nLatestRecvSeqNum )
-                {
-                    SteamNetworkingMicroseconds usecDelay = SteamNetworkingMicroseconds( nPackedDelay ) << k_nAckDelayPrecisionShift;
-                    SteamNetworkingMicroseconds usecElapsed = usecNow - inFlightPkt->second.m_usecWhenSent;
-                    assert!( usecElapsed >= 0 );
-
-                    // Account for their reported delay, and calculate ping, in MS
-                    int msPing = (usecElapsed - usecDelay) / 1000;
-
-                    // Does this seem bogus?  (We allow a small amount of slop.)
-                    // NOTE: A malicious sender could lie about this delay, tricking us
-                    // into thinking that the real network latency is low, they are just
-                    // delaying their replies.  This actually matters, since the ping time
-                    // is an input into the rate calculation.  So we might need to
-                    // occasionally send pings that require an immediately reply, and
-                    // if those ping times seem way out of whack with the ones where they are
-                    // allowed to send a delay, take action against them.
-                    if msPing < -1 {
-                        // Either they are lying or some weird timer stuff is happening.
-                        // Either way, discard it.
-
-                        SpewType( steamdatagram_snp_log_ackrtt, "%s decode pkt %lld latest recv %lld delay %lluusec INVALID ping %lldusec\n",
-                            m_sName.c_str(),
-                            (long long)nPktNum, (long long)nLatestRecvSeqNum,
-                            (unsigned long long)usecDelay,
-                            (long long)usecElapsed
-                        );
-                    } else {
-                        // Clamp, if we have slop
-                        if msPing < 0 {
-                            msPing = 0;
-                        }
-                        m_statsEndToEnd.m_ping.ReceivedPing( msPing, usecNow );
-
-                        // Spew
-                        SpewType( steamdatagram_snp_log_ackrtt, "%s decode pkt %lld latest recv %lld delay %.1fms ping %.1fms\n",
-                            m_sName.c_str(),
-                            (long long)nPktNum, (long long)nLatestRecvSeqNum,
-                            (float)(usecDelay * 1e-3 ),
-                            (float)(usecElapsed * 1e-3 )
-                        );
-                    }
+            let offset = if prefix & 0b0000_1000 != 0 {
+                buf.read_prefix_varint()?
+            } else {
+                0
+            };
+
+            let size = read_size(buf, prefix & 0b111)?;
+            let payload = read_segment(buf, size)?;
+            let last = prefix & 0b0010_0000 != 0;
+            Ok(Frame::Unreliable { message_num, offset, last, payload })
+        } else if prefix & 0b1110_0000 == 0b0100_0000 {
+            let mm = (prefix >> 3) & 0b11;
+            let stream_pos = match self.stream_pos {
+                None => {
+                    let (lower, bits) = match mm {
+                        0b00 => (u64::from(buf.read_u24::<LE>()?), 24),
+                        0b01 => (u64::from(buf.read_u32::<LE>()?), 32),
+                        0b10 => (buf.read_u48::<LE>()?, 48),
+                        _ => return Err(Error::new(InvalidData, "reserved stream_pos size")),
+                    };
+                    nearest_with_same_lower_bits(lower, bits, self.ctx.stream_pos)
                 }
-            }
+                Some(end) => end.checked_add(match mm {
+                    0b00 => 0,
+                    0b01 => u64::from(buf.read_u8()?),
+                    0b10 => u64::from(buf.read_u16::<LE>()?),
+                    _ => u64::from(buf.read_u32::<LE>()?),
+                }).ok_or_else(|| Error::new(InvalidData, "stream_pos overflow"))?,
+            };
 
-            // Parse number of blocks
-            int nBlocks = nFrameType&7;
-            if nBlocks == 7 {
-                READ_8BITU( nBlocks, "ack num blocks" );
+            let size = read_size(buf, prefix & 0b111)?;
+            let payload = read_segment(buf, size)?;
+            let end = stream_pos.checked_add(size as u64)
+                .ok_or_else(|| Error::new(InvalidData, "stream_pos overflow"))?;
+            self.stream_pos = Some(end);
+            if let Some(n) = self.message_num.as_mut() {
+                *n = n.wrapping_add(1);
             }
-
-            // If they actually sent us any blocks, that means they are fragmented.
-            // We should make sure and tell them to stop sending us these nacks
-            // and move forward.  This could be more robust, if we remember when
-            // the last stop_waiting value we sent was, and when we sent it.
-            if nBlocks > 0 {
-                // Decrease flush delay the more blocks they send us.
-                SteamNetworkingMicroseconds usecDelay = 250*1000 / nBlocks;
-                m_receiverState.m_usecWhenFlushAck = std::min( m_receiverState.m_usecWhenFlushAck, usecNow + usecDelay );
+            Ok(Frame::Reliable { stream_pos, payload })
+        } else if prefix & 0b1111_1100 == 0b1000_0000 {
+            let offset = match prefix & 0b11 {
+                0b00 => u64::from(buf.read_u8()?),
+                0b01 => u64::from(buf.read_u16::<LE>()?),
+                0b10 => u64::from(buf.read_u24::<LE>()?),
+                _ => buf.read_u64::<LE>()?,
+            };
+            if offset >= self.ctx.pkt_num {
+                return Err(Error::new(InvalidData, "stop_waiting offset out of pkt_num"));
             }
+            Ok(Frame::StopWaiting(self.ctx.pkt_num - offset - 1))
+        } else if prefix & 0b1111_0000 == 0b1001_0000 {
+            let latest = if prefix & 0b0000_1000 != 0 {
+                nearest_with_same_lower_bits(u64::from(buf.read_u32::<LE>()?), 32, self.ctx.next_send)
+            } else {
+                nearest_with_same_lower_bits(u64::from(buf.read_u16::<LE>()?), 16, self.ctx.next_send)
+            };
+            let delay = buf.read_u16::<LE>()?;
+            let count = match prefix & 0b111 {
+                0b111 => buf.read_u8()?,
+                n => n,
+            };
 
-            // Process ack blocks, working backwards from the latest received sequence number.
-            // Note that we have to parse all this stuff out, even if it's old news (packets older
-            // than the stop_aiting value we sent), because we need to do that to get to the rest
-            // of the packet.
-            bool bAckedReliableRange = false;
-            int64 nPktNumAckEnd = nLatestRecvSeqNum+1;
-            while ( nBlocks >= 0 )
-            {
-
-                // Parse out number of acks/nacks.
-                // Have we parsed all the real blocks?
-                int64 nPktNumAckBegin, nPktNumNackBegin;
-                if ( nBlocks == 0 )
-                {
-                    // Implicit block.  Everything earlier between the last
-                    // NACK and the stop_waiting value is implicitly acked!
-                    if ( nPktNumAckEnd <= m_senderState.m_nMinPktWaitingOnAck )
-                        break;
-
-                    nPktNumAckBegin = m_senderState.m_nMinPktWaitingOnAck;
-                    nPktNumNackBegin = nPktNumAckBegin;
-                    SpewType( steamdatagram_snp_log_packet+1, "  %s decode pkt %lld ack last block ack begin %lld\n",
-                        m_sName.c_str(),
-                        (long long)nPktNum, (long long)nPktNumAckBegin );
+            let mut blocks = AckBlocks::new();
+            for _ in 0..count {
+                let header = buf.read_u8()?;
+                let mut acks = u64::from(header >> 4 & 7);
+                let mut nacks = u64::from(header & 7);
+                if header & 0x80 != 0 {
+                    acks |= buf.read_prefix_varint()? << 3;
                 }
-                else
-                {
-                    uint8 nBlockHeader;
-                    READ_8BITU( nBlockHeader, "ack block header" );
-
-                    // Ack count?
-                    int64 numAcks = ( nBlockHeader>> 4 ) & 7;
-                    if ( nBlockHeader & 0x80 )
-                    {
-                        uint64 nUpperBits;
-                        READ_VARINT( nUpperBits, "ack count upper bits" );
-                        if ( nUpperBits > 100000 )
-                            DECODE_ERROR( "Ack count of %llu<<3 is crazy", (unsigned long long)nUpperBits );
-                        numAcks |= nUpperBits<<3;
-                    }
-                    nPktNumAckBegin = nPktNumAckEnd - numAcks;
-                    if ( nPktNumAckBegin < 0 )
-                        DECODE_ERROR( "Ack range underflow, end=%lld, num=%lld", (long long)nPktNumAckEnd, (long long)numAcks );
-
-                    // Extended nack count?
-                    int64 numNacks = nBlockHeader & 7;
-                    if ( nBlockHeader & 0x08)
-                    {
-                        uint64 nUpperBits;
-                        READ_VARINT( nUpperBits, "nack count upper bits" );
-                        if ( nUpperBits > 100000 )
-                            DECODE_ERROR( "Nack count of %llu<<3 is crazy", nUpperBits );
-                        numNacks |= nUpperBits<<3;
-                    }
-                    nPktNumNackBegin = nPktNumAckBegin - numNacks;
-                    if ( nPktNumNackBegin < 0 )
-                        DECODE_ERROR( "Nack range underflow, end=%lld, num=%lld", (long long)nPktNumAckBegin, (long long)numAcks );
-
-                    SpewType( steamdatagram_snp_log_packet+1, "  %s decode pkt %lld nack [%lld,%lld) ack [%lld,%lld)\n",
-                        m_sName.c_str(),
-                        (long long)nPktNum,
-                        (long long)nPktNumNackBegin, (long long)( nPktNumNackBegin + numNacks ),
-                        (long long)nPktNumAckBegin, (long long)( nPktNumAckBegin + numAcks )
-                    );
+                if header & 0x08 != 0 {
+                    nacks |= buf.read_prefix_varint()? << 3;
                 }
+                blocks.push(AckBlock { acks, nacks });
+            }
+            Ok(Frame::Ack { latest, delay, blocks })
+        } else {
+            Err(Error::new(InvalidData, "reserved prefix"))
+        }
+    }
+}
 
-                // Process acks first.
-                assert!( nPktNumAckBegin >= 0 );
-                while ( inFlightPkt->first >= nPktNumAckBegin )
-                {
-                    assert!( inFlightPkt->first < nPktNumAckEnd );
-
-                    // Scan reliable segments, and see if any are marked for retry or are in flight
-                    for ( const SNPRange_t &relRange: inFlightPkt->second.m_vecReliableSegments )
-                    {
-
-                        // If range is present, it should be in only one of these two tables.
-                        if ( m_senderState.m_listInFlightReliableRange.erase( relRange ) == 0 )
-                        {
-                            if ( m_senderState.m_listReadyRetryReliableRange.erase( relRange ) > 0 )
-                            {
-
-                                // When we put stuff into the reliable retry list, we mark it as pending again.
-                                // But now it's acked, so it's no longer pending, even though we didn't send it.
-                                m_senderState.m_cbPendingReliable -= int( relRange.length() );
-                                assert!( m_senderState.m_cbPendingReliable >= 0 );
-
-                                bAckedReliableRange = true;
-                            }
-                        }
-                        else
-                        {
-                            bAckedReliableRange = true;
-                            assert!( m_senderState.m_listReadyRetryReliableRange.count( relRange ) == 0 );
-                        }
-                    }
+impl<'a> Iterator for Decoder<'a> {
+    type Item = io::Result<Frame<'a>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let frame = self.decode();
+        if frame.is_err() {
+            self.buf = &[];
+        }
+        Some(frame)
+    }
+}
 
-                    // Check if this was the next packet we were going to timeout, then advance
-                    // pointer.  This guy didn't timeout.
-                    if ( inFlightPkt == m_senderState.m_itNextInFlightPacketToTimeout )
-                        ++m_senderState.m_itNextInFlightPacketToTimeout;
+#[cfg(test)]
+fn round_trip(ctx: Context, frames: &[Frame]) -> usize {
+    let mut buf = [0u8; crate::protocol::MAX_PAYLOAD];
+    let len = encode(ctx, frames, &mut buf[..]).unwrap();
+    let decoded: Vec<_> = decode(ctx, &buf[..len]).map(Result::unwrap).collect();
+    assert_eq!(&decoded[..], frames);
+    len
+}
 
-                    // No need to track this anymore, remove from our table
-                    inFlightPkt = m_senderState.m_mapInFlightPacketsByPktNum.erase( inFlightPkt );
-                    --inFlightPkt;
-                    assert!( !m_senderState.m_mapInFlightPacketsByPktNum.empty() );
-                }
+#[test]
+fn nearest() {
+    assert_eq!(nearest_with_same_lower_bits(0x0001, 16, 0x1_FFFF), 0x2_0001);
+    assert_eq!(nearest_with_same_lower_bits(0xFFFF, 16, 0x2_0001), 0x1_FFFF);
+    assert_eq!(nearest_with_same_lower_bits(0x1234, 16, 0x5_1200), 0x5_1234);
+    assert_eq!(nearest_with_same_lower_bits(0xFFFF, 16, 0), !0);
+}
 
-                // Ack of in-flight end-to-end stats?
-                if ( nPktNumAckBegin <= m_statsEndToEnd.m_pktNumInFlight && m_statsEndToEnd.m_pktNumInFlight < nPktNumAckEnd )
-                    m_statsEndToEnd.InFlightPktAck( usecNow );
+#[test]
+fn unreliable_segments() {
+    let ctx = Context { message_num: 0x1_0000, .. Context::default() };
+    round_trip(ctx, &[
+        Frame::Unreliable { message_num: 0x1_0005, offset: 0, last: true, payload: b"first" },
+        Frame::Unreliable { message_num: 0x1_0006, offset: 0, last: false, payload: b"next" },
+        Frame::Unreliable { message_num: 0x1_0010, offset: 300, last: true, payload: b"skip" },
+        Frame::Unreliable { message_num: 0x1_0010, offset: 304, last: false, payload: &[7; 600] },
+    ]);
+
+    // 32-bit message_num
+    let len = round_trip(Context::default(), &[
+        Frame::Unreliable { message_num: 0x8_0000, offset: 0, last: true, payload: b"far" },
+    ]);
+    assert_eq!(len, 1 + 4 + 3);
+}
 
-                // Process nacks.
-                assert!(nPktNumNackBegin >= 0);
-                while inFlightPkt->first >= nPktNumNackBegin {
-                    assert!( inFlightPkt->first < nPktNumAckEnd );
-                    SNP_SenderProcessPacketNack( inFlightPkt->first, inFlightPkt->second, "NACK" );
+#[test]
+fn reliable_segments() {
+    let ctx = Context { stream_pos: 1_000, .. Context::default() };
+    round_trip(ctx, &[
+        Frame::Reliable { stream_pos: 1_000, payload: b"hello" },
+        Frame::Reliable { stream_pos: 1_005, payload: b"world" },
+        Frame::Reliable { stream_pos: 1_200, payload: &[1; 100] },
+        Frame::Reliable { stream_pos: 100_000, payload: &[2; 10] },
+        Frame::Reliable { stream_pos: 0x1_0000_0000, payload: b"tail" },
+    ]);
+
+    let ctx = Context { stream_pos: 0x7_0000_0000, .. Context::default() };
+    round_trip(ctx, &[Frame::Reliable { stream_pos: 0x8_0000_0000, payload: b"48" }]);
+}
 
-                    // We'll keep the record on hand, though, in case an ACK comes in
-                    --inFlightPkt;
-                }
+#[test]
+fn mixed_segments() {
+    let ctx = Context { pkt_num: 0x1_2345, next_send: 900, .. Context::default() };
+    let blocks: AckBlocks = (0..10u64)
+        .map(|i| AckBlock { acks: i * 5, nacks: 100 - i * 9 })
+        .collect();
+    round_trip(ctx, &[
+        Frame::Ack { latest: 899, delay: 31, blocks },
+        Frame::StopWaiting(0x1_2300),
+        Frame::StopWaiting(0x1_2345 - 0x100 - 1),
+        Frame::StopWaiting(0),
+        Frame::Unreliable { message_num: 7, offset: 0, last: true, payload: b"unreliable" },
+        Frame::Reliable { stream_pos: 3, payload: b"reliable" },
+        Frame::Unreliable { message_num: 9, offset: 0, last: true, payload: b"after" },
+        Frame::Ack { latest: 1, delay: 0, blocks: AckBlocks::new() },
+    ]);
+}
 
-                // Continue on to the the next older block
-                nPktNumAckEnd = nPktNumNackBegin;
-                --nBlocks;
-            }
+#[test]
+fn invalid_frames() {
+    let ctx = Context { pkt_num: 10, .. Context::default() };
+    let mut buf = [0u8; 64];
+
+    let frame = Frame::StopWaiting(10);
+    assert!(encode(ctx, &[frame], &mut buf[..]).is_err());
+    let frame = Frame::Reliable { stream_pos: 0, payload: &[0; MAX_SEGMENT + 1] };
+    assert!(Encoder::new(ctx).encode(&mut &mut buf[..], &frame, false).is_err());
+    let frame = Frame::Unreliable { message_num: 1 << 33, offset: 0, last: true, payload: b"far" };
+    assert!(encode(ctx, &[frame], &mut buf[..]).is_err());
+
+    // reserved prefix, reserved size, truncated segment, stop_waiting out of pkt_num
+    for data in &[&[0b1100_0000][..], &[0b0010_0101, 0, 0], &[0b0010_0000, 0, 0, 5, 1], &[0b1000_0000, 10]] {
+        let mut frames = decode(ctx, data);
+        assert!(frames.next().unwrap().is_err());
+        assert!(frames.next().is_none());
+    }
 
-            // Should we check for discarding reliable messages we are keeping around in case
-            // of retransmission, since we know now that they were delivered?
-            if bAckedReliableRange {
-                m_senderState.RemoveAckedReliableMessageFromUnackedList();
-
-                // Spew where we think the peer is decoding the reliable stream
-                if g_eSteamDatagramDebugOutputDetailLevel <= k_ESteamNetworkingSocketsDebugOutputType_Debug {
-                    int64 nPeerReliablePos = m_senderState.m_nReliableStreamPos;
-                    if ( !m_senderState.m_listInFlightReliableRange.empty() )
-                        nPeerReliablePos = std::min( nPeerReliablePos, m_senderState.m_listInFlightReliableRange.begin()->first.m_nBegin );
-                    if ( !m_senderState.m_listReadyRetryReliableRange.empty() )
-                        nPeerReliablePos = std::min( nPeerReliablePos, m_senderState.m_listReadyRetryReliableRange.begin()->first.m_nBegin );
-
-                    SpewType( steamdatagram_snp_log_packet+1, "  %s decode pkt %lld peer reliable pos = %lld\n",
-                        m_sName.c_str(),
-                        (long long)nPktNum, (long long)nPeerReliablePos );
-                }
-            }
+    // stream_pos overflow
+    let mut frames = decode(Context::default(), &[0x40, 0xFF, 0xFF, 0xFF, 0x01, 0xAA]);
+    assert_eq!(frames.next().unwrap().unwrap_err().kind(), InvalidData);
+    assert!(frames.next().is_none());
+}
 
-            // Check if any of this was new info, then advance our stop_waiting value.
-            if nLatestRecvSeqNum > m_senderState.m_nMinPktWaitingOnAck {
-                SpewType( steamdatagram_snp_log_packet, "  %s updating min_waiting_on_ack %lld -> %lld\n",
-                    m_sName.c_str(),
-                    (long long)m_senderState.m_nMinPktWaitingOnAck, (long long)nLatestRecvSeqNum );
-                m_senderState.m_nMinPktWaitingOnAck = nLatestRecvSeqNum;
-                //m_senderState.m_usecWhenAdvancedMinPktWaitingOnAck = usecNow;
-            }
-        }
-        else
-        {
-            DECODE_ERROR( "Invalid SNP frame lead byte 0x%02x", nFrameType );
+#[test]
+fn payload_packet() {
+    use crate::{protocol::{Packet, MTU}, crypto::{keygen, DefaultAead}};
+
+    let protocol = 0x11223344_55667788;
+    let key = keygen();
+    let seq = 0x1_0000;
+
+    let ctx = Context { pkt_num: seq, .. Context::default() };
+    let frames = [
+        Frame::StopWaiting(seq - 5),
+        Frame::Reliable { stream_pos: 0, payload: b"reliable data" },
+        Frame::Unreliable { message_num: 1, offset: 0, last: true, payload: b"unreliable data" },
+    ];
+
+    let mut payload = [0u8; crate::protocol::MAX_PAYLOAD];
+    let len = encode(ctx, &frames, &mut payload[..]).unwrap();
+
+    let mut packet = [0u8; MTU];
+    let len = Packet::encode_payload::<DefaultAead>(protocol, &mut packet, seq, &key, &mut payload[..len]).unwrap();
+
+    match Packet::decode(&mut packet[..len]).unwrap() {
+        Packet::Payload { seq, buf, tag } => {
+            Packet::open::<DefaultAead>(protocol, buf, seq, 0, tag, &key).unwrap();
+            let ctx = Context { pkt_num: seq, .. Context::default() };
+            let decoded: Vec<_> = decode(ctx, buf).map(Result::unwrap).collect();
+            assert_eq!(&decoded[..], &frames[..]);
         }
+        _ => panic!("not a payload packet"),
     }
 }
-*/
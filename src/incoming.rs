@@ -1,18 +1,22 @@
 use std::{
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     collections::HashMap,
     sync::atomic::{AtomicU64, Ordering},
     time::Duration,
+    marker::PhantomData,
 };
 
 use crate::{
     token::{
         ChallengeToken,
         PrivateToken,
+        ResumeToken,
+        Ticket,
         CHALLENGE_LEN,
+        USER,
     },
-    crypto::{keygen, KEY, HMAC},
-    protocol::{Packet, Request},
+    crypto::{keygen, hchacha20, verify::verify, Aead, DefaultAead, KEY, HMAC},
+    protocol::{Packet, Request, Resume, HandshakeError, COOKIE_LEN},
     unix_time,
 };
 
@@ -20,17 +24,29 @@ use crate::{
 pub struct KeyPair {
     expire: u64,
     timeout: u32,
+    mtu: usize,
     send_key: [u8; KEY],
     recv_key: [u8; KEY],
 }
 
 impl KeyPair {
-    fn new(expire: u64, token: &PrivateToken) -> Self {
+    fn new(expire: u64, mtu: usize, token: &PrivateToken) -> Self {
         Self {
             recv_key: *token.client_key(),
             send_key: *token.server_key(),
             timeout: token.timeout(),
             expire,
+            mtu,
+        }
+    }
+
+    fn resumed(mtu: usize, token: &ResumeToken) -> Self {
+        Self {
+            recv_key: *token.client_key(),
+            send_key: *token.server_key(),
+            timeout: token.timeout(),
+            expire: token.expire(),
+            mtu,
         }
     }
 
@@ -40,9 +56,13 @@ impl KeyPair {
     pub fn timeout(&self) -> Duration {
         Duration::from_secs(u64::from(self.timeout))
     }
+    pub fn mtu(&self) -> usize { self.mtu }
 }
 
-pub struct Incoming {
+/// Time for which the cookie is valid, in seconds.
+const COOKIE_TIMEOUT: u64 = 10;
+
+pub struct Incoming<C: Aead = DefaultAead> {
     protocol: u64,
     timestamp: u64,
     private: [u8; KEY],
@@ -51,9 +71,16 @@ pub struct Incoming {
 
     pending: HashMap<SocketAddr, KeyPair>,
     token_history: HashMap<[u8; HMAC], (SocketAddr, u64)>,
+    ticket_history: HashMap<[u8; HMAC], u64>,
+
+    cookie_key: [u8; KEY],
+    cookie_threshold: usize,
+    requests: usize,
+
+    _aead: PhantomData<fn() -> C>,
 }
 
-impl Incoming {
+impl<C: Aead> Incoming<C> {
     pub fn new(protocol: u64, private: [u8; KEY]) -> Self {
         Self {
             protocol,
@@ -63,35 +90,109 @@ impl Incoming {
             timestamp: unix_time(),
             pending: HashMap::new(),
             token_history: HashMap::new(),
+            ticket_history: HashMap::new(),
+            cookie_key: keygen(),
+            cookie_threshold: 0,
+            requests: 0,
+            _aead: PhantomData,
         }
     }
 
-    pub fn open_request<'a>(&self, r: &'a mut Request) -> Result<(u64, &'a PrivateToken), ()> {
-        if !r.is_valid(self.protocol, self.timestamp) { return Err(()) }
-        r.open_token(&self.private)
+    pub fn open_request(&self, r: &Request) -> Result<(u64, PrivateToken), HandshakeError> {
+        r.check(self.protocol, self.timestamp)?;
+        r.open_token::<C>(&self.private)
     }
 
-    pub fn open_response<'a>(&self, buf: &'a mut [u8; 8 + CHALLENGE_LEN], addr: &SocketAddr, seq: u64, prefix: u8, tag: &[u8; HMAC])
-        -> Result<([u8; KEY], &'a ChallengeToken), ()>
+    pub fn open_response(&self, buf: &mut [u8; 8 + CHALLENGE_LEN], addr: &SocketAddr, seq: u64, prefix: u8, tag: &[u8; HMAC])
+        -> Result<([u8; KEY], ChallengeToken), HandshakeError>
     {
-        let pending = self.pending.get(addr).ok_or(())?;
-        Packet::open(self.protocol, buf, seq, prefix, tag, &pending.recv_key)?;
-        let token = ChallengeToken::decode_packet(buf, &self.key)?;
+        let pending = self.pending.get(addr).ok_or(HandshakeError::NotPending)?;
+        Packet::open::<C>(self.protocol, buf, seq, prefix, tag, &pending.recv_key)
+            .map_err(|()| HandshakeError::BadMac)?;
+        let token = ChallengeToken::decode_packet::<C>(buf, &self.key)?;
         Ok((pending.send_key, token))
     }
 
     pub fn gen_challenge(&self, seq: u64, buf: &mut [u8], token: &PrivateToken) -> usize {
         let challenge_seq = self.sequence.fetch_add(1, Ordering::Relaxed);
         let mut m = ChallengeToken::new(token.client_id(), *token.user())
-            .encode_packet(challenge_seq, &self.key);
-        Packet::encode_handshake(self.protocol, buf, seq, token.server_key(), &mut m).unwrap()
+            .encode_packet::<C>(challenge_seq, &self.key);
+        Packet::encode_handshake::<C>(self.protocol, buf, seq, token.server_key(), &mut m).unwrap()
+    }
+
+    /// Opens resume packet, returns keys and the token.
+    pub fn open_resume(&self, r: &Resume, mtu: usize) -> Result<(KeyPair, ResumeToken), HandshakeError> {
+        r.check(self.protocol)?;
+        let token = r.open_token::<C>(&self.key)?;
+        if token.expire() <= self.timestamp { return Err(HandshakeError::Expired) }
+        Ok((KeyPair::resumed(mtu, &token), token))
+    }
+
+    /// Generates resumption ticket valid until `expire`.
+    ///
+    /// It's sealed by the key of this server, so it works only with the same `Server`.
+    pub fn gen_ticket(&self, client_id: u64, expire: u64, timeout: u32, user: [u8; USER]) -> Ticket {
+        let seq = self.sequence.fetch_add(1, Ordering::Relaxed);
+        Ticket::new::<C>(ResumeToken::generate(client_id, expire, timeout, user), seq, &self.key)
+    }
+
+    /// Returns `false` if the ticket was already used, tickets are single use.
+    pub fn add_ticket_history(&mut self, hmac: [u8; HMAC], expire: u64) -> bool {
+        self.ticket_history.insert(hmac, expire).is_none()
+    }
+
+    pub fn cookie_threshold(&self) -> usize { self.cookie_threshold }
+    pub fn set_cookie_threshold(&mut self, threshold: usize) { self.cookie_threshold = threshold }
+
+    /// Counts the request, returns `true` if the cookie is required for it.
+    ///
+    /// Cookies are required after `cookie_threshold` requests in the current second.
+    pub fn count_request(&mut self) -> bool {
+        self.requests += 1;
+        self.requests > self.cookie_threshold
+    }
+
+    /// Generates cookie for `addr` with the current timestamp.
+    pub fn gen_cookie(&self, addr: &SocketAddr) -> [u8; COOKIE_LEN] {
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        cookie[8..].copy_from_slice(&self.cookie_mac(addr, self.timestamp));
+        cookie
+    }
+
+    /// Checks that the cookie was generated for `addr` by this server and isn't expired.
+    pub fn check_cookie(&self, cookie: &[u8; COOKIE_LEN], addr: &SocketAddr) -> bool {
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&cookie[..8]);
+        let timestamp = u64::from_le_bytes(timestamp);
+        if timestamp > self.timestamp || timestamp + COOKIE_TIMEOUT <= self.timestamp {
+            return false;
+        }
+        let mac = self.cookie_mac(addr, timestamp);
+        verify(&cookie[8..], &mac)
+    }
+
+    fn cookie_mac(&self, addr: &SocketAddr, timestamp: u64) -> [u8; HMAC] {
+        let mut input = [0u8; 16];
+        input[..2].copy_from_slice(b"ck");
+        input[2..4].copy_from_slice(&addr.port().to_le_bytes());
+        input[8..].copy_from_slice(&timestamp.to_le_bytes());
+        let key = hchacha20(&input, &self.cookie_key, None);
+
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        let mut mac = [0u8; HMAC];
+        mac.copy_from_slice(&hchacha20(&ip.octets(), &key, None)[..HMAC]);
+        mac
     }
 
     pub fn remove(&mut self, addr: &SocketAddr) -> Option<KeyPair> {
         self.pending.remove(addr)
     }
-    pub fn insert(&mut self, addr: SocketAddr, expire: u64, token: &PrivateToken) {
-        self.pending.entry(addr).or_insert_with(|| KeyPair::new(expire, &token));
+    pub fn insert(&mut self, addr: SocketAddr, expire: u64, mtu: usize, token: &PrivateToken) {
+        self.pending.entry(addr).or_insert_with(|| KeyPair::new(expire, mtu, &token));
     }
     pub fn add_token_history(&mut self, hmac: [u8; HMAC], addr: SocketAddr, expire: u64) -> bool {
         self.token_history.entry(hmac).or_insert((addr, expire)).0 == addr
@@ -100,6 +201,10 @@ impl Incoming {
         let timestamp = unix_time();
         self.pending.retain(|_, p| p.expire > timestamp);
         self.token_history.retain(|_, v| v.1 > timestamp);
+        self.ticket_history.retain(|_, &mut expire| expire > timestamp);
+        if timestamp != self.timestamp {
+            self.requests = 0;
+        }
         self.timestamp = timestamp;
     }
 }
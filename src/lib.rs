@@ -43,28 +43,45 @@
 
 mod client;
 mod server;
+mod server_system;
 mod server_list;
 mod incoming;
 mod replay_protection;
+mod path_mtu;
+mod batch;
+mod buffer;
+mod stats;
+mod rekey;
+mod filter;
+mod codec;
 mod simulator;
 
 pub mod prefix_varint;
+pub mod frame;
+pub mod fragment;
 pub mod bitset;
 pub mod token;
 pub mod protocol;
 pub mod crypto;
 
-//pub mod server_system;
-
 pub use crate::{
     replay_protection::ReplayProtection,
     client::{Client, State, ConnectingState, Error},
-    server::{Server, Connection},
+    server::{Server, Connection, ServerEvent, DisconnectReason},
+    server_system::ThreadedServer,
+    protocol::{DenyReason, CloseReason, HandshakeError},
     server_list::ServerList,
     incoming::Incoming,
     simulator::{SimulatedSocket, SimulatorConfig, config_socket},
+    fragment::{Fragments, Error as FragmentError},
+    stats::Stats,
+    filter::{Filter, FilterStats, RateLimit},
+    codec::DecodeError,
+    buffer::{Buffer, BufferPool},
 };
 
+pub use oni_reliable::reliable::{Reliable, Error as ReliableError};
+
 /*
 pub const IP4_HEADER: usize = 20 + 8;
 pub const IP6_HEADER: usize = 40 + 8;
@@ -81,6 +98,7 @@ pub fn unix_time() -> u64 {
 }
 
 use std::{io, net::{SocketAddr, UdpSocket}};
+use crate::protocol::MTU;
 
 pub trait Socket: Sized {
     /// Creates a socket from the given address.
@@ -101,6 +119,24 @@ pub trait Socket: Sized {
     /// This will return an error when the length of `buf` is greater than `MTU`.
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
 
+    /// Receives up to `bufs.len()` datagrams,
+    /// `meta` gets the length and the origin of each one.
+    /// On success, returns the number of received datagrams.
+    ///
+    /// Implementation can receive less datagrams than available.
+    /// The default one calls `recv_from` in a loop.
+    fn recv_batch(&self, bufs: &mut [[u8; MTU]], meta: &mut [(usize, SocketAddr)]) -> io::Result<usize> {
+        batch::recv_loop(self, bufs, meta)
+    }
+    /// Sends datagrams with the length and the destination from `meta`.
+    /// On success, returns the number of sent datagrams.
+    ///
+    /// Implementation can send less datagrams than given.
+    /// The default one calls `send_to` in a loop.
+    fn send_batch(&self, bufs: &[[u8; MTU]], meta: &[(usize, SocketAddr)]) -> io::Result<usize> {
+        batch::send_loop(self, bufs, meta)
+    }
+
     fn connect(&self, addr: SocketAddr) -> io::Result<()>;
     fn send(&self, buf: &[u8]) -> io::Result<usize>;
     fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
@@ -108,6 +144,13 @@ pub trait Socket: Sized {
     /// ## Simulated socket
     /// Does nothing.
     fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Returns the file descriptor to wait for datagrams with `poll`.
+    ///
+    /// ## Simulated socket
+    /// Returns `None`, `ThreadedServer` checks it at a fixed interval.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> { None }
 }
 
 impl Socket for UdpSocket {
@@ -126,6 +169,14 @@ impl Socket for UdpSocket {
     fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
         UdpSocket::send_to(self, buf, addr)
     }
+    #[cfg(target_os = "linux")]
+    fn recv_batch(&self, bufs: &mut [[u8; MTU]], meta: &mut [(usize, SocketAddr)]) -> io::Result<usize> {
+        batch::recvmmsg(self, bufs, meta)
+    }
+    #[cfg(target_os = "linux")]
+    fn send_batch(&self, bufs: &[[u8; MTU]], meta: &[(usize, SocketAddr)]) -> io::Result<usize> {
+        batch::sendmmsg(self, bufs, meta)
+    }
     fn send(&self, buf: &[u8]) -> io::Result<usize> {
         UdpSocket::send(self, buf)
     }
@@ -135,6 +186,10 @@ impl Socket for UdpSocket {
     fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
         UdpSocket::set_nonblocking(self, nonblocking)
     }
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(std::os::unix::io::AsRawFd::as_raw_fd(self))
+    }
 }
 
 impl Socket for SimulatedSocket {
@@ -0,0 +1,125 @@
+//! Path MTU discovery.
+//!
+//! Probe is a packet padded up to the probed size.
+//! The peer acknowledges every received probe,
+//! so the largest acknowledged size gets through this path.
+//!
+//! Search starts from the upper bound and continues by bisection.
+
+use std::time::{Duration, Instant};
+
+pub struct PathMtu {
+    low: usize,
+    high: usize,
+    max: usize,
+
+    probe: usize,
+    attempts: usize,
+    last_probe: Option<Instant>,
+}
+
+impl PathMtu {
+    pub const INTERVAL: Duration = Duration::from_millis(250);
+    pub const MAX_ATTEMPTS: usize = 3;
+    pub const PRECISION: usize = 8;
+
+    /// `low` is known to be working, `high` is the upper bound.
+    pub fn new(low: usize, high: usize) -> Self {
+        Self {
+            low,
+            high,
+            max: high,
+
+            probe: high,
+            attempts: 0,
+            last_probe: None,
+        }
+    }
+
+    /// The largest confirmed MTU.
+    pub fn mtu(&self) -> usize { self.low }
+    /// The upper bound given to `new`.
+    pub fn max(&self) -> usize { self.max }
+
+    pub fn is_done(&self) -> bool {
+        self.high < self.low + Self::PRECISION
+    }
+
+    /// Returns time of the next probe, `None` if discovery is done.
+    pub fn deadline(&self, now: Instant) -> Option<Instant> {
+        if self.is_done() {
+            None
+        } else {
+            Some(self.last_probe.map_or(now, |last| last + Self::INTERVAL))
+        }
+    }
+
+    /// Returns size of the next probe, if it should be sent now.
+    pub fn probe(&mut self, now: Instant) -> Option<usize> {
+        if self.is_done() {
+            return None;
+        }
+        if let Some(last) = self.last_probe {
+            if last + Self::INTERVAL > now {
+                return None;
+            }
+            if self.attempts >= Self::MAX_ATTEMPTS {
+                // all attempts are lost
+                self.high = self.probe - 1;
+                self.attempts = 0;
+                self.bisect();
+                if self.is_done() {
+                    return None;
+                }
+            }
+        }
+        self.attempts += 1;
+        self.last_probe = Some(now);
+        Some(self.probe)
+    }
+
+    /// Processes acknowledged probe.
+    pub fn ack(&mut self, size: usize) {
+        if size <= self.low || size > self.max {
+            return;
+        }
+        self.low = size;
+        self.high = self.high.max(size);
+        self.attempts = 0;
+        self.last_probe = None;
+        self.bisect();
+    }
+
+    fn bisect(&mut self) {
+        self.probe = (self.low + self.high + 1) / 2;
+    }
+}
+
+#[test]
+fn path_mtu_discovery() {
+    let path = 1100;
+    let mut pmtu = PathMtu::new(1000, 1200);
+    let mut now = Instant::now();
+
+    let mut probes = 0;
+    while !pmtu.is_done() {
+        if let Some(size) = pmtu.probe(now) {
+            probes += 1;
+            if size <= path {
+                pmtu.ack(size);
+            }
+        }
+        now += Duration::from_millis(50);
+    }
+
+    assert!(pmtu.mtu() <= path && pmtu.mtu() + PathMtu::PRECISION > path);
+    assert!(probes < 30);
+    assert_eq!(pmtu.probe(now), None);
+
+    // the whole range is available
+    let mut pmtu = PathMtu::new(1000, 1200);
+    assert_eq!(pmtu.probe(now), Some(1200));
+    pmtu.ack(1200);
+    assert!(pmtu.is_done());
+    assert_eq!(pmtu.mtu(), 1200);
+}
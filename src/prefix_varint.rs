@@ -15,6 +15,18 @@ pub trait WritePrefixVarint: std::io::Write {
 
 impl<T: std::io::Write> WritePrefixVarint for T {}
 
+pub trait ReadPrefixVarint: std::io::Read {
+    fn read_prefix_varint(&mut self) -> std::io::Result<u64> {
+        let mut buf = [0u8; 9];
+        self.read_exact(&mut buf[..1])?;
+        let z = read_z(buf[0]);
+        self.read_exact(&mut buf[1..z as usize])?;
+        Ok(unsafe { read_varint64_unchecked(buf.as_ptr(), z) })
+    }
+}
+
+impl<T: std::io::Read> ReadPrefixVarint for T {}
+
 #[inline(always)]
 pub fn read_z(b: u8) -> u32 {
     b.trailing_zeros() + 1
@@ -46,8 +58,13 @@ pub fn read_varint(buf: &[u8]) -> Result<u64, ()> {
     if buf.is_empty() { return Err(()); }
     let z = read_z(buf[0]);
     if buf.len() < z as usize { return Err(()); }
-    unsafe {
-        Ok(read_varint64_unchecked(buf.as_ptr(), z))
+    if buf.len() >= 9 {
+        unsafe { Ok(read_varint64_unchecked(buf.as_ptr(), z)) }
+    } else {
+        // unchecked version reads 8 or 9 bytes
+        let mut tmp = [0u8; 9];
+        tmp[..buf.len()].copy_from_slice(buf);
+        unsafe { Ok(read_varint64_unchecked(tmp.as_ptr(), z)) }
     }
 }
 
@@ -105,6 +122,22 @@ fn safe() {
     }
 }
 
+#[test]
+fn read_trait() {
+    for &value in &[0u64, 0x7F, 0x80, 0x3FFF, 0x4000, 0x00FF_FFFF_FFFF_FFFF, !0] {
+        let mut buf = Vec::new();
+        buf.write_prefix_varint(value).unwrap();
+        buf.push(0xAA);
+
+        let mut rd = &buf[..];
+        assert_eq!(rd.read_prefix_varint().unwrap(), value);
+        assert_eq!(rd, &[0xAA]);
+
+        let mut short = &buf[..buf.len() - 2];
+        assert!(short.read_prefix_varint().is_err());
+    }
+}
+
 #[test]
 fn safe2() {
     let tests: &[(u64, usize)] = &[
@@ -4,6 +4,8 @@
 //! Client  →       auth       →  Relay
 //! Client  ←       token      ←  Relay
 //! Client  →      request     →  Server ×10 ≡ 10hz ≤ 1sec
+//! Client  ←      cookie      ←  Server (under load)
+//! Client  → request + cookie →  Server
 //! Client  ←  response/close  ←  Server
 //! Client  →     challenge    →  Server ×10 ≡ 10hz ≤ 1sec
 //! Client  ↔   payload/close  ↔  Server
@@ -20,7 +22,8 @@
 //!  x1000000  49 bits sequence in 7 bytes
 //!  10000000  56 bits sequence in 8 bytes
 //!  00000000  64 bits sequence in 9 bytes
-//! [00000001] [content ....] - request packet
+//! [00000001] [content ....] [padding] - request packet, padded with zeros up to MTU
+//! [00000011] [cookie] - cookie packet
 //! [0000xxx1] - reserved for future use
 //! [0010sss1] [sequence 1-8 bytes] [ciphertext] [hmac] - challenge / response packets
 //! [0011sss1] [sequence 1-8 bytes] [ciphertext] [hmac] - disconnect / denied packets
@@ -29,7 +32,11 @@
 //!      001    2 bytes
 //!      ...
 //!      111    8 bytes
-//! [01xxxxx1] - reserved for future use
+//! [0100sss1] [sequence 1-8 bytes] [ciphertext] [hmac] - path MTU probe / ping packets
+//! [0101sss1] [sequence 1-8 bytes] [ciphertext] [hmac] - path MTU probe / ping ack packets
+//! [01100001] [content ....] [padding] - resume packet, padded with zeros up to MTU
+//! [0110xxx1] - reserved for future use
+//! [0111sss1] [sequence 1-8 bytes] [ciphertext] [hmac] - resumption ticket packets
 //! [10xxxxx1] - reserved for future use
 //! [11xxxxx1] - reserved for future use
 //! ```
@@ -39,40 +46,61 @@
 //! ```txt
 //! [prefix byte] (u8)
 //! [sequence] (1-8 bytes)
-//! [ciphertext] (0-1175 bytes)     // 0 for disconnect/denied and 308 for challenge/response
+//! [ciphertext] (0-1175 bytes)     // reason for disconnect/denied and 308 for challenge/response
 //! [hmac] (16 bytes)
 //! ```
 //!
+//! Sequence is the nonce, keys of the connection change every 65536 packets,
+//! the key epoch is `sequence / 65536`.
+//!
+
+#![deny(unsafe_code)]
 
 use byteorder::{LE, ByteOrder, WriteBytesExt};
 use std::{
     fmt,
-    mem::{transmute, size_of},
+    convert::TryFrom,
     time::Duration,
     io::{self, Write},
-    slice::from_raw_parts,
 };
 use crate::{
-    token::{CHALLENGE_LEN, PrivateToken, PRIVATE_LEN},
-    prefix_varint::WritePrefixVarint,
+    token::{CHALLENGE_LEN, PrivateToken, PRIVATE_LEN, ResumeToken, RESUME_LEN, TICKET_LEN},
+    prefix_varint::{WritePrefixVarint, read_varint},
+    codec::{Reader, Writer, DecodeError, read_array},
     crypto::{
         nonce_from_u64,
-        open,
-        seal,
+        Aead,
         KEY,
         HMAC,
         XNONCE,
     },
 };
 
-/// Protocol version.
-pub const VERSION: [u8; VERSION_LEN] = *b"ONI\0";
+/// Protocol version, bumped on every change of the wire format.
+pub const VERSION: [u8; VERSION_LEN] = *b"ONI\x01";
 /// Protocol version length.
 pub const VERSION_LEN: usize = 4;
 
 /// Maximum Transmission Unit.
 pub const MTU: usize = 1200;
 
+/// Length of the cookie: timestamp and MAC of client's address.
+pub const COOKIE_LEN: usize = 8 + 16;
+
+/// Length of request packet without padding.
+///
+/// Every request has room for the cookie, which adds `COOKIE_LEN` to `MIN_MTU`.
+pub const REQUEST_LEN: usize = 1 + VERSION_LEN + 8 + 8 + XNONCE + PRIVATE_LEN + COOKIE_LEN;
+
+/// Length of resume packet without padding.
+pub const RESUME_REQUEST_LEN: usize = 1 + VERSION_LEN + 8 + 8 + RESUME_LEN;
+
+/// Minimum supported MTU, equal to `REQUEST_LEN`.
+///
+/// The request packet must fit in a single datagram,
+/// it carries the private connect token of `PRIVATE_LEN` bytes and the cookie.
+pub const MIN_MTU: usize = REQUEST_LEN;
+
 // 1 byte for prefix
 // at least 1 byte for sequence
 /// Minimum size of packet.
@@ -84,35 +112,216 @@ pub const MAX_OVERHEAD: usize = 9 + HMAC;
 /// Maximum length of payload in bytes.
 pub const MAX_PAYLOAD: usize = MTU - MAX_OVERHEAD;
 
+/// Maximum length of payload in bytes for given MTU.
+pub fn max_payload(mtu: usize) -> usize {
+    mtu.min(MTU) - MAX_OVERHEAD
+}
+
+/// Length of probe plaintext: `[probed size] u16`, `[id] u16`, `[loss] u16`.
+pub const PROBE_LEN: usize = 6;
+
+/// Plaintext of probe packet.
+///
+/// Ack echoes `size` and `id` of the probe.
+/// Probe with zero `size` is used as ping only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Probe {
+    /// Probed MTU.
+    pub size: u16,
+    /// Identifier to match ack with probe.
+    pub id: u16,
+    /// Loss of incoming packets by the sender, in 1/65535 units.
+    pub loss: u16,
+}
+
+impl Probe {
+    pub fn read(buf: &[u8]) -> Option<Self> {
+        if buf.len() >= PROBE_LEN {
+            Some(Self {
+                size: LE::read_u16(&buf[0..]),
+                id: LE::read_u16(&buf[2..]),
+                loss: LE::read_u16(&buf[4..]),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&self, buf: &mut [u8]) {
+        LE::write_u16(&mut buf[0..], self.size);
+        LE::write_u16(&mut buf[2..], self.id);
+        LE::write_u16(&mut buf[4..], self.loss);
+    }
+}
+
 pub const NUM_DISCONNECT_PACKETS: usize = 10;
 
+/// Why the connection request was denied.
+///
+/// Sent in the denied packet as a single byte.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq)]
+pub enum DenyReason {
+    /// Client with the same address or id is already connected.
+    AlreadyConnected,
+    /// Connect token is already used from another address.
+    TokenAlreadyUsed,
+    /// Server has no free slots.
+    ServerFull,
+    /// Server is shutting down.
+    ShuttingDown,
+}
+
+impl DenyReason {
+    pub fn code(self) -> u8 {
+        match self {
+            DenyReason::AlreadyConnected => 1,
+            DenyReason::TokenAlreadyUsed => 2,
+            DenyReason::ServerFull => 3,
+            DenyReason::ShuttingDown => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(DenyReason::AlreadyConnected),
+            2 => Some(DenyReason::TokenAlreadyUsed),
+            3 => Some(DenyReason::ServerFull),
+            4 => Some(DenyReason::ShuttingDown),
+            _ => None,
+        }
+    }
+}
+
+/// Why the server ignored a connection request, challenge response or resume packet.
+///
+/// Unlike `DenyReason` it is never sent to the client.
+/// Replayed tokens and already connected clients are denied instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// Packet is malformed.
+    Decode(DecodeError),
+    /// Version in the packet doesn't match `VERSION`.
+    BadVersion,
+    /// Protocol id in the packet doesn't match the protocol id of the server.
+    BadProtocol,
+    /// Connect token or resume ticket is expired.
+    Expired,
+    /// Token or packet failed authentication:
+    /// it's forged, corrupted or sealed with another key.
+    BadMac,
+    /// Server list in the connect token can't be read.
+    BadServerList,
+    /// Connect token doesn't contain the address of the server.
+    NotInServerList,
+    /// Challenge response came from an address without a pending request.
+    NotPending,
+}
+
+impl HandshakeError {
+    /// Returns a short static description, e.g. for tracing.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HandshakeError::Decode(DecodeError::TooShort) => "packet is too short",
+            HandshakeError::Decode(DecodeError::TooLong) => "packet is too long",
+            HandshakeError::Decode(DecodeError::BadPrefix) => "bad packet prefix",
+            HandshakeError::BadVersion => "bad version",
+            HandshakeError::BadProtocol => "bad protocol id",
+            HandshakeError::Expired => "token expired",
+            HandshakeError::BadMac => "authentication failed",
+            HandshakeError::BadServerList => "bad server list",
+            HandshakeError::NotInServerList => "server is not in the server list",
+            HandshakeError::NotPending => "no pending request",
+        }
+    }
+}
+
+impl From<DecodeError> for HandshakeError {
+    fn from(err: DecodeError) -> Self {
+        HandshakeError::Decode(err)
+    }
+}
+
+/// Reason why the server closed the connection.
+///
+/// Sent in the disconnect packet as `[code] [message]`, message is optional UTF-8.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Client is kicked.
+    Kicked,
+    /// Client is banned.
+    Banned,
+    /// Server is shutting down.
+    ServerShutdown,
+    /// Client uses incompatible protocol version.
+    ProtocolMismatch,
+    /// Client is idle for too long.
+    Idle,
+}
+
+impl CloseReason {
+    pub fn code(self) -> u8 {
+        match self {
+            CloseReason::Kicked => 1,
+            CloseReason::Banned => 2,
+            CloseReason::ServerShutdown => 3,
+            CloseReason::ProtocolMismatch => 4,
+            CloseReason::Idle => 5,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(CloseReason::Kicked),
+            2 => Some(CloseReason::Banned),
+            3 => Some(CloseReason::ServerShutdown),
+            4 => Some(CloseReason::ProtocolMismatch),
+            5 => Some(CloseReason::Idle),
+            _ => None,
+        }
+    }
+}
+
+/// Maximum length of message in the disconnect packet.
+pub const CLOSE_MESSAGE_LEN: usize = 255;
+
 pub const PACKET_SEND_RATE: u64 = 10;
 pub const PACKET_SEND_DELTA: Duration =
     Duration::from_nanos(1_000_000_000 / PACKET_SEND_RATE);
 
-#[repr(C)]
+#[derive(Clone)]
 pub struct Request {
     prefix: u8,
     version: [u8; VERSION_LEN],
     protocol: [u8; 8],
     expire: [u8; 8],
     nonce: [u8; XNONCE],
-    _reserved: [u8; 131],
-    // NOTE: 45 + 131 = 176
     token: [u8; PRIVATE_LEN],
+    cookie: [u8; COOKIE_LEN],
 }
 
 impl PartialEq for Request {
-    fn eq(&self, _: &Self) -> bool {
-        //self.prefix == 1
-        unimplemented!("<Request as PartialEq>::eq")
+    fn eq(&self, other: &Self) -> bool {
+        self.prefix == other.prefix &&
+            self.version == other.version &&
+            self.protocol == other.protocol &&
+            self.expire == other.expire &&
+            self.nonce == other.nonce &&
+            self.token[..] == other.token[..] &&
+            self.cookie == other.cookie
     }
 }
 
 impl fmt::Debug for Request {
-    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
-        //write!(f, "Request")
-        unimplemented!("<Request as Debug>::fmt")
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Request")
+            .field("prefix", &self.prefix)
+            .field("version", &self.version)
+            .field("protocol", &u64::from_le_bytes(self.protocol))
+            .field("expire", &self.expire())
+            .field("nonce", &self.nonce)
+            .field("token", &&self.token[..])
+            .field("cookie", &self.cookie)
+            .finish()
     }
 }
 
@@ -121,48 +330,172 @@ impl Request {
         u64::from_le_bytes(self.expire)
     }
 
-    pub fn open_token(&mut self, private_key: &[u8; KEY]) -> Result<(u64, &PrivateToken), ()> {
+    pub fn open_token<C: Aead>(&self, private_key: &[u8; KEY]) -> Result<(u64, PrivateToken), HandshakeError> {
         let protocol = u64::from_le_bytes(self.protocol);
         let expire = self.expire();
-        let token = PrivateToken::open(&mut self.token, protocol, expire, &self.nonce, private_key)?;
+        let mut token = self.token;
+        let token = PrivateToken::open::<C>(&mut token, protocol, expire, &self.nonce, private_key)?;
         Ok((expire, token))
     }
 
     pub fn is_valid(&self, protocol: u64, timestamp: u64) -> bool {
-        // XXX prefix = 1
-        if self.prefix != 1 { return false; }
+        self.check(protocol, timestamp).is_ok()
+    }
+
+    /// Checks the request before opening the token.
+    pub fn check(&self, protocol: u64, timestamp: u64) -> Result<(), HandshakeError> {
+        if self.prefix != Packet::REQUEST { return Err(DecodeError::BadPrefix.into()); }
         // If the version info in the packet doesn't match VERSION, ignore the packet.
-        if self.version != VERSION { return false; }
+        if self.version != VERSION { return Err(HandshakeError::BadVersion); }
         // If the protocol id in the packet doesn't match the expected protocol id of the dedicated server, ignore the packet.
-        if u64::from_le_bytes(self.protocol) != protocol { return false; }
+        if u64::from_le_bytes(self.protocol) != protocol { return Err(HandshakeError::BadProtocol); }
         // If the connect token expire timestamp is <= the current timestamp, ignore the packet.
-        if self.expire() <= timestamp { return false; }
+        if self.expire() <= timestamp { return Err(HandshakeError::Expired); }
 
-        true
+        Ok(())
     }
 
     pub fn new(protocol: u64, expire: u64, nonce: [u8; 24], token: [u8; PRIVATE_LEN]) -> Self {
         Self {
-            prefix: 1,
+            prefix: Packet::REQUEST,
             version: VERSION,
             protocol: protocol.to_le_bytes(),
             expire: expire.to_le_bytes(),
             nonce,
-            _reserved: [0u8; 131],
             token,
+            cookie: [0u8; COOKIE_LEN],
         }
     }
 
-    pub fn write(self) -> [u8; MTU] {
-        unsafe { transmute(self) }
+    /// Cookie sent by the server under load, zeros if there is none.
+    pub fn cookie(&self) -> &[u8; COOKIE_LEN] { &self.cookie }
+    pub fn set_cookie(&mut self, cookie: [u8; COOKIE_LEN]) { self.cookie = cookie }
+
+    /// Writes request padded with zeros up to `MTU`.
+    ///
+    /// Send only first `mtu` bytes for smaller MTU.
+    pub fn write(&self) -> [u8; MTU] {
+        let mut buf = [0u8; MTU];
+        let len = Writer::new(&mut buf)
+            .put(&[self.prefix])
+            .put(&self.version)
+            .put(&self.protocol)
+            .put(&self.expire)
+            .put(&self.nonce)
+            .put(&self.token)
+            .put(&self.cookie)
+            .len();
+        debug_assert_eq!(len, REQUEST_LEN);
+        buf
     }
 
-    fn _read(buf: &mut [u8]) -> Result<&mut Self, ()> {
-        if buf.len() == MTU {
-            Ok(unsafe { &mut *(buf.as_ptr() as *mut Self) })
-        } else {
-            Err(())
+    /// Reads request of `MIN_MTU..=MTU` bytes, the padding is ignored.
+    pub fn read(buf: &[u8]) -> Result<Self, DecodeError> {
+        check_padded(buf)?;
+        let mut r = Reader::new(buf);
+        let prefix = r.read_u8()?;
+        if prefix != Packet::REQUEST {
+            return Err(DecodeError::BadPrefix);
         }
+        Ok(Self {
+            prefix,
+            version: read_array!(r, VERSION_LEN),
+            protocol: read_array!(r, 8),
+            expire: read_array!(r, 8),
+            nonce: read_array!(r, XNONCE),
+            token: read_array!(r, PRIVATE_LEN),
+            cookie: read_array!(r, COOKIE_LEN),
+        })
+    }
+}
+
+/// Checks length of request and resume packets, which are padded up to `MTU`.
+fn check_padded(buf: &[u8]) -> Result<(), DecodeError> {
+    if buf.len() < MIN_MTU {
+        Err(DecodeError::TooShort)
+    } else if buf.len() > MTU {
+        Err(DecodeError::TooLong)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Resume {
+    prefix: u8,
+    version: [u8; VERSION_LEN],
+    protocol: [u8; 8],
+    token: [u8; 8 + RESUME_LEN],
+}
+
+impl Resume {
+    const PREFIX: u8 = 0b0110_0001;
+
+    pub fn new(protocol: u64, token: [u8; 8 + RESUME_LEN]) -> Self {
+        Self {
+            prefix: Self::PREFIX,
+            version: VERSION,
+            protocol: protocol.to_le_bytes(),
+            token,
+        }
+    }
+
+    pub fn is_valid(&self, protocol: u64) -> bool {
+        self.check(protocol).is_ok()
+    }
+
+    /// Checks the packet before opening the token.
+    pub fn check(&self, protocol: u64) -> Result<(), HandshakeError> {
+        if self.prefix != Self::PREFIX { return Err(DecodeError::BadPrefix.into()); }
+        if self.version != VERSION { return Err(HandshakeError::BadVersion); }
+        if u64::from_le_bytes(self.protocol) != protocol { return Err(HandshakeError::BadProtocol); }
+        Ok(())
+    }
+
+    pub fn open_token<C: Aead>(&self, key: &[u8; KEY]) -> Result<ResumeToken, HandshakeError> {
+        ResumeToken::decode_packet::<C>(&self.token, key)
+    }
+
+    /// Writes resume packet padded with zeros up to `MTU`.
+    ///
+    /// Send only first `mtu` bytes for smaller MTU.
+    pub fn write(&self) -> [u8; MTU] {
+        let mut buf = [0u8; MTU];
+        let len = Writer::new(&mut buf)
+            .put(&[self.prefix])
+            .put(&self.version)
+            .put(&self.protocol)
+            .put(&self.token)
+            .len();
+        debug_assert_eq!(len, RESUME_REQUEST_LEN);
+        buf
+    }
+
+    /// Reads resume packet of `MIN_MTU..=MTU` bytes, the padding is ignored.
+    pub fn read(buf: &[u8]) -> Result<Self, DecodeError> {
+        check_padded(buf)?;
+        let mut r = Reader::new(buf);
+        let prefix = r.read_u8()?;
+        if prefix != Self::PREFIX {
+            return Err(DecodeError::BadPrefix);
+        }
+        Ok(Self {
+            prefix,
+            version: read_array!(r, VERSION_LEN),
+            protocol: read_array!(r, 8),
+            token: read_array!(r, 8 + RESUME_LEN),
+        })
+    }
+}
+
+impl fmt::Debug for Resume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Resume")
+            .field("prefix", &self.prefix)
+            .field("version", &self.version)
+            .field("protocol", &u64::from_le_bytes(self.protocol))
+            .field("token", &&self.token[..])
+            .finish()
     }
 }
 
@@ -188,60 +521,152 @@ pub enum Packet<'a> {
     Close {
         /// Prefix byte.
         prefix: u8,
+        /// Contains `[ciphertext]` with optional reason.
+        buf: &'a mut [u8],
         /// Sequence number of this packet.
         seq: u64,
         /// Contains `[hmac]`.
         tag: &'a [u8; HMAC],
     },
-    Request(&'a mut Request),
+    Probe {
+        /// Prefix byte.
+        prefix: u8,
+        /// Is it ack for probe?
+        ack: bool,
+        /// Contains `[ciphertext]`.
+        buf: &'a mut [u8],
+        /// Sequence number of this packet.
+        seq: u64,
+        /// Contains `[hmac]`.
+        tag: &'a [u8; HMAC],
+    },
+    Ticket {
+        /// Prefix byte.
+        prefix: u8,
+        /// Contains `[ciphertext]`.
+        buf: &'a mut [u8; TICKET_LEN],
+        /// Sequence number of this packet.
+        seq: u64,
+        /// Contains `[hmac]`.
+        tag: &'a [u8; HMAC],
+    },
+    Request(Request),
+    Resume(Resume),
+    Cookie(&'a [u8; COOKIE_LEN]),
 }
 
 impl<'a> PartialEq for Packet<'a> {
     fn eq(&self, other: &Self) -> bool {
+        use self::Packet::*;
         match (self, other) {
-            (Packet::Payload { buf, seq, tag }, Packet::Payload { buf: _buf, seq: _seq, tag: _tag }) => {
-                buf == _buf && tag == _tag && seq == _seq
+            (Payload { buf, seq, tag }, Payload { buf: _buf, seq: _seq, tag: _tag }) => {
+                buf == _buf && seq == _seq && tag == _tag
             }
-            _ => unimplemented!("<Packet as PartialEq>::eq")
+            (Handshake { prefix, buf, seq, tag }, Handshake { prefix: _prefix, buf: _buf, seq: _seq, tag: _tag }) => {
+                prefix == _prefix && buf[..] == _buf[..] && seq == _seq && tag == _tag
+            }
+            (Close { prefix, buf, seq, tag }, Close { prefix: _prefix, buf: _buf, seq: _seq, tag: _tag }) => {
+                prefix == _prefix && buf == _buf && seq == _seq && tag == _tag
+            }
+            (Probe { prefix, ack, buf, seq, tag }, Probe { prefix: _prefix, ack: _ack, buf: _buf, seq: _seq, tag: _tag }) => {
+                prefix == _prefix && ack == _ack && buf == _buf && seq == _seq && tag == _tag
+            }
+            (Ticket { prefix, buf, seq, tag }, Ticket { prefix: _prefix, buf: _buf, seq: _seq, tag: _tag }) => {
+                prefix == _prefix && buf[..] == _buf[..] && seq == _seq && tag == _tag
+            }
+            (Request(a), Request(b)) => a == b,
+            (Resume(a), Resume(b)) => a == b,
+            (Cookie(a), Cookie(b)) => a == b,
+            _ => false,
         }
     }
 }
 
 impl<'a> fmt::Debug for Packet<'a> {
-    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
-        //write!(f, "Request")
-        unimplemented!("<Packet as Debug>::fmt")
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Packet::Payload { buf, seq, tag } => f.debug_struct("Payload")
+                .field("buf", buf)
+                .field("seq", seq)
+                .field("tag", tag)
+                .finish(),
+            Packet::Handshake { prefix, buf, seq, tag } => f.debug_struct("Handshake")
+                .field("prefix", prefix)
+                .field("buf", &&buf[..])
+                .field("seq", seq)
+                .field("tag", tag)
+                .finish(),
+            Packet::Close { prefix, buf, seq, tag } => f.debug_struct("Close")
+                .field("prefix", prefix)
+                .field("buf", buf)
+                .field("seq", seq)
+                .field("tag", tag)
+                .finish(),
+            Packet::Probe { prefix, ack, buf, seq, tag } => f.debug_struct("Probe")
+                .field("prefix", prefix)
+                .field("ack", ack)
+                .field("buf", buf)
+                .field("seq", seq)
+                .field("tag", tag)
+                .finish(),
+            Packet::Ticket { prefix, buf, seq, tag } => f.debug_struct("Ticket")
+                .field("prefix", prefix)
+                .field("buf", &&buf[..])
+                .field("seq", seq)
+                .field("tag", tag)
+                .finish(),
+            Packet::Request(request) => f.debug_tuple("Request").field(request).finish(),
+            Packet::Resume(resume) => f.debug_tuple("Resume").field(resume).finish(),
+            Packet::Cookie(cookie) => f.debug_tuple("Cookie").field(cookie).finish(),
+        }
     }
 }
 
-#[repr(C)]
-struct EncryptedAd {
-    _version: [u8; VERSION_LEN],
-    _protocol: [u8; 8],
-    _prefix: u8,
+/// Additional data of encrypted packets.
+fn encrypted_ad(protocol: u64, prefix: u8) -> [u8; VERSION_LEN + 8 + 1] {
+    let mut ad = [0u8; VERSION_LEN + 8 + 1];
+    Writer::new(&mut ad)
+        .put(&VERSION)
+        .put(&protocol.to_le_bytes())
+        .put(&[prefix]);
+    ad
 }
 
-impl EncryptedAd {
-    fn new(protocol: u64, prefix: u8) -> Self {
-        Self {
-            _version: VERSION,
-            _protocol: protocol.to_le_bytes(),
-            _prefix: prefix,
-        }
-    }
-    fn as_slice(&self) -> &[u8] {
-        let p: *const Self = self;
-        unsafe { from_raw_parts(p as *const u8, size_of::<Self>()) }
+/// Checks that the length of a fixed-size part is `expected`.
+fn check_len(len: usize, expected: usize) -> Result<(), DecodeError> {
+    if len < expected {
+        Err(DecodeError::TooShort)
+    } else if len > expected {
+        Err(DecodeError::TooLong)
+    } else {
+        Ok(())
     }
 }
 
+/// Splits `[ciphertext] [hmac]`.
+fn split_tag(buf: &mut [u8]) -> (&mut [u8], &[u8; HMAC]) {
+    let (buf, tag) = buf.split_at_mut(buf.len() - HMAC);
+    (buf, <&[u8; HMAC]>::try_from(&*tag).unwrap())
+}
+
 #[inline]
 fn sequence_bytes_required(sequence: u64) -> u32 {
     1 + (64 - (sequence | 1).leading_zeros() - 1) / 8
 }
 
 impl<'a> Packet<'a> {
-    pub fn encode_close(protocol: u64, mut buf: &mut [u8], seq: u64, k: &[u8; KEY]) -> io::Result<usize> {
+    const REQUEST: u8 = 0b0000_0001;
+    const COOKIE: u8 = 0b0000_0011;
+
+    /// Returns `true` for prefixes of request, resume and challenge response packets.
+    ///
+    /// Their decode errors are reported, e.g. a client of other version
+    /// sends requests of other length.
+    pub fn is_handshake(prefix: u8) -> bool {
+        prefix == Self::REQUEST || prefix == Resume::PREFIX || prefix & 0b1111_0001 == 0b0010_0001
+    }
+
+    pub fn encode_close<C: Aead>(protocol: u64, mut buf: &mut [u8], seq: u64, k: &[u8; KEY], m: &mut [u8]) -> io::Result<usize> {
         let start_len = buf.len();
 
         let sss = sequence_bytes_required(seq);
@@ -249,14 +674,15 @@ impl<'a> Packet<'a> {
         buf.write_u8(prefix)?;
         buf.write_uint::<LE>(seq, sss as usize)?;
 
-        let tag = Self::seal(protocol, &mut [], seq, prefix, k);
+        let tag = Self::seal::<C>(protocol, m, seq, prefix, k);
+        buf.write_all(m)?;
         buf.write_all(&tag)?;
 
         Ok(start_len - buf.len())
     }
 
     // TODO: version without encryption?
-    pub fn encode_handshake(protocol: u64, mut buf: &mut [u8], seq: u64, k: &[u8; KEY], m: &mut [u8; 8 + CHALLENGE_LEN]) -> io::Result<usize> {
+    pub fn encode_handshake<C: Aead>(protocol: u64, mut buf: &mut [u8], seq: u64, k: &[u8; KEY], m: &mut [u8; 8 + CHALLENGE_LEN]) -> io::Result<usize> {
         let start_len = buf.len();
 
         let sss = sequence_bytes_required(seq);
@@ -264,7 +690,49 @@ impl<'a> Packet<'a> {
         buf.write_u8(prefix)?;
         buf.write_uint::<LE>(seq, sss as usize)?;
 
-        let tag = Self::seal(protocol, m, seq, prefix, k);
+        let tag = Self::seal::<C>(protocol, m, seq, prefix, k);
+
+        buf.write_all(m)?;
+        buf.write_all(&tag)?;
+
+        Ok(start_len - buf.len())
+    }
+
+    /// Encodes probe padded up to `probe.size` or ack for the probe.
+    pub fn encode_probe<C: Aead>(protocol: u64, mut buf: &mut [u8], seq: u64, k: &[u8; KEY], probe: Probe, ack: bool) -> io::Result<usize> {
+        let start_len = buf.len();
+
+        let sss = sequence_bytes_required(seq);
+        let prefix = 0b0100_0001 | (ack as u8) << 4 | ((sss - 1) as u8) << 1;
+        buf.write_u8(prefix)?;
+        buf.write_uint::<LE>(seq, sss as usize)?;
+
+        let len = if ack {
+            PROBE_LEN
+        } else {
+            (probe.size as usize).saturating_sub(1 + sss as usize + HMAC).max(PROBE_LEN).min(MTU)
+        };
+        let mut m = [0u8; MTU];
+        let m = &mut m[..len];
+        probe.write(m);
+
+        let tag = Self::seal::<C>(protocol, m, seq, prefix, k);
+
+        buf.write_all(m)?;
+        buf.write_all(&tag)?;
+
+        Ok(start_len - buf.len())
+    }
+
+    pub fn encode_ticket<C: Aead>(protocol: u64, mut buf: &mut [u8], seq: u64, k: &[u8; KEY], m: &mut [u8; TICKET_LEN]) -> io::Result<usize> {
+        let start_len = buf.len();
+
+        let sss = sequence_bytes_required(seq);
+        let prefix = 0b0111_0001 | ((sss - 1) as u8) << 1;
+        buf.write_u8(prefix)?;
+        buf.write_uint::<LE>(seq, sss as usize)?;
+
+        let tag = Self::seal::<C>(protocol, m, seq, prefix, k);
 
         buf.write_all(m)?;
         buf.write_all(&tag)?;
@@ -272,17 +740,23 @@ impl<'a> Packet<'a> {
         Ok(start_len - buf.len())
     }
 
-    pub fn encode_keep_alive(protocol: u64, buf: &mut [u8], seq: u64, k: &[u8; KEY]) -> io::Result<usize> {
-        Self::encode_payload(protocol, buf, seq, k, &mut [])
+    pub fn encode_cookie(buf: &mut [u8], cookie: &[u8; COOKIE_LEN]) -> usize {
+        buf[0] = Self::COOKIE;
+        buf[1..=COOKIE_LEN].copy_from_slice(cookie);
+        1 + COOKIE_LEN
+    }
+
+    pub fn encode_keep_alive<C: Aead>(protocol: u64, buf: &mut [u8], seq: u64, k: &[u8; KEY]) -> io::Result<usize> {
+        Self::encode_payload::<C>(protocol, buf, seq, k, &mut [])
     }
 
-    pub fn encode_payload(protocol: u64, mut buf: &mut [u8], seq: u64, k: &[u8; KEY], m: &mut [u8]) -> io::Result<usize> {
+    pub fn encode_payload<C: Aead>(protocol: u64, mut buf: &mut [u8], seq: u64, k: &[u8; KEY], m: &mut [u8]) -> io::Result<usize> {
 
         let start_len = buf.len();
 
         buf.write_prefix_varint_custom(seq, 2)?;
 
-        let tag = Self::seal(protocol, m, seq, 0, k);
+        let tag = Self::seal::<C>(protocol, m, seq, 0, k);
 
         buf.write_all(m)?;
         buf.write_all(&tag)?;
@@ -290,59 +764,72 @@ impl<'a> Packet<'a> {
         Ok(start_len - buf.len())
     }
 
-    pub fn decode(buf: &'a mut [u8]) -> Option<Self> {
-        // FUCKING BLACK MAGIC HERE
-        // So, dont't touch it.
-        //
-        // TODO: early check size?
-        if buf.len() < MIN_PACKET { return None; }
+    pub fn decode(buf: &'a mut [u8]) -> Result<Self, DecodeError> {
+        if buf.len() < MIN_PACKET { return Err(DecodeError::TooShort); }
 
         let prefix = buf[0];
         if prefix & 1 == 0 {
-            let z = crate::prefix_varint::read_z(prefix);
-
-            if buf.len() >= HMAC + z as usize {
-                let seq = unsafe {
-                    crate::prefix_varint::read_varint64_unchecked(buf.as_ptr(), z)
-                };
-                let buf = &mut buf[z as usize..];
-                let (buf, tag) = buf.split_at_mut(buf.len() - HMAC);
-                let tag = unsafe { &*(tag.as_ptr() as *const [u8; HMAC]) };
-                return Some(Packet::Payload { seq, buf, tag })
-            }
-        } else if prefix == 1 && buf.len() == MTU {
-            return Some(Packet::Request(unsafe { &mut *(buf.as_ptr() as *mut Request) }));
+            let z = crate::prefix_varint::read_z(prefix) as usize;
+            if buf.len() < HMAC + z { return Err(DecodeError::TooShort); }
+
+            let seq = read_varint(buf).map_err(|()| DecodeError::TooShort)?;
+            let (buf, tag) = split_tag(&mut buf[z..]);
+            Ok(Packet::Payload { seq, buf, tag })
+        } else if prefix == Self::REQUEST {
+            Request::read(buf).map(Packet::Request)
+        } else if prefix == Self::COOKIE {
+            check_len(buf.len(), 1 + COOKIE_LEN)?;
+            Ok(Packet::Cookie(<&[u8; COOKIE_LEN]>::try_from(&buf[1..]).unwrap()))
+        } else if prefix == Resume::PREFIX {
+            Resume::read(buf).map(Packet::Resume)
+        } else if prefix & 0b1111_0001 == 0b0111_0001 {
+            let sss = (prefix & 0b0000_1110) >> 1;
+            let len = sss as usize + 1;
+
+            check_len(buf.len(), 1 + len + TICKET_LEN + HMAC)?;
+            let seq = LE::read_uint(&buf[1..], len);
+            let (buf, tag) = split_tag(&mut buf[1 + len..]);
+            let buf = <&mut [u8; TICKET_LEN]>::try_from(buf).unwrap();
+            Ok(Packet::Ticket { prefix, seq, buf, tag })
         } else if prefix & 0b1110_0000 == 0b0010_0000 {
             let typ = (prefix & 0b0001_0000) >> 4 != 0;
             let sss = (prefix & 0b0000_1110) >> 1;
-            let len = sss + 1;
-            debug_assert!(len >= 1 && len <= 8);
-
-            if buf.len() >= 1 + HMAC + len as usize {
-                let seq = LE::read_uint(&buf[1..], len as usize);
-                let buf = &mut buf[1 + len as usize..];
-
-                let (buf, tag) = buf.split_at_mut(buf.len() - HMAC);
-                let tag = unsafe { &*(tag.as_ptr() as *const [u8; HMAC]) };
-                if typ && buf.is_empty() {
-                    return Some(Packet::Close { prefix, seq, tag });
-                } else if !typ && buf.len() == 8 + CHALLENGE_LEN {
-                    let buf = unsafe { &mut *(buf.as_mut_ptr() as *mut [u8; 8 + CHALLENGE_LEN]) };
-                    return Some(Packet::Handshake { prefix, seq, buf, tag });
-                }
+            let len = sss as usize + 1;
+
+            if typ {
+                if buf.len() < 1 + len + HMAC { return Err(DecodeError::TooShort); }
+                let seq = LE::read_uint(&buf[1..], len);
+                let (buf, tag) = split_tag(&mut buf[1 + len..]);
+                Ok(Packet::Close { prefix, seq, buf, tag })
+            } else {
+                check_len(buf.len(), 1 + len + 8 + CHALLENGE_LEN + HMAC)?;
+                let seq = LE::read_uint(&buf[1..], len);
+                let (buf, tag) = split_tag(&mut buf[1 + len..]);
+                let buf = <&mut [u8; 8 + CHALLENGE_LEN]>::try_from(buf).unwrap();
+                Ok(Packet::Handshake { prefix, seq, buf, tag })
             }
+        } else if prefix & 0b1110_0001 == 0b0100_0001 {
+            let ack = prefix & 0b0001_0000 != 0;
+            let sss = (prefix & 0b0000_1110) >> 1;
+            let len = sss as usize + 1;
+
+            if buf.len() < 1 + HMAC + len + PROBE_LEN { return Err(DecodeError::TooShort); }
+            let seq = LE::read_uint(&buf[1..], len);
+            let (buf, tag) = split_tag(&mut buf[1 + len..]);
+            Ok(Packet::Probe { prefix, ack, seq, buf, tag })
+        } else {
+            Err(DecodeError::BadPrefix)
         }
-        None
     }
 
-    pub fn seal(protocol: u64, m: &mut [u8], seq: u64, prefix: u8, k: &[u8; KEY]) -> [u8; HMAC] {
-        let ad = EncryptedAd::new(protocol, prefix);
-        seal(m, Some(ad.as_slice()), &nonce_from_u64(seq), k)
+    pub fn seal<C: Aead>(protocol: u64, m: &mut [u8], seq: u64, prefix: u8, k: &[u8; KEY]) -> [u8; HMAC] {
+        let ad = encrypted_ad(protocol, prefix);
+        C::seal(m, Some(&ad), &nonce_from_u64(seq), k)
     }
 
-    pub fn open(protocol: u64, c: &mut [u8], seq: u64, prefix: u8, t: &[u8; HMAC], k: &[u8; KEY]) -> Result<(), ()> {
-        let ad = EncryptedAd::new(protocol, prefix);
-        open(c, Some(ad.as_slice()), t, &nonce_from_u64(seq), k)
+    pub fn open<C: Aead>(protocol: u64, c: &mut [u8], seq: u64, prefix: u8, t: &[u8; HMAC], k: &[u8; KEY]) -> Result<(), ()> {
+        let ad = encrypted_ad(protocol, prefix);
+        C::open(c, Some(&ad), t, &nonce_from_u64(seq), k)
     }
 }
 
@@ -393,7 +880,7 @@ fn decode_payload_packet() {
     let mut buffer = [0u8; MIN_PACKET];
 
     // full 8 bit sequence and bad size
-    assert_eq!(Packet::decode(&mut buffer), None);
+    assert_eq!(Packet::decode(&mut buffer), Err(DecodeError::TooShort));
 
     // full 8 bit sequence and ok size
     // XXX: It can be used for some black magic?
@@ -441,7 +928,7 @@ fn decode_payload_packet() {
     // 21 bit sequence and bad size
     buffer[0] = 0b00000100;
     buffer[1] = 0b00000000;
-    assert_eq!(Packet::decode(&mut buffer), None);
+    assert_eq!(Packet::decode(&mut buffer), Err(DecodeError::TooShort));
 }
 
 #[test]
@@ -456,15 +943,27 @@ fn decode_packet() {
         Packet::Payload { seq, buf, tag } => {
             unimplemented!("payload packet: {} {:?} {:?}", seq, buf, tag)
         }
-        Packet::Close { prefix, seq, tag } => {
-            unimplemented!("close packet: {} {} {:?}", prefix, seq, tag)
+        Packet::Close { prefix, seq, buf, tag } => {
+            unimplemented!("close packet: {} {} {:?} {:?}", prefix, seq, &buf[..], tag)
         }
         Packet::Handshake { prefix, seq, buf, tag } => {
             unimplemented!("challenge packet: {} {} {:?} {:?}", prefix, seq, &buf[..], tag)
         }
+        Packet::Probe { prefix, ack, seq, buf, tag } => {
+            unimplemented!("probe packet: {} {} {} {:?} {:?}", prefix, ack, seq, &buf[..], tag)
+        }
+        Packet::Ticket { prefix, seq, buf, tag } => {
+            unimplemented!("ticket packet: {} {} {:?} {:?}", prefix, seq, &buf[..], tag)
+        }
         Packet::Request(_request) => {
             unimplemented!("request packet")
         }
+        Packet::Resume(_resume) => {
+            unimplemented!("resume packet")
+        }
+        Packet::Cookie(cookie) => {
+            unimplemented!("cookie packet: {:?}", &cookie[..])
+        }
     }
 
     /*
@@ -478,8 +977,7 @@ fn decode_packet() {
 
 #[test]
 fn request_packet() {
-    use crate::{unix_time, crypto::{keygen, crypto_random}, token::{USER, DATA, PublicToken}};
-    assert_eq!(size_of::<Request>(), MTU);
+    use crate::{unix_time, crypto::{keygen, crypto_random, DefaultAead}, token::{USER, DATA, PublicToken}};
 
     let protocol  = 0x11223344_55667788;
     let client_id = 0x55667788_11223344;
@@ -497,13 +995,125 @@ fn request_packet() {
     let tok = PublicToken::generate(data, user, expire, timeout, client_id, protocol, &private_key);
 
     let req = Request::new(protocol, tok.expire_timestamp(), tok.nonce(), *tok.token());
-    let mut req = Request::write(req);
+    let buf = req.write();
 
     let timestamp = unix_time();
-    let r = Request::_read(&mut req[..]).unwrap();
+    let r = Request::read(&buf[..]).unwrap();
+    assert_eq!(r, req);
     assert!(r.is_valid(protocol, timestamp));
-    let (expire, private) = r.open_token(&private_key).unwrap();
+    let (expire, private) = r.open_token::<DefaultAead>(&private_key).unwrap();
 
     assert_eq!(expire, tok.expire_timestamp());
     assert_eq!(&private.data()[..], &tok.data()[..]);
+
+    // padding is optional
+    let mut r = Request::read(&buf[..MIN_MTU]).unwrap();
+    assert!(r.is_valid(protocol, timestamp));
+    assert_eq!(r.cookie(), &[0u8; COOKIE_LEN]);
+    r.set_cookie([3u8; COOKIE_LEN]);
+    let mut raw = r.write();
+    match Packet::decode(&mut raw[..MIN_MTU]) {
+        Ok(Packet::Request(r)) => assert_eq!(r.cookie(), &[3u8; COOKIE_LEN]),
+        _ => panic!("bad request"),
+    }
+
+    let mut buf = [0u8; MTU];
+    let len = Packet::encode_cookie(&mut buf, &[7u8; COOKIE_LEN]);
+    match Packet::decode(&mut buf[..len]) {
+        Ok(Packet::Cookie(cookie)) => assert_eq!(cookie, &[7u8; COOKIE_LEN]),
+        _ => panic!("bad cookie"),
+    }
+    let cookie = Packet::decode(&mut buf[..len]).unwrap();
+    assert_eq!(cookie, Packet::Cookie(&[7u8; COOKIE_LEN]));
+    assert_ne!(cookie, Packet::Request(req.clone()));
+    assert!(format!("{:?}", req).starts_with("Request { prefix: 1, version: [79, 78, 73, 1]"));
+    assert!(format!("{:?}", cookie).starts_with("Cookie([7, 7,"));
+    assert_eq!(Request::read(&raw[..MIN_MTU - 1]), Err(DecodeError::TooShort));
+    assert_eq!(Request::read(&[1u8; MTU + 1][..]), Err(DecodeError::TooLong));
+    raw[0] = Resume::PREFIX;
+    assert_eq!(Request::read(&raw[..]), Err(DecodeError::BadPrefix));
+
+    // every check has its own error
+    assert_eq!(req.check(protocol + 1, timestamp), Err(HandshakeError::BadProtocol));
+    assert_eq!(req.check(protocol, tok.expire_timestamp()), Err(HandshakeError::Expired));
+    let mut raw = req.write();
+    raw[1] ^= 1;
+    assert_eq!(Request::read(&raw[..]).unwrap().check(protocol, timestamp), Err(HandshakeError::BadVersion));
+    let mut raw = req.write();
+    raw[REQUEST_LEN - COOKIE_LEN - 1] ^= 1;
+    let r = Request::read(&raw[..]).unwrap();
+    assert_eq!(r.check(protocol, timestamp), Ok(()));
+    assert_eq!(r.open_token::<DefaultAead>(&private_key).err(), Some(HandshakeError::BadMac));
+    assert_eq!(Packet::decode(&mut [0b1000_0001; MTU][..]), Err(DecodeError::BadPrefix));
+}
+
+#[test]
+fn probe_packet() {
+    use crate::crypto::{keygen, DefaultAead};
+
+    let protocol = 0x11223344_55667788;
+    let key = keygen();
+    let mut buf = [0u8; MTU];
+    let probe = Probe { size: 1100, id: 3, loss: 0x1234 };
+
+    let len = Packet::encode_probe::<DefaultAead>(protocol, &mut buf, 0x1234, &key, probe, false).unwrap();
+    assert_eq!(len, 1100);
+    match Packet::decode(&mut buf[..len]) {
+        Ok(Packet::Probe { prefix, ack: false, seq: 0x1234, buf, tag }) => {
+            Packet::open::<DefaultAead>(protocol, buf, 0x1234, prefix, tag, &key).unwrap();
+            assert_eq!(Probe::read(buf), Some(probe));
+        }
+        _ => panic!("bad probe"),
+    }
+
+    let len = Packet::encode_probe::<DefaultAead>(protocol, &mut buf, 7, &key, probe, true).unwrap();
+    assert_eq!(len, 2 + PROBE_LEN + HMAC);
+    match Packet::decode(&mut buf[..len]) {
+        Ok(Packet::Probe { prefix, ack: true, seq: 7, buf, tag }) => {
+            Packet::open::<DefaultAead>(protocol, buf, 7, prefix, tag, &key).unwrap();
+            assert_eq!(Probe::read(buf), Some(probe));
+        }
+        _ => panic!("bad probe ack"),
+    }
+
+    // ping
+    let ping = Probe { size: 0, id: 4, loss: 0 };
+    let len = Packet::encode_probe::<DefaultAead>(protocol, &mut buf, 8, &key, ping, false).unwrap();
+    assert_eq!(len, 2 + PROBE_LEN + HMAC);
+}
+
+#[test]
+fn resume_packets() {
+    use crate::{crypto::{keygen, DefaultAead}, token::{USER, Ticket}};
+
+    let protocol = 0x11223344_55667788;
+    let server_key = keygen();
+    let key = keygen();
+
+    let ticket = Ticket::new::<DefaultAead>(ResumeToken::generate(42, 12345, 5, [7u8; USER]), 0, &server_key);
+
+    let mut buf = [0u8; MTU];
+    let mut m = ticket.write();
+    let len = Packet::encode_ticket::<DefaultAead>(protocol, &mut buf, 0x1234, &key, &mut m).unwrap();
+    match Packet::decode(&mut buf[..len]) {
+        Ok(Packet::Ticket { prefix, seq: 0x1234, buf, tag }) => {
+            Packet::open::<DefaultAead>(protocol, buf, 0x1234, prefix, tag, &key).unwrap();
+            assert_eq!(&buf[..], &ticket.write()[..]);
+        }
+        _ => panic!("bad ticket"),
+    }
+
+    let resume = Resume::new(protocol, *ticket.token());
+    let mut buf = resume.write();
+    match Packet::decode(&mut buf[..MIN_MTU]) {
+        Ok(Packet::Resume(r)) => {
+            assert_eq!(r, resume);
+            assert!(r.is_valid(protocol));
+            assert!(!r.is_valid(protocol + 1));
+            let token = r.open_token::<DefaultAead>(&server_key).unwrap();
+            assert_eq!(token.client_id(), 42);
+            assert_eq!(token.client_key(), ticket.client_key());
+        }
+        _ => panic!("bad resume"),
+    }
 }
@@ -0,0 +1,112 @@
+//! Rekeying of long-lived connections.
+//!
+//! The sequence number is the nonce, so each key is used for `EPOCH_LEN` packets only.
+//! Epoch of the packet is `sequence / EPOCH_LEN`, the full sequence is sent,
+//! so the receiver computes the epoch and picks the previous, the current or the next key.
+//! The key of the next epoch is derived from the current one with `hchacha20`,
+//! so rekeying needs no extra packets and the first epoch uses keys from the token.
+//!
+//! Receiver keeps the key of the previous epoch for reordered packets
+//! and switches to the next epoch only after an authenticated packet.
+
+use crate::crypto::{hchacha20, Key};
+
+/// Number of packets sent with the same key.
+pub const EPOCH_LEN: u64 = 1 << 16;
+
+pub fn epoch(seq: u64) -> u64 { seq / EPOCH_LEN }
+
+/// Derives the key of `epoch` from the key of the previous one.
+pub fn next_key(key: &Key, epoch: u64) -> Key {
+    let mut input = [0u8; 16];
+    input[..8].copy_from_slice(b"oni rkey");
+    input[8..].copy_from_slice(&epoch.to_le_bytes());
+    hchacha20(&input, key, None)
+}
+
+pub struct KeySchedule {
+    epoch: u64,
+    key: Key,
+    prev: Option<Key>,
+}
+
+impl KeySchedule {
+    pub fn new(key: Key) -> Self {
+        Self { epoch: 0, key, prev: None }
+    }
+
+    /// Key of the current epoch.
+    pub fn key(&self) -> &Key { &self.key }
+
+    /// Switches to the epoch of the outgoing packet.
+    pub fn advance(&mut self, seq: u64) {
+        while self.epoch < epoch(seq) {
+            self.next();
+        }
+    }
+
+    /// Returns key for the incoming packet, `None` if its epoch is too old or too new.
+    pub fn get(&self, seq: u64) -> Option<Key> {
+        let epoch = epoch(seq);
+        if epoch == self.epoch {
+            Some(self.key)
+        } else if epoch + 1 == self.epoch {
+            self.prev
+        } else if epoch == self.epoch + 1 {
+            Some(next_key(&self.key, epoch))
+        } else {
+            None
+        }
+    }
+
+    /// Switches to the epoch of the authenticated incoming packet.
+    pub fn confirm(&mut self, seq: u64) {
+        if epoch(seq) == self.epoch + 1 {
+            self.next();
+        }
+    }
+
+    fn next(&mut self) {
+        self.epoch += 1;
+        self.prev = Some(self.key);
+        self.key = next_key(&self.key, self.epoch);
+    }
+}
+
+#[test]
+fn key_schedule() {
+    use crate::crypto::keygen;
+
+    let key = keygen();
+    let mut send = KeySchedule::new(key);
+    let mut recv = KeySchedule::new(key);
+
+    send.advance(EPOCH_LEN - 1);
+    assert_eq!(send.epoch, 0);
+    assert_eq!(send.key(), &key);
+    assert_eq!(recv.get(EPOCH_LEN - 1), Some(key));
+
+    // the next epoch isn't confirmed by the lookup
+    send.advance(EPOCH_LEN);
+    assert_eq!(send.epoch, 1);
+    assert_ne!(send.key(), &key);
+    assert_eq!(recv.get(EPOCH_LEN).as_ref(), Some(send.key()));
+    assert_eq!(recv.epoch, 0);
+
+    recv.confirm(EPOCH_LEN);
+    assert_eq!(recv.epoch, 1);
+    assert_eq!(recv.key(), send.key());
+    // reordered packets of the previous epoch
+    assert_eq!(recv.get(EPOCH_LEN - 1), Some(key));
+    recv.confirm(EPOCH_LEN - 1);
+    assert_eq!(recv.epoch, 1);
+
+    // keys don't repeat and old epochs are forgotten
+    send.advance(EPOCH_LEN * 3);
+    assert_eq!(send.epoch, 3);
+    assert_eq!(recv.get(EPOCH_LEN * 3), None);
+    recv.confirm(EPOCH_LEN * 2);
+    assert_eq!(recv.get(0), None);
+    assert_eq!(recv.get(EPOCH_LEN * 3).as_ref(), Some(send.key()));
+    assert_ne!(send.key(), &next_key(&key, 3));
+}
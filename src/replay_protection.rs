@@ -3,6 +3,7 @@ use crate::bitset::BitSet256;
 #[derive(Default)]
 pub struct ReplayProtection {
     seq: u64,
+    first: Option<u64>,
     bits: BitSet256,
 }
 
@@ -10,15 +11,33 @@ impl ReplayProtection {
     pub fn new() -> Self {
         Self {
             seq: 0,
+            first: None,
             bits: BitSet256::default(),
         }
     }
 
-    pub fn already_received(&mut self, seq: u64) -> bool {
+    /// Returns `true` if `seq` is too old or is already recorded.
+    pub fn is_received(&self, seq: u64) -> bool {
         let len = self.bits.len() as u64;
         if seq.wrapping_add(len) <= self.seq {
             return true;
         }
+        if seq > self.seq {
+            return false;
+        }
+        unsafe { self.bits.get_unchecked((seq % len) as usize) }
+    }
+
+    /// Records `seq` of authenticated packet.
+    ///
+    /// Records only after `open` succeeds,
+    /// so spoofed packets can't move the window or affect the loss.
+    pub fn insert(&mut self, seq: u64) {
+        let len = self.bits.len() as u64;
+        if seq.wrapping_add(len) <= self.seq {
+            return;
+        }
+        self.first = Some(self.first.map_or(seq, |first| first.min(seq)));
         if seq > self.seq {
             for bit in self.seq+1..=seq {
                 let bit = (bit % len) as usize;
@@ -29,12 +48,27 @@ impl ReplayProtection {
             }
             self.seq = seq;
         }
-        unsafe {
-            let bit = (seq % len) as usize;
-            let ret = self.bits.get_unchecked(bit);
-            self.bits.set_unchecked(bit);
-            ret
+        unsafe { self.bits.set_unchecked((seq % len) as usize); }
+    }
+
+    #[cfg(test)]
+    fn already_received(&mut self, seq: u64) -> bool {
+        if self.is_received(seq) {
+            return true;
         }
+        self.insert(seq);
+        false
+    }
+
+    /// Estimates loss of incoming packets by gaps in the last received sequences.
+    pub fn loss(&self) -> f32 {
+        let first = match self.first {
+            Some(first) => first,
+            None => return 0.0,
+        };
+        let window = (self.seq - first + 1).min(self.bits.len() as u64);
+        let received: u32 = self.bits.as_slice().iter().map(|b| b.count_ones()).sum();
+        1.0 - received as f32 / window as f32
     }
 }
 
@@ -69,3 +103,29 @@ fn replay_protection() {
         "Old packets should be considered already received");
     }
 }
+
+#[test]
+fn replay_protection_loss() {
+    let mut rp = ReplayProtection::new();
+    assert_eq!(rp.loss(), 0.0);
+
+    for sequence in 10..20 {
+        rp.already_received(sequence);
+    }
+    assert_eq!(rp.loss(), 0.0);
+
+    // every second packet is lost
+    for sequence in 20..1000 {
+        if sequence % 2 == 0 {
+            rp.already_received(sequence);
+        }
+    }
+    assert!((rp.loss() - 0.5).abs() < 0.01, "loss: {}", rp.loss());
+
+    // checking doesn't record
+    let loss = rp.loss();
+    assert!(!rp.is_received(100_000));
+    assert!(!rp.is_received(999));
+    assert_eq!(rp.loss(), loss);
+    assert_eq!(rp.seq, 998);
+}
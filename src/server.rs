@@ -1,22 +1,30 @@
 use crossbeam_channel::{Sender, Receiver, unbounded};
 //use crossbeam::queue::SegQueue;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use std::{
-    net::{SocketAddr, UdpSocket},
+    net::{IpAddr, SocketAddr, UdpSocket},
     time::{Instant, Duration},
-    collections::HashMap,
-    mem::uninitialized,
-    sync::atomic::{AtomicBool, AtomicU64, Ordering},
-    sync::Arc,
+    collections::{HashMap, VecDeque},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
+    marker::PhantomData,
 };
 use crate::{
     Socket,
-    protocol::{Packet, MTU, PACKET_SEND_DELTA, MAX_PAYLOAD, NUM_DISCONNECT_PACKETS},
-    crypto::{KEY, HMAC},
+    protocol::{Packet, Probe, DenyReason, CloseReason, HandshakeError, CLOSE_MESSAGE_LEN, MTU, MIN_MTU, PACKET_SEND_DELTA, NUM_DISCONNECT_PACKETS, max_payload},
+    path_mtu::PathMtu,
+    batch::{RecvBatch, SendBatch},
+    buffer::{Buffer, BufferPool},
+    stats::{Stats, StatsTracker, encode_loss},
+    crypto::{KEY, HMAC, Aead, DefaultAead, crypto_random},
+    rekey::KeySchedule,
     incoming::{Incoming, KeyPair},
+    filter::Filter,
     token::USER,
     replay_protection::ReplayProtection,
     server_list::ServerList,
+    server_system::Waker,
+    unix_time,
 };
 
 /*
@@ -26,30 +34,34 @@ fn example() {
     let mut server = Server::new(666, private_key, addr).unwrap();
 
     //let local_addr = server.local_addr();
-    let mut connected: HashSet<Connection> = HashSet::new();
+    let mut connected: HashMap<u64, Connection> = HashMap::new();
     loop {
-        server.update(|c, user| {
-            println!("connected {}:{:?} with data {:?}", c.id(), c.addr(), &user[..]);
-            connected.insert(c);
-        });
-
-        connected.retain(|c| !c.is_closed());
-
-        let mut buf = [0u8; MAX_PAYLOAD];
-        for client in &connected {
-            match client.recv(&mut buf[..]) {
-                Ok(0) => (),
-                Ok(len) => println!("recv: {:?}", &buf[..len as usize]),
-                Err(()) => continue,
+        server.update();
+        while let Some(event) = server.poll_event() {
+            match event {
+                ServerEvent::Accepted { connection: c, user } => {
+                    println!("connected {}:{:?} with data {:?}", c.id(), c.addr(), &user[..]);
+                    connected.insert(c.id(), c);
+                }
+                ServerEvent::Disconnected { id, .. } => {
+                    connected.remove(&id);
+                }
+                ServerEvent::Payload { id, data, .. } => {
+                    println!("recv: {:?}", &data[..]);
+                    let _ = connected[&id].send(b"fuck you").is_err();
+                }
+                ServerEvent::Denied { .. } => (),
             }
-
-            let _ = client.send(b"fuck you").is_err();
         }
     }
 }
 */
 
-pub type Payload = (u16, [u8; MAX_PAYLOAD]);
+enum Outgoing {
+    Payload(Buffer),
+    /// Disconnect packet with optional `[code] [message]`.
+    Close(Buffer),
+}
 
 /*
 struct Channel<A, B> {
@@ -106,12 +118,100 @@ impl<A, B> Channel<A, B> {
 }
 */
 
+/// Why the connection was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Nothing was received from client until timeout.
+    TimedOut,
+    /// Client sent disconnect packet.
+    ClientClosed,
+    /// Connection was closed by `Connection::close` or `Connection::close_with_reason`.
+    ServerClosed,
+    /// Client resumed the session with a ticket, the new connection replaces this one.
+    Resumed,
+}
+
+pub enum ServerEvent {
+    /// New connection, also after the client resumed the session with a ticket.
+    Accepted {
+        connection: Connection,
+        user: [u8; USER],
+    },
+    Denied {
+        addr: SocketAddr,
+        reason: DenyReason,
+    },
+    /// Request, challenge response or resume packet was ignored,
+    /// nothing is sent to the client.
+    Rejected {
+        addr: SocketAddr,
+        error: HandshakeError,
+    },
+    Disconnected {
+        id: u64,
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+    Payload {
+        id: u64,
+        addr: SocketAddr,
+        data: Buffer,
+    },
+    /// Client moved to the new address, e.g. after NAT rebinding.
+    ///
+    /// The connection is moved only after the client has answered
+    /// from the new address to an encrypted probe sent there.
+    Migrated {
+        id: u64,
+        from: SocketAddr,
+        to: SocketAddr,
+    },
+}
+
+impl std::fmt::Debug for ServerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ServerEvent::Accepted { connection, user } => f.debug_struct("Accepted")
+                .field("connection", connection)
+                .field("user", &&user[..])
+                .finish(),
+            ServerEvent::Denied { addr, reason } => f.debug_struct("Denied")
+                .field("addr", addr)
+                .field("reason", reason)
+                .finish(),
+            ServerEvent::Rejected { addr, error } => f.debug_struct("Rejected")
+                .field("addr", addr)
+                .field("error", error)
+                .finish(),
+            ServerEvent::Disconnected { id, addr, reason } => f.debug_struct("Disconnected")
+                .field("id", id)
+                .field("addr", addr)
+                .field("reason", reason)
+                .finish(),
+            ServerEvent::Payload { id, addr, data } => f.debug_struct("Payload")
+                .field("id", id)
+                .field("addr", addr)
+                .field("data", &&data[..])
+                .finish(),
+            ServerEvent::Migrated { id, from, to } => f.debug_struct("Migrated")
+                .field("id", id)
+                .field("from", from)
+                .field("to", to)
+                .finish(),
+        }
+    }
+}
+
 pub struct Connection {
     closed: Arc<AtomicBool>,
-    recv_ch: Receiver<Payload>,
-    send_ch: Sender<(SocketAddr, Payload)>,
-    addr: SocketAddr,
+    send_ch: Sender<(u64, Outgoing)>,
+    waker: Arc<Waker>,
+    addr: Arc<Mutex<SocketAddr>>,
     id: u64,
+    mtu: usize,
+    path_mtu: Arc<AtomicUsize>,
+    stats: Arc<Mutex<Stats>>,
+    pool: BufferPool,
 }
 
 impl std::hash::Hash for Connection {
@@ -121,6 +221,16 @@ impl std::hash::Hash for Connection {
     }
 }
 
+impl std::fmt::Debug for Connection {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("id", &self.id)
+            .field("addr", &self.addr())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
 impl Eq for Connection {}
 impl PartialEq for Connection {
     fn eq(&self, other: &Self) -> bool {
@@ -130,7 +240,21 @@ impl PartialEq for Connection {
 
 impl Connection {
     pub fn id(&self) -> u64 { self.id }
-    pub fn addr(&self) -> SocketAddr { self.addr }
+    /// Current address of the client, it changes after `ServerEvent::Migrated`.
+    pub fn addr(&self) -> SocketAddr { *self.addr.lock().unwrap() }
+
+    /// Negotiated MTU of this connection.
+    pub fn mtu(&self) -> usize { self.mtu }
+    /// Maximum length of payload accepted by `send`.
+    pub fn max_payload(&self) -> usize { max_payload(self.mtu) }
+
+    /// MTU confirmed by path MTU discovery.
+    pub fn path_mtu(&self) -> usize { self.path_mtu.load(Ordering::Relaxed) }
+    /// Length of payload which gets through this path.
+    pub fn usable_payload(&self) -> usize { max_payload(self.path_mtu()) }
+
+    /// Network statistics, updated by `Server::update`.
+    pub fn stats(&self) -> Stats { *self.stats.lock().unwrap() }
 
     #[inline]
     pub fn is_closed(&self) -> bool {
@@ -138,72 +262,121 @@ impl Connection {
     }
 
     pub fn close(&self) {
+        self.send_close(&[]);
+    }
+
+    /// Closes the connection, the client gets `reason` and `message`.
+    ///
+    /// Message is truncated to `CLOSE_MESSAGE_LEN` bytes.
+    pub fn close_with_reason(&self, reason: CloseReason, message: &str) {
+        let mut len = message.len().min(CLOSE_MESSAGE_LEN);
+        while !message.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut m = [0u8; 1 + CLOSE_MESSAGE_LEN];
+        m[0] = reason.code();
+        m[1..=len].copy_from_slice(&message.as_bytes()[..len]);
+        self.send_close(&m[..=len]);
+    }
+
+    fn send_close(&self, m: &[u8]) {
         if self.is_closed() { return; }
         self.closed.store(true, Ordering::SeqCst);
         // send disconnect packets
         for _ in 0..NUM_DISCONNECT_PACKETS {
-            self.send_ch.send((self.addr, (0, unsafe { uninitialized() })));
+            self.send_ch.send((self.id, Outgoing::Close(self.pool.copy_from(m))));
         }
+        self.waker.wake();
     }
 
-    pub fn recv(&self, buf: &mut [u8; MAX_PAYLOAD]) -> Result<u16, ()> {
-        if self.is_closed() {
+    /// Returns an empty buffer from the server's pool, see `send_buffer`.
+    pub fn buffer(&self) -> Buffer { self.pool.get() }
+
+    pub fn send(&self, buf: &[u8]) -> Result<usize, ()> {
+        if self.is_closed() || buf.len() > self.max_payload() {
             Err(())
+        } else if buf.is_empty() {
+            Ok(0)
         } else {
-            match self.recv_ch.try_recv() {
-                Some((len, payload)) => {
-                    buf[..len as usize].copy_from_slice(&payload[..len as usize]);
-                    Ok(len)
-                }
-                None => Ok(0)
-            }
+            self.send_buffer(self.pool.copy_from(buf))
         }
     }
 
-    pub fn send(&self, buf: &[u8]) -> Result<usize, ()> {
-        if self.is_closed() {
+    /// Sends the buffer without copying.
+    pub fn send_buffer(&self, buf: Buffer) -> Result<usize, ()> {
+        let len = buf.len();
+        if self.is_closed() || len > self.max_payload() {
             Err(())
+        } else if len == 0 {
+            Ok(0)
         } else {
-            let len = buf.len().min(MAX_PAYLOAD);
-            if len == 0 {
-                Ok(0)
-            } else {
-                let mut payload = [0u8; MAX_PAYLOAD];
-                payload[..len].copy_from_slice(&buf[..len]);
-                self.send_ch.send((self.addr, (len as u16, payload)));
-                Ok(len)
-            }
+            self.send_ch.send((self.id, Outgoing::Payload(buf)));
+            self.waker.wake();
+            Ok(len)
         }
     }
 }
 
-struct Conn {
+struct Conn<C: Aead> {
     closed: Arc<AtomicBool>,
-    recv_queue: Sender<Payload>,
     sequence: Arc<AtomicU64>,
 
     last_recv: Instant,
     last_send: Instant,
     timeout: Duration,
-    send_key: [u8; KEY],
-    recv_key: [u8; KEY],
+    send_key: KeySchedule,
+    recv_key: KeySchedule,
     id: u64,
     replay_protection: ReplayProtection,
+
+    path_mtu: PathMtu,
+    path_mtu_shared: Arc<AtomicUsize>,
+
+    stats: StatsTracker,
+    stats_shared: Arc<Mutex<Stats>>,
+
+    addr_shared: Arc<Mutex<SocketAddr>>,
+    /// Unconfirmed address, probe id and time of the path challenge.
+    path_challenge: Option<(SocketAddr, u16, Instant)>,
+
+    user: [u8; USER],
+    timeout_secs: u32,
+    resume_window: Duration,
+    next_ticket: Option<Instant>,
+
+    _aead: PhantomData<fn() -> C>,
 }
 
-impl Conn {
-    fn new(id: u64, time: Instant, keys: &KeyPair, recv_queue: Sender<Payload>) -> Self {
+impl<C: Aead> Conn<C> {
+    /// Minimal time between path challenges.
+    const PATH_CHALLENGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn new(id: u64, addr: SocketAddr, time: Instant, keys: &KeyPair, user: [u8; USER], resume_window: Duration) -> Self {
         Self {
             last_send: time,
             last_recv: time,
-            recv_key: *keys.recv_key(),
-            send_key: *keys.send_key(),
+            recv_key: KeySchedule::new(*keys.recv_key()),
+            send_key: KeySchedule::new(*keys.send_key()),
             timeout: keys.timeout(),
             id,
             replay_protection: ReplayProtection::new(),
             sequence: Arc::new(AtomicU64::new(1)),
-            recv_queue,
             closed: Arc::new(AtomicBool::new(false)),
+
+            path_mtu: PathMtu::new(MIN_MTU, keys.mtu()),
+            path_mtu_shared: Arc::new(AtomicUsize::new(MIN_MTU)),
+
+            stats: StatsTracker::new(time),
+            stats_shared: Arc::new(Mutex::new(Stats::default())),
+
+            addr_shared: Arc::new(Mutex::new(addr)),
+            path_challenge: None,
+
+            user,
+            timeout_secs: keys.timeout_secs(),
+            resume_window,
+            next_ticket: if resume_window == Duration::from_secs(0) { None } else { Some(time) },
+            _aead: PhantomData,
         }
     }
 
@@ -211,41 +384,149 @@ impl Conn {
         self.closed.load(Ordering::SeqCst) || self.last_recv + self.timeout < time
     }
 
+    /// Returns time of the next keep-alive, probe or timeout.
+    fn deadline(&self, time: Instant) -> Instant {
+        let mut deadline = (self.last_send + PACKET_SEND_DELTA)
+            .min(self.last_recv + self.timeout)
+            .min(self.stats.ping_deadline(time));
+        if let Some(probe) = self.path_mtu.deadline(time) {
+            deadline = deadline.min(probe);
+        }
+        if let Some(ticket) = self.next_ticket {
+            deadline = deadline.min(ticket);
+        }
+        deadline
+    }
+
     fn seq_send(&mut self, time: Instant) -> u64 {
         self.last_send = time;
-        self.sequence.fetch_add(1, Ordering::Relaxed)
+        let seq = self.sequence.fetch_add(1, Ordering::Relaxed);
+        self.send_key.advance(seq);
+        seq
+    }
+
+    /// Decrypts the packet with the key of its epoch, replayed packets are dropped.
+    fn open(&mut self, protocol: u64, prefix: u8, seq: u64, m: &mut [u8], tag: &[u8; HMAC]) -> bool {
+        if self.replay_protection.is_received(seq) {
+            return false;
+        }
+        let key = match self.recv_key.get(seq) {
+            Some(key) => key,
+            None => return false,
+        };
+        if Packet::open::<C>(protocol, m, seq, prefix, tag, &key).is_err() {
+            return false;
+        }
+        self.recv_key.confirm(seq);
+        self.replay_protection.insert(seq);
+        true
     }
 
     fn process_payload<'a>(&mut self, protocol: u64, seq: u64, m: &'a mut [u8], tag: &[u8; HMAC], time: Instant) -> Option<&'a [u8]> {
-        if self.replay_protection.already_received(seq) {
+        if !self.open(protocol, 0, seq, m, tag) {
             return None;
         }
-        if Packet::open(protocol, m, seq, 0, tag, &self.recv_key).is_err() {
+
+        self.last_recv = time;
+        Some(m)
+    }
+
+    fn process_probe(&mut self, protocol: u64, prefix: u8, seq: u64, m: &mut [u8], tag: &[u8; HMAC], time: Instant) -> Option<Probe> {
+        if !self.open(protocol, prefix, seq, m, tag) {
             return None;
         }
-
         self.last_recv = time;
+        let probe = Probe::read(m)?;
+        self.stats.set_send_loss(probe.loss);
+        Some(probe)
+    }
 
-        if !m.is_empty() {
-            let mut packet = [0u8; MAX_PAYLOAD];
-            packet[..m.len()].copy_from_slice(m);
-            self.recv_queue.send((m.len() as u16, packet));
-            Some(m)
-        } else {
-            None
+    fn ack_probe(&mut self, probe: Probe, time: Instant) {
+        self.path_mtu.ack(probe.size as usize);
+        self.path_mtu_shared.store(self.path_mtu.mtu(), Ordering::Relaxed);
+        self.stats.pong(probe.id, time);
+    }
+
+    /// Returns the next probe or ping, if it should be sent now.
+    fn next_probe(&mut self, time: Instant) -> Option<Probe> {
+        let size = match self.path_mtu.probe(time) {
+            Some(size) => size as u16,
+            None if self.stats.need_ping(time) => 0,
+            None => return None,
+        };
+        let id = self.stats.ping(time);
+        Some(Probe { size, id, loss: encode_loss(self.replay_protection.loss()) })
+    }
+
+    fn update_stats(&mut self, time: Instant) {
+        self.stats.update(time, self.replay_protection.loss());
+        *self.stats_shared.lock().unwrap() = self.stats.stats();
+    }
+
+    /// Returns `true` if the packet is authenticated by the key of this client.
+    ///
+    /// Unlike `Packet::open` it doesn't touch `m`, so it's used to find the client
+    /// for a packet from unknown address.
+    fn authenticate(&self, protocol: u64, prefix: u8, seq: u64, m: &[u8], tag: &[u8; HMAC]) -> bool {
+        let key = match self.recv_key.get(seq) {
+            Some(key) => key,
+            None => return false,
+        };
+        let mut copy = [0u8; MTU];
+        let copy = &mut copy[..m.len()];
+        copy.copy_from_slice(m);
+        Packet::open::<C>(protocol, copy, seq, prefix, tag, &key).is_ok()
+    }
+
+    /// Returns a ping to send to the new address, if there is no pending challenge.
+    fn challenge_path(&mut self, addr: SocketAddr, time: Instant) -> Option<Probe> {
+        if let Some((_, _, sent)) = self.path_challenge {
+            if sent + Self::PATH_CHALLENGE_TIMEOUT > time {
+                return None;
+            }
         }
+        let mut id = [0u8; 2];
+        crypto_random(&mut id);
+        let id = u16::from_le_bytes(id);
+        self.path_challenge = Some((addr, id, time));
+        Some(Probe { size: 0, id, loss: encode_loss(self.replay_protection.loss()) })
     }
 
-    fn process_disconnect(&mut self, protocol: u64, prefix: u8, seq: u64, tag: &[u8; HMAC]) -> bool {
-        if self.replay_protection.already_received(seq) {
-            false
-        } else {
-            Packet::open(protocol, &mut [], seq, prefix, tag, &self.recv_key).is_ok()
+    /// Returns `true` if the acknowledged probe answers the path challenge.
+    fn is_path_response(&self, addr: SocketAddr, probe: Probe) -> bool {
+        match self.path_challenge {
+            Some((to, id, _)) => to == addr && id == probe.id,
+            None => false,
+        }
+    }
+
+    /// Moves the connection to the confirmed address,
+    /// path MTU discovery starts again for the new path.
+    fn migrate(&mut self, addr: SocketAddr) {
+        self.path_challenge = None;
+        *self.addr_shared.lock().unwrap() = addr;
+        self.path_mtu = PathMtu::new(MIN_MTU, self.path_mtu.max());
+        self.path_mtu_shared.store(self.path_mtu.mtu(), Ordering::Relaxed);
+    }
+
+    /// Returns expire timestamp of the next resumption ticket, if it should be sent now.
+    fn next_ticket(&mut self, time: Instant) -> Option<u64> {
+        match self.next_ticket {
+            Some(next) if next <= time => {
+                // the client always has a valid ticket
+                self.next_ticket = Some(time + self.resume_window / 3);
+                Some(unix_time() + self.resume_window.as_secs())
+            }
+            _ => None,
         }
     }
+
+    fn process_disconnect(&mut self, protocol: u64, prefix: u8, seq: u64, m: &mut [u8], tag: &[u8; HMAC]) -> bool {
+        self.open(protocol, prefix, seq, m, tag)
+    }
 }
 
-impl Drop for Conn {
+impl<C: Aead> Drop for Conn<C> {
     fn drop(&mut self) {
         self.closed.store(true, Ordering::SeqCst);
     }
@@ -255,29 +536,43 @@ use self::ConnectionError::*;
 
 enum ConnectionError {
     InvalidPacket,
+    Rejected(HandshakeError),
     AlreadyConnected,
     TokenAlreadyUsed,
-    ConnectionDenied([u8; KEY]),
+    ConnectionDenied([u8; KEY], DenyReason),
 }
 
-pub struct Server<S: Socket = UdpSocket> {
+pub struct Server<S: Socket = UdpSocket, C: Aead = DefaultAead> {
     time: Instant,
     protocol: u64,
 
     socket: S,
     local_addr: SocketAddr,
 
-    recv_ch: Receiver<(SocketAddr, Payload)>,
-    send_ch: Sender<(SocketAddr, Payload)>,
+    recv_ch: Receiver<(u64, Outgoing)>,
+    send_ch: Sender<(u64, Outgoing)>,
+    waker: Arc<Waker>,
 
-    connected: HashMap<SocketAddr, Conn>,
+    connected: HashMap<SocketAddr, Conn<C>>,
     connected_by_id: FnvHashMap<u64, SocketAddr>,
+    /// Subnets of unknown addresses checked against all clients in this update.
+    migration_checks: FnvHashSet<IpAddr>,
 
-    incoming: Incoming,
+    incoming: Incoming<C>,
+    filter: Filter,
 
     global_sequence: AtomicU64,
 
     capacity: usize,
+    mtu: usize,
+    resume_window: Duration,
+
+    events: VecDeque<ServerEvent>,
+    shutdown: bool,
+
+    rx: RecvBatch,
+    tx: SendBatch,
+    pool: BufferPool,
 }
 
 impl Server<UdpSocket> {
@@ -296,199 +591,593 @@ impl Server<crate::SimulatedSocket> {
 
 impl<S: Socket> Server<S> {
     pub fn with_socket(protocol: u64, private: [u8; KEY], socket: S) -> std::io::Result<Self> {
+        Self::with_aead(protocol, private, socket)
+    }
+}
+
+#[cfg(unix)]
+impl<S: Socket + std::os::unix::io::AsRawFd, C: Aead> std::os::unix::io::AsRawFd for Server<S, C> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd { self.socket.as_raw_fd() }
+}
+
+#[cfg(windows)]
+impl<S: Socket + std::os::windows::io::AsRawSocket, C: Aead> std::os::windows::io::AsRawSocket for Server<S, C> {
+    fn as_raw_socket(&self) -> std::os::windows::io::RawSocket { self.socket.as_raw_socket() }
+}
+
+impl<S: Socket, C: Aead> Server<S, C> {
+    /// Maximum number of packets from unknown addresses per `update`,
+    /// which are checked against keys of all clients to find migrated ones.
+    ///
+    /// Every subnet of `Filter::subnet` is charged only once per `update`,
+    /// so a flood from one subnet can't take the checks of other ones.
+    pub const MAX_MIGRATION_CHECKS: usize = 16;
+
+    /// Default time for which resumption tickets are valid.
+    pub const RESUME_WINDOW: Duration = Duration::from_secs(30);
+
+    /// Default number of connection requests per second before cookies are required.
+    pub const COOKIE_THRESHOLD: usize = 256;
+
+    /// Creates the server which uses `C` for encryption instead of `DefaultAead`.
+    ///
+    /// Clients must use the same `Aead`, see `Client::with_aead` and `PublicToken::generate_with`.
+    pub fn with_aead(protocol: u64, private: [u8; KEY], socket: S) -> std::io::Result<Self> {
         socket.set_nonblocking(true)?;
         let local_addr = socket.local_addr()?;
 
         let (send_ch, recv_ch) = unbounded();
 
+        let mut incoming = Incoming::new(protocol, private);
+        incoming.set_cookie_threshold(Self::COOKIE_THRESHOLD);
+
         Ok(Self {
             time: Instant::now(),
             protocol,
-            incoming: Incoming::new(protocol, private),
+            incoming,
+            filter: Filter::new(),
 
             socket,
             local_addr,
 
             recv_ch,
             send_ch,
+            waker: Arc::new(Waker::default()),
 
             connected: HashMap::default(),
             connected_by_id: HashMap::default(),
+            migration_checks: FnvHashSet::default(),
 
             global_sequence: AtomicU64::new(0x0100_0000),
 
             capacity: 0,
+            mtu: MTU,
+            resume_window: Self::RESUME_WINDOW,
+
+            events: VecDeque::new(),
+            shutdown: false,
+
+            rx: RecvBatch::new(),
+            tx: SendBatch::new(),
+            pool: BufferPool::new(),
         })
     }
 
     #[doc(hidden)]
     pub fn socket(&self) -> &S { &self.socket }
 
+    /// Wakes the I/O thread of `ThreadedServer` when connections queue packets.
+    pub(crate) fn waker(&self) -> &Arc<Waker> { &self.waker }
+
     pub fn local_addr(&self) -> SocketAddr { self.local_addr }
 
-    pub fn update<F>(&mut self, mut callback: F)
-        where F: FnMut(Connection, &[u8; USER])
-    {
+    pub fn mtu(&self) -> usize { self.mtu }
+
+    /// Sets MTU for new connections.
+    ///
+    /// Connection uses the smaller of this value and the size of request packet sent by client.
+    ///
+    /// The lower bound is `MIN_MTU`, see `Client::set_mtu`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mtu` is not in `MIN_MTU..=MTU`.
+    pub fn set_mtu(&mut self, mtu: usize) {
+        assert!(mtu >= MIN_MTU && mtu <= MTU, "MTU must be in {}..={}", MIN_MTU, MTU);
+        self.mtu = mtu;
+    }
+
+    /// Maximum number of connected clients, zero means unlimited.
+    pub fn max_clients(&self) -> usize { self.capacity }
+
+    /// Sets maximum number of connected clients, zero means unlimited.
+    ///
+    /// Clients over the limit receive the denied packet with `DenyReason::ServerFull`.
+    /// Already connected clients are not affected.
+    pub fn set_max_clients(&mut self, max: usize) {
+        self.capacity = max;
+    }
+
+    /// Time for which resumption tickets are valid.
+    pub fn resume_window(&self) -> Duration { self.resume_window }
+
+    /// Sets time for which resumption tickets of new connections are valid,
+    /// zero disables them.
+    ///
+    /// Connected clients periodically get a ticket.
+    /// After a disconnect the client can use it once to reconnect with the same id and user data,
+    /// see `Client::resume`.
+    /// If the old connection isn't timed out yet, the resumed one replaces it.
+    /// Tickets are sealed by a random key of this server, so they are valid only for it.
+    pub fn set_resume_window(&mut self, window: Duration) {
+        self.resume_window = window;
+    }
+
+    /// Number of connection requests per second before cookies are required.
+    pub fn cookie_threshold(&self) -> usize { self.incoming.cookie_threshold() }
+
+    /// Sets number of connection requests per second before cookies are required,
+    /// zero requires them always.
+    ///
+    /// Under load the server answers requests with a cookie bound to the address of the client
+    /// and keeps no state for them.
+    /// The client repeats the request with the cookie,
+    /// so the token is decrypted only for clients that own their address.
+    pub fn set_cookie_threshold(&mut self, threshold: usize) {
+        self.incoming.set_cookie_threshold(threshold);
+    }
+
+    /// Number of connected clients.
+    pub fn num_clients(&self) -> usize { self.connected.len() }
+
+    /// Returns time when `update` should be called next,
+    /// `None` if it's needed only when the socket is readable.
+    ///
+    /// Returned time can be in the past, `update` should be called immediately in this case.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if !self.recv_ch.is_empty() {
+            return Some(self.time);
+        }
+        let time = self.time;
+        self.connected.values().map(|c| c.deadline(time)).min()
+    }
+
+    /// Ban list and rate limits of incoming datagrams.
+    pub fn filter(&self) -> &Filter { &self.filter }
+
+    /// Ban list and rate limits of incoming datagrams.
+    ///
+    /// Datagrams from banned or limited addresses are dropped before any processing.
+    /// Connected clients are dropped only by bans, rate limits don't apply to them.
+    pub fn filter_mut(&mut self) -> &mut Filter { &mut self.filter }
+
+    /// Pool of buffers used by `Connection` and `ServerEvent::Payload`.
+    pub fn buffer_pool(&self) -> &BufferPool { &self.pool }
+
+    /// Returns `true` if `shutdown` was called.
+    pub fn is_shutdown(&self) -> bool { self.shutdown }
+
+    /// Closes all connections and stops accepting new ones.
+    ///
+    /// Payloads queued by `Connection::send` are sent before the close packets,
+    /// clients get `CloseReason::ServerShutdown`.
+    /// Connection requests are denied with `DenyReason::ShuttingDown` afterwards.
+    pub fn shutdown(&mut self) {
+        oni_trace::scope![server shutdown];
+
+        self.shutdown = true;
+
+        let now = Instant::now();
+        self.time = now;
+
+        let mut tx = std::mem::replace(&mut self.tx, SendBatch::default());
+        self.send_queued(now, &mut tx);
+
+        for (addr, mut c) in self.connected.drain() {
+            for _ in 0..NUM_DISCONNECT_PACKETS {
+                // sealed in place
+                let mut reason = [CloseReason::ServerShutdown.code()];
+                let seq = c.seq_send(now);
+                let protocol = self.protocol;
+                tx.encode(&self.socket, addr, |buf| {
+                    Packet::encode_close::<C>(protocol, buf, seq, c.send_key.key(), &mut reason).unwrap()
+                });
+            }
+            self.events.push_back(ServerEvent::Disconnected {
+                id: c.id,
+                addr,
+                reason: DisconnectReason::ServerClosed,
+            });
+        }
+        self.connected_by_id.clear();
+
+        tx.flush(&self.socket);
+        self.tx = tx;
+    }
+
+    /// Returns the next event queued by `update`.
+    pub fn poll_event(&mut self) -> Option<ServerEvent> {
+        self.events.pop_front()
+    }
+
+    pub fn update(&mut self) {
         oni_trace::scope![server update];
 
         self.incoming.update();
 
         let now = Instant::now();
         self.time = now;
+        self.migration_checks.clear();
+        self.filter.update(now);
 
-        let mut buffer = [0u8; MTU];
+        let mut rx = std::mem::replace(&mut self.rx, RecvBatch::default());
+        let mut tx = std::mem::replace(&mut self.tx, SendBatch::default());
         {
             oni_trace::scope![check socket];
-            while let Ok((len, addr)) = self.socket.recv_from(&mut buffer[..]) {
-                oni_trace::scope![recv_from];
-                match self.process_packet(&mut buffer[..len], addr, &mut callback) {
-                    Ok(0) => (),
-                    Ok(len) => {
-                        let _ = self.socket.send_to(&buffer[..len], addr);
-                    }
-                    Err(ConnectionDenied(key)) => {
-                        let seq = self.global_sequence.fetch_add(1, Ordering::Relaxed);
-                        let len = Packet::encode_close(self.protocol, &mut buffer, seq, &key)
-                            .unwrap();
-                        let _ = self.socket.send_to(&buffer[..len], addr);
+            while let Ok(count) = rx.recv(&self.socket) {
+                oni_trace::scope![recv_batch];
+                for i in 0..count {
+                    let (buffer, len, addr) = rx.get_mut(i);
+                    if len != 0 {
+                        self.process_datagram(buffer, len, addr, &mut tx);
                     }
-                    Err(_) => (),
+                }
+                if count < rx.capacity() {
+                    break;
                 }
             }
         }
 
-        // events
-        let socket = &mut self.socket;
-        let count = self.recv_ch.len();
-        {
-            oni_trace::scope![events];
-            for _ in 0..count {
-                let (addr, (len, mut payload)) = self.recv_ch.recv().unwrap();
-                let client = match self.connected.get_mut(&addr) {
-                    Some(c) => c,
-                    None => continue,
-                };
-                let seq = client.seq_send(now);
-                let key = &client.send_key;
-                let len = if len == 0 {
-                    Packet::encode_close(self.protocol, &mut buffer, seq, &key).unwrap()
-                } else {
-                    let m = &mut payload[..len as usize];
-                    Packet::encode_payload(self.protocol, &mut buffer, seq, &key, m).unwrap()
-                };
-                let _ = socket.send_to(&buffer[..len], addr);
-            }
-        }
+        self.send_queued(now, &mut tx);
 
         {
             oni_trace::scope![check for timeout];
             let by_id = &mut self.connected_by_id;
-            self.connected.retain(|_, c| {
+            let events = &mut self.events;
+            self.connected.retain(|&addr, c| {
                 let remove = c.check(now);
-                if remove { by_id.remove(&c.id).unwrap(); }
+                if remove {
+                    by_id.remove(&c.id).unwrap();
+                    let reason = if c.closed.load(Ordering::SeqCst) {
+                        DisconnectReason::ServerClosed
+                    } else {
+                        DisconnectReason::TimedOut
+                    };
+                    events.push_back(ServerEvent::Disconnected { id: c.id, addr, reason });
+                }
                 !remove
             });
         }
 
+        let protocol = self.protocol;
+        let socket = &self.socket;
+
         {
             oni_trace::scope![send keep-alive];
-            let deadline = now - PACKET_SEND_DELTA;
-            for (addr, c) in self.connected.iter_mut().filter(|(_, c)| c.last_send > deadline) {
+            for (addr, c) in self.connected.iter_mut().filter(|(_, c)| c.last_send + PACKET_SEND_DELTA <= now) {
                 let seq = c.seq_send(now);
-                let key = &c.send_key;
-                let len = Packet::encode_keep_alive(self.protocol, &mut buffer, seq, &key).unwrap();
-                let _ = socket.send_to(&buffer[..len], *addr);
+                let key = c.send_key.key();
+                let stats = &mut c.stats;
+                tx.encode(socket, *addr, |buf| {
+                    let len = Packet::encode_keep_alive::<C>(protocol, buf, seq, &key).unwrap();
+                    stats.on_send(len);
+                    len
+                });
+            }
+        }
+
+        {
+            oni_trace::scope![send probes];
+            for (addr, c) in self.connected.iter_mut() {
+                if let Some(probe) = c.next_probe(now) {
+                    let seq = c.seq_send(now);
+                    let key = c.send_key.key();
+                    let stats = &mut c.stats;
+                    tx.encode(socket, *addr, |buf| {
+                        let len = Packet::encode_probe::<C>(protocol, buf, seq, &key, probe, false).unwrap();
+                        stats.on_send(len);
+                        len
+                    });
+                }
+            }
+        }
+
+        {
+            oni_trace::scope![send tickets];
+            for (addr, c) in self.connected.iter_mut() {
+                if let Some(expire) = c.next_ticket(now) {
+                    let mut ticket = self.incoming.gen_ticket(c.id, expire, c.timeout_secs, c.user).write();
+                    let seq = c.seq_send(now);
+                    let key = c.send_key.key();
+                    let stats = &mut c.stats;
+                    tx.encode(socket, *addr, |buf| {
+                        let len = Packet::encode_ticket::<C>(protocol, buf, seq, &key, &mut ticket).unwrap();
+                        stats.on_send(len);
+                        len
+                    });
+                }
+            }
+        }
+
+        tx.flush(socket);
+        self.rx = rx;
+        self.tx = tx;
+
+        {
+            oni_trace::scope![update stats];
+            for c in self.connected.values_mut() {
+                c.update_stats(now);
             }
         }
     }
 
+    /// Processes received datagram, the response is queued into `tx`.
+    fn process_datagram(&mut self, buffer: &mut [u8; MTU], len: usize, addr: SocketAddr, tx: &mut SendBatch) {
+        let allowed = if self.connected.contains_key(&addr) {
+            self.filter.check_connected(addr.ip(), self.time)
+        } else {
+            self.filter.check(addr.ip(), self.time)
+        };
+        if !allowed {
+            return;
+        }
+        match self.process_packet(buffer, len, addr) {
+            Ok(0) => (),
+            Ok(len) => {
+                if let Some(c) = self.connected.get_mut(&addr) {
+                    c.stats.on_send(len);
+                }
+                tx.push(&self.socket, &buffer[..len], addr);
+            }
+            Err(ConnectionDenied(key, reason)) => {
+                let seq = self.global_sequence.fetch_add(1, Ordering::Relaxed);
+                let protocol = self.protocol;
+                tx.encode(&self.socket, addr, |buf| {
+                    let mut code = [reason.code()];
+                    Packet::encode_close::<C>(protocol, buf, seq, &key, &mut code).unwrap()
+                });
+                self.events.push_back(ServerEvent::Denied { addr, reason });
+            }
+            Err(AlreadyConnected) => {
+                self.events.push_back(ServerEvent::Denied { addr, reason: DenyReason::AlreadyConnected });
+            }
+            Err(TokenAlreadyUsed) => {
+                self.events.push_back(ServerEvent::Denied { addr, reason: DenyReason::TokenAlreadyUsed });
+            }
+            Err(Rejected(error)) => {
+                oni_trace::instant!("rejected" => error.as_str());
+                self.events.push_back(ServerEvent::Rejected { addr, error });
+            }
+            Err(InvalidPacket) => (),
+        }
+    }
+
+    /// Queues payloads and close packets sent by `Connection`.
+    fn send_queued(&mut self, now: Instant, tx: &mut SendBatch) {
+        oni_trace::scope![events];
+        let count = self.recv_ch.len();
+        for _ in 0..count {
+            let (id, outgoing) = self.recv_ch.recv().unwrap();
+            let addr = match self.connected_by_id.get(&id) {
+                Some(&addr) => addr,
+                None => continue,
+            };
+            let client = self.connected.get_mut(&addr).unwrap();
+            let seq = client.seq_send(now);
+            let key = client.send_key.key();
+            let stats = &mut client.stats;
+            let protocol = self.protocol;
+            tx.encode(&self.socket, addr, |buf| {
+                let len = match outgoing {
+                    Outgoing::Close(mut m) => Packet::encode_close::<C>(protocol, buf, seq, &key, &mut m).unwrap(),
+                    Outgoing::Payload(mut m) => Packet::encode_payload::<C>(protocol, buf, seq, &key, &mut m).unwrap(),
+                };
+                stats.on_send(len);
+                len
+            });
+        }
+    }
+
     fn is_already_connected(&self, addr: SocketAddr, id: u64) -> bool {
         self.connected.contains_key(&addr) || self.connected_by_id.contains_key(&id)
     }
 
     fn can_connect(&self) -> bool {
-        self.capacity == 0 || self.capacity <= self.connected.len()
+        self.capacity == 0 || self.connected.len() < self.capacity
+    }
+
+    /// Returns the current address of the client which sent the packet from `addr`.
+    ///
+    /// If `addr` is unknown, the packet is checked against the client
+    /// with the pending path challenge to `addr`, or against keys of all clients
+    /// once per subnet and up to `MAX_MIGRATION_CHECKS` times per update.
+    fn find_client(&mut self, addr: SocketAddr, prefix: u8, seq: u64, m: &[u8], tag: &[u8; HMAC]) -> Option<SocketAddr> {
+        if self.connected.contains_key(&addr) {
+            return Some(addr);
+        }
+        let protocol = self.protocol;
+        let challenged = self.connected.iter()
+            .find(|(_, c)| c.path_challenge.map_or(false, |(to, _, _)| to == addr));
+        if let Some((&current, c)) = challenged {
+            if c.authenticate(protocol, prefix, seq, m, tag) {
+                return Some(current);
+            }
+        }
+        if self.migration_checks.len() >= Self::MAX_MIGRATION_CHECKS {
+            return None;
+        }
+        if !self.migration_checks.insert(self.filter.subnet(addr.ip())) {
+            return None;
+        }
+        self.connected.iter()
+            .find(|(_, c)| c.authenticate(protocol, prefix, seq, m, tag))
+            .map(|(&current, _)| current)
+    }
+
+    /// Moves the client at `from` to the confirmed address `to`.
+    fn migrate(&mut self, from: SocketAddr, to: SocketAddr) {
+        if self.connected.contains_key(&to) {
+            return;
+        }
+        let mut client = self.connected.remove(&from).unwrap();
+        client.migrate(to);
+        self.connected_by_id.insert(client.id, to);
+        self.events.push_back(ServerEvent::Migrated { id: client.id, from, to });
+        self.connected.insert(to, client);
+    }
+
+    /// Writes the path challenge for the client at `current` into `buffer`.
+    fn challenge_path(&mut self, buffer: &mut [u8], current: SocketAddr, addr: SocketAddr) -> usize {
+        let time = self.time;
+        let client = self.connected.get_mut(&current).unwrap();
+        match client.challenge_path(addr, time) {
+            Some(probe) => {
+                let seq = client.seq_send(time);
+                Packet::encode_probe::<C>(self.protocol, buffer, seq, client.send_key.key(), probe, false).unwrap()
+            }
+            None => 0,
+        }
+    }
+
+    /// Adds the new connection, the keep-alive response is written into `buffer`.
+    fn accept(&mut self, buffer: &mut [u8], addr: SocketAddr, client_id: u64, user: [u8; USER], keys: &KeyPair) -> usize {
+        let conn = Conn::new(client_id, addr, self.time, keys, user, self.resume_window);
+
+        self.events.push_back(ServerEvent::Accepted {
+            connection: Connection {
+                closed: conn.closed.clone(),
+                send_ch: self.send_ch.clone(),
+                waker: self.waker.clone(),
+                addr: conn.addr_shared.clone(),
+                id: client_id,
+                mtu: keys.mtu(),
+                path_mtu: conn.path_mtu_shared.clone(),
+                stats: conn.stats_shared.clone(),
+                pool: self.pool.clone(),
+            },
+            user,
+        });
+
+        self.connected_by_id.insert(client_id, addr);
+        self.connected.insert(addr, conn);
+
+        // Respond with a connection keep-alive packet.
+        Packet::encode_keep_alive::<C>(self.protocol, buffer, 0u64, keys.send_key()).unwrap()
     }
 
-    fn process_packet<F>(&mut self, mut buffer: &mut [u8], addr: SocketAddr, callback: &mut F) -> Result<usize, ConnectionError>
-        where F: FnMut(Connection, &[u8; USER])
-    {
-        match Packet::decode(buffer).ok_or(InvalidPacket)? {
+    /// Processes the first `len` bytes of `buffer`, the response is written into `buffer`.
+    fn process_packet(&mut self, mut buffer: &mut [u8], len: usize, addr: SocketAddr) -> Result<usize, ConnectionError> {
+        let mtu = len.min(self.mtu);
+        let prefix = buffer[..len].first().cloned().unwrap_or(0);
+        let packet = match Packet::decode(&mut buffer[..len]) {
+            Ok(packet) => packet,
+            Err(error) if Packet::is_handshake(prefix) => return Err(Rejected(error.into())),
+            Err(_) => return Err(InvalidPacket),
+        };
+        match packet {
             Packet::Request(request) => {
-                let (expire, token) = self.incoming.open_request(request).map_err(|_| InvalidPacket)?;
-                let list = ServerList::deserialize(token.data()).map_err(|_| InvalidPacket)?;
-                if !list.contains(&self.local_addr) { return Err(InvalidPacket); }
+                if self.incoming.count_request() && !self.incoming.check_cookie(request.cookie(), &addr) {
+                    let cookie = self.incoming.gen_cookie(&addr);
+                    return Ok(Packet::encode_cookie(buffer, &cookie));
+                }
+                let (expire, token) = self.incoming.open_request(&request).map_err(Rejected)?;
+                let list = ServerList::deserialize(token.data()).map_err(|()| Rejected(HandshakeError::BadServerList))?;
+                if !list.contains(&self.local_addr) { return Err(Rejected(HandshakeError::NotInServerList)); }
                 if self.is_already_connected(addr, token.client_id()) { return Err(AlreadyConnected); }
                 if !self.incoming.add_token_history(*token.hmac(), addr, expire) { return Err(TokenAlreadyUsed); }
 
-                if !self.can_connect() { return Err(ConnectionDenied(*token.server_key())); }
+                if self.shutdown { return Err(ConnectionDenied(*token.server_key(), DenyReason::ShuttingDown)); }
+                if !self.can_connect() { return Err(ConnectionDenied(*token.server_key(), DenyReason::ServerFull)); }
 
-                self.incoming.insert(addr, expire, &token);
+                self.incoming.insert(addr, expire, mtu, &token);
                 let seq = self.global_sequence.fetch_add(1, Ordering::Relaxed);
-                let token = token.clone();
                 Ok(self.incoming.gen_challenge(seq, buffer, &token))
             }
             Packet::Handshake { prefix, seq, buf, tag } => {
-                let (send_key, token) = self.incoming.open_response(buf, &addr, seq, prefix, tag).map_err(|_| InvalidPacket)?;
+                let (send_key, token) = self.incoming.open_response(buf, &addr, seq, prefix, tag).map_err(Rejected)?;
 
                 if self.is_already_connected(addr, token.client_id()) { return Err(AlreadyConnected); }
-                if !self.can_connect() { return Err(ConnectionDenied(send_key)); }
+                if self.shutdown { return Err(ConnectionDenied(send_key, DenyReason::ShuttingDown)); }
+                if !self.can_connect() { return Err(ConnectionDenied(send_key, DenyReason::ServerFull)); }
                 let keys = self.incoming.remove(&addr).unwrap();
-
-                // Respond with a connection keep-alive packet.
-                let key = keys.send_key();
-                let client_id = token.client_id();
-
-                let (recv_queue, recv_ch) = unbounded();
-                let conn = Conn::new(client_id, self.time, &keys, recv_queue);
-
-                callback(Connection {
-                    closed: conn.closed.clone(),
-                    recv_ch,
-                    send_ch: self.send_ch.clone(),
-                    addr,
-                    id: client_id,
-                }, token.user());
-
-                self.connected_by_id.insert(client_id, addr);
-                self.connected.insert(addr, conn);
-
-                let len = Packet::encode_keep_alive(self.protocol, &mut buffer, 0u64, &key).unwrap();
-
-                Ok(len)
+                let (client_id, user) = (token.client_id(), *token.user());
+                Ok(self.accept(buffer, addr, client_id, user, &keys))
             }
-            Packet::Close { prefix, seq, tag } => {
-                //unimplemented!("close packet: {} {} {:?} {:?}", prefix, seq, buf, tag)
-
-                if let Some(client) = self.connected.get_mut(&addr) {
-                    if client.process_disconnect(self.protocol, prefix, seq, tag) {
-                        let client = self.connected.remove(&addr).unwrap();
-                        self.connected_by_id.remove(&client.id).expect("client_id not saved");
-                    }
+            Packet::Resume(resume) => {
+                let (keys, token) = self.incoming.open_resume(&resume, mtu).map_err(Rejected)?;
+                let (client_id, user) = (token.client_id(), *token.user());
+                // the old connection of the session is replaced, e.g. after the client's link dropped
+                let old = self.connected_by_id.get(&client_id).cloned();
+                if self.connected.contains_key(&addr) && old != Some(addr) { return Err(AlreadyConnected); }
+                if !self.incoming.add_ticket_history(*token.hmac(), token.expire()) { return Err(TokenAlreadyUsed); }
+
+                if self.shutdown { return Err(ConnectionDenied(*keys.send_key(), DenyReason::ShuttingDown)); }
+                if let Some(old) = old {
+                    self.connected.remove(&old);
+                    self.connected_by_id.remove(&client_id);
+                    self.events.push_back(ServerEvent::Disconnected {
+                        id: client_id,
+                        addr: old,
+                        reason: DisconnectReason::Resumed,
+                    });
+                }
+                if !self.can_connect() { return Err(ConnectionDenied(*keys.send_key(), DenyReason::ServerFull)); }
+                Ok(self.accept(buffer, addr, client_id, user, &keys))
+            }
+            Packet::Ticket { .. } | Packet::Cookie(_) => Err(InvalidPacket),
+            Packet::Close { prefix, seq, buf, tag } => {
+                let current = self.find_client(addr, prefix, seq, buf, tag).ok_or(InvalidPacket)?;
+                let client = self.connected.get_mut(&current).unwrap();
+                if client.process_disconnect(self.protocol, prefix, seq, buf, tag) {
+                    let client = self.connected.remove(&current).unwrap();
+                    self.connected_by_id.remove(&client.id).expect("client_id not saved");
+                    self.events.push_back(ServerEvent::Disconnected {
+                        id: client.id,
+                        addr: current,
+                        reason: DisconnectReason::ClientClosed,
+                    });
                 }
                 Ok(0)
             }
             Packet::Payload { seq, buf, tag } => {
-                if let Some(client) = self.connected.get_mut(&addr) {
-                    client.process_payload(self.protocol, seq, buf, tag, self.time);
+                let current = self.find_client(addr, 0, seq, buf, tag).ok_or(InvalidPacket)?;
+                let client = self.connected.get_mut(&current).unwrap();
+                let m = client.process_payload(self.protocol, seq, buf, tag, self.time).ok_or(InvalidPacket)?;
+                client.stats.on_recv(len);
+                // keep-alive is an empty payload
+                if !m.is_empty() {
+                    let data = self.pool.copy_from(m);
+                    self.events.push_back(ServerEvent::Payload { id: client.id, addr: current, data });
+                }
+                if current != addr {
+                    Ok(self.challenge_path(buffer, current, addr))
+                } else {
+                    Ok(0)
+                }
+            }
+            Packet::Probe { prefix, ack, seq, buf, tag } => {
+                let current = self.find_client(addr, prefix, seq, buf, tag).ok_or(InvalidPacket)?;
+                let client = self.connected.get_mut(&current).unwrap();
+                let probe = client.process_probe(self.protocol, prefix, seq, buf, tag, self.time)
+                    .ok_or(InvalidPacket)?;
+                client.stats.on_recv(len);
+                if current != addr {
+                    if ack && client.is_path_response(addr, probe) {
+                        self.migrate(current, addr);
+                        Ok(0)
+                    } else {
+                        Ok(self.challenge_path(buffer, current, addr))
+                    }
+                } else if ack {
+                    client.ack_probe(probe, self.time);
+                    Ok(0)
+                } else {
+                    let seq = client.seq_send(self.time);
+                    let loss = encode_loss(client.replay_protection.loss());
+                    let probe = Probe { loss, .. probe };
+                    Ok(Packet::encode_probe::<C>(self.protocol, &mut buffer, seq, client.send_key.key(), probe, true).unwrap())
                 }
-                Ok(0)
             }
         }
     }
 }
-
-/*
-enum ServerEvent {
-    Accept {
-        id: u64,
-        // TODO
-    },
-    Denied {
-        addr: SocketAddr,
-        send_key: [u8; KEY]
-    },
-    Disconnect {
-    },
-}
-*/
@@ -1,139 +1,301 @@
-use specs::prelude::*;
-use shrev::{EventChannel, EventIterator, ReaderId};
-use crossbeam::queue::SegQueue;
-
-use std::net::{SocketAddr, UdpSocket};
-use std::sync::Arc;
-
-pub struct OniConnection {
-    /// The buffer of events that have been received.
-    recv_buffer: SegQueue<OniNetEvent>,
-    /// The buffer of events to be sent.
-    send_buffer: SegQueue<OniNetEvent>,
+//! Server with a dedicated network I/O thread.
+//!
+//! The thread owns the socket: it receives and decrypts packets,
+//! encrypts queued payloads and sends keep-alive and probe packets.
+//! Game logic gets `ServerEvent`s through a channel,
+//! `Connection` handles work the same way as with `Server`.
+//!
+//! Between updates the thread blocks in `poll` on the socket and a self-pipe
+//! (eventfd on Linux), until a datagram arrives, a connection queues a packet or the next deadline.
+//! Sockets without a file descriptor, e.g. `SimulatedSocket`, and all sockets off unix
+//! are checked every `POLL_INTERVAL`.
+
+use crossbeam_channel::{Sender, Receiver, unbounded, after};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use crate::{
+    Socket,
+    crypto::{KEY, Aead, DefaultAead},
+    server::{Server, ServerEvent},
+};
+
+enum Command {
+    SetMaxClients(usize),
+    Shutdown,
+}
+
+pub struct ThreadedServer<S: Socket = UdpSocket, C: Aead = DefaultAead> {
+    local_addr: SocketAddr,
+    events: Receiver<ServerEvent>,
+    commands: Sender<Command>,
+    num_clients: Arc<AtomicUsize>,
+    waker: Arc<Waker>,
+    thread: Option<JoinHandle<Server<S, C>>>,
+}
+
+impl ThreadedServer<UdpSocket> {
+    pub fn new(protocol: u64, private: [u8; KEY], addr: SocketAddr) -> io::Result<Self> {
+        Self::spawn(Server::new(protocol, private, addr)?)
+    }
 }
 
-impl Component for OniConnection {
-    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+impl<S: Socket, C: Aead> ThreadedServer<S, C> {
+    /// Maximum time between updates of the I/O thread, if the socket can't be polled.
+    pub const POLL_INTERVAL: Duration = Duration::from_millis(1);
+    /// Maximum time the I/O thread blocks on the socket without a deadline.
+    pub const MAX_WAIT: Duration = Duration::from_secs(1);
 }
 
-impl OniConnection {
-    fn new() -> Self {
+/// Wakes the I/O thread blocked on the socket.
+///
+/// Connections call `wake` after they queue a packet,
+/// it costs a write to the self-pipe only if the thread is parked.
+pub(crate) struct Waker {
+    parked: AtomicBool,
+    /// Read and write ends of the self-pipe, the same eventfd on Linux,
+    /// or `-1` until the I/O thread is started.
+    fds: [AtomicI32; 2],
+}
+
+impl Default for Waker {
+    fn default() -> Self {
         Self {
-            recv_buffer: SegQueue::new(),
-            send_buffer: SegQueue::new(),
+            parked: AtomicBool::new(false),
+            fds: [AtomicI32::new(-1), AtomicI32::new(-1)],
         }
     }
 }
 
-struct RawEvent {
+impl Waker {
+    pub(crate) fn wake(&self) {
+        if self.parked.swap(false, Ordering::SeqCst) {
+            self.notify();
+        }
+    }
+
+    /// Marks the thread as parked, it must check its queues after that.
+    fn park(&self) {
+        self.parked.store(true, Ordering::SeqCst);
+    }
+
+    fn unpark(&self) {
+        self.parked.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns the read end, creates the pipe on the first call from the I/O thread.
+    #[cfg(unix)]
+    fn fd(&self) -> Option<i32> {
+        let fd = self.fds[0].load(Ordering::SeqCst);
+        if fd >= 0 {
+            return Some(fd);
+        }
+        let (read, write) = pipe()?;
+        self.fds[1].store(write, Ordering::SeqCst);
+        self.fds[0].store(read, Ordering::SeqCst);
+        Some(read)
+    }
+
+    #[cfg(unix)]
+    fn notify(&self) {
+        let fd = self.fds[1].load(Ordering::SeqCst);
+        if fd >= 0 {
+            // eventfd takes exactly 8 bytes
+            let one = 1u64.to_ne_bytes();
+            unsafe { libc::write(fd, one.as_ptr() as *const _, one.len()); }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn notify(&self) {}
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub enum OniNetEvent {
-    //
+impl Drop for Waker {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            let (read, write) = (*self.fds[0].get_mut(), *self.fds[1].get_mut());
+            if read >= 0 {
+                unsafe { libc::close(read); }
+            }
+            if write >= 0 && write != read {
+                unsafe { libc::close(write); }
+            }
+        }
+    }
 }
 
-/// The state of the connection.
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub enum ConnectionState {
-    /// The connection is established.
-    Connected,
-    /// The connection is being established.
-    Connecting,
-    /// The connection has been dropped.
-    Disconnected,
+#[cfg(target_os = "linux")]
+fn pipe() -> Option<(i32, i32)> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return None;
+    }
+    Some((fd, fd))
 }
 
-struct Channel<A, B> {
-    tx: SegQueue<A>,
-    rx: SegQueue<B>,
+#[cfg(all(unix, not(target_os = "linux")))]
+fn pipe() -> Option<(i32, i32)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return None;
+    }
+    for &fd in &fds {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK);
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    Some((fds[0], fds[1]))
 }
 
-pub struct OniServerSystem {
-    ch: Arc<Channel<RawEvent, (usize, SocketAddr)>>,
-    //removed_reader_id: ReaderId<RemovedFlag>,
+/// Blocks until the socket is readable, the waker is notified or `timeout`.
+///
+/// Returns `false` if the socket can't be polled.
+#[cfg(unix)]
+fn wait<S: Socket>(socket: &S, waker: &Waker, timeout: Duration) -> bool {
+    let (socket, event) = match (socket.raw_fd(), waker.fd()) {
+        (Some(socket), Some(event)) => (socket, event),
+        _ => return false,
+    };
+    let mut fds = [
+        libc::pollfd { fd: socket, events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: event, events: libc::POLLIN, revents: 0 },
+    ];
+    // round up, otherwise it spins during the last millisecond
+    let ms = (timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos() + 999_999) / 1_000_000)
+        .min(i32::max_value() as u64) as i32;
+    unsafe {
+        libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, ms);
+        if fds[1].revents & libc::POLLIN != 0 {
+            // drains the pipe, eventfd is reset by one read
+            let mut buf = [0u8; 64];
+            libc::read(event, buf.as_mut_ptr() as *mut _, buf.len());
+        }
+    }
+    true
 }
 
-impl OniServerSystem {
-    /// Creates a `OniSystem` and binds the Socket on the ip and port added in parameters.
-    pub fn new(addr: SocketAddr) -> std::io::Result<Self> {
-        let socket = Arc::new(UdpSocket::bind(addr)?);
-        socket.set_nonblocking(true)?;
+#[cfg(not(unix))]
+fn wait<S: Socket>(_socket: &S, _waker: &Waker, _timeout: Duration) -> bool {
+    false
+}
 
-        let ch = Arc::new(Channel {
-            tx: SegQueue::new(),
-            rx: SegQueue::new(),
-        });
+impl<S: Socket + Send + 'static, C: Aead + 'static> ThreadedServer<S, C> {
+    /// Moves `server` to the new I/O thread.
+    pub fn spawn(server: Server<S, C>) -> io::Result<Self> {
+        let local_addr = server.local_addr();
+        let (event_tx, events) = unbounded();
+        let (commands, command_rx) = unbounded();
+        let num_clients = Arc::new(AtomicUsize::new(server.num_clients()));
+        let waker = server.waker().clone();
 
-        let chan = ch.clone();
+        let thread = {
+            let num_clients = num_clients.clone();
+            thread::Builder::new()
+                .name(format!("oni server {}", local_addr))
+                .spawn(move || run(server, event_tx, command_rx, num_clients))?
+        };
 
-        std::thread::spawn(move || {
-            while let Some(packet) = chan.tx.try_pop() {
-                //
-            }
+        Ok(Self {
+            local_addr,
+            events,
+            commands,
+            num_clients,
+            waker,
+            thread: Some(thread),
+        })
+    }
 
-            let mut buffer = [0u8; crate::protocol::MTU];
-            while let Ok((len, addr)) = socket.recv_from(&mut buffer) {
-                // TODO
-                chan.rx.push((len, addr));
-            }
-        });
+    pub fn local_addr(&self) -> SocketAddr { self.local_addr }
 
-        Ok(Self { ch })
-    }
-}
+    /// Number of connected clients at the last update of the I/O thread.
+    pub fn num_clients(&self) -> usize { self.num_clients.load(Ordering::Relaxed) }
 
-impl<'a> System<'a> for OniServerSystem {
-    type SystemData = (WriteStorage<'a, OniConnection>);
+    /// Sets maximum number of connected clients, zero means unlimited.
+    pub fn set_max_clients(&self, max: usize) {
+        self.commands.send(Command::SetMaxClients(max));
+        self.waker.wake();
+    }
 
-    fn setup(&mut self, res: &mut Resources) {
-        Self::SystemData::setup(res);
+    /// Returns the next event, if any.
+    pub fn poll_event(&self) -> Option<ServerEvent> {
+        self.events.try_recv()
     }
 
-    fn run(&mut self, mut connections: Self::SystemData) {
-        //for mut connection in (&mut connections).join() {
+    /// Blocks until the next event or timeout.
+    pub fn wait_event(&self, timeout: Duration) -> Option<ServerEvent> {
+        let timer = after(timeout);
+        select! {
+            recv(self.events, event) => event,
+            recv(timer, _) => None,
+        }
+    }
 
-        /*
-        for mut connection in (&mut net_connections).join() {
-            let target = net_connection.target.clone();
+    /// Shuts the server down and waits for the I/O thread.
+    ///
+    /// See `Server::shutdown`, events produced by it are still available by `poll_event`.
+    /// Returns the server if the I/O thread didn't panic.
+    pub fn shutdown(&mut self) -> Option<Server<S, C>> {
+        let thread = self.thread.take()?;
+        self.commands.send(Command::Shutdown);
+        self.waker.wake();
+        thread.join().ok()
+    }
+}
 
-            if net_connection.state == ConnectionState::Connected
-                || net_connection.state == ConnectionState::Connecting
-            {
-                self.tx
-                    .send(InternalSocketEvent::SendEvents {
-                        target,
-                        events: net_connection.send_buffer_early_read().cloned().collect(),
-                    }).unwrap();
-            } else if net_connection.state == ConnectionState::Disconnected {
-                self.tx.send(InternalSocketEvent::Stop).unwrap();
-            }
+impl<S: Socket, C: Aead> Drop for ThreadedServer<S, C> {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.commands.send(Command::Shutdown);
+            self.waker.wake();
+            let _ = thread.join();
         }
+    }
+}
 
-        for raw_event in self.rx.try_iter() {
-            let mut matched = false;
-            // Get the NetConnection from the source
-            for mut net_connection in (&mut net_connections).join() {
-                // We found the origin
-                if net_connection.target == raw_event.source {
-                    matched = true;
-                    // Get the event
-                    let net_event = deserialize_event::<E>(raw_event.data.as_slice());
-                    match net_event {
-                        Ok(ev) => {
-                            net_connection.receive_buffer.single_write(ev);
-                        }
-                        Err(e) => error!(
-                            "Failed to deserialize an incoming network event: {} From source: {:?}",
-                            e, raw_event.source
-                        ),
+fn run<S: Socket, C: Aead>(
+    mut server: Server<S, C>,
+    events: Sender<ServerEvent>,
+    commands: Receiver<Command>,
+    num_clients: Arc<AtomicUsize>,
+) -> Server<S, C> {
+    loop {
+        while let Some(command) = commands.try_recv() {
+            match command {
+                Command::SetMaxClients(max) => server.set_max_clients(max),
+                Command::Shutdown => {
+                    server.shutdown();
+                    while let Some(event) = server.poll_event() {
+                        events.send(event);
                     }
-                }
-                if !matched {
-                    println!("Received packet from unknown source");
+                    num_clients.store(0, Ordering::Relaxed);
+                    return server;
                 }
             }
         }
-        */
+
+        server.update();
+        while let Some(event) = server.poll_event() {
+            events.send(event);
+        }
+        num_clients.store(server.num_clients(), Ordering::Relaxed);
+
+        // packets queued after `park` notify the waker, so they are either seen here or wake `poll`
+        let waker = server.waker().clone();
+        waker.park();
+        let now = Instant::now();
+        let deadline = server.next_deadline()
+            .map_or(ThreadedServer::<S>::MAX_WAIT, |deadline| deadline.saturating_duration_since(now))
+            .min(ThreadedServer::<S>::MAX_WAIT);
+        if deadline > Duration::from_secs(0) && commands.is_empty() && !wait(server.socket(), &waker, deadline) {
+            thread::sleep(deadline.min(ThreadedServer::<S>::POLL_INTERVAL));
+        }
+        waker.unpark();
     }
 }
@@ -0,0 +1,185 @@
+//! Per-connection network statistics.
+//!
+//! RTT is measured with probe packets: every probe carries an id
+//! and the peer echoes it back in the ack.
+//! If path MTU discovery is done, zero-sized probes are sent as pings.
+//!
+//! Incoming loss is estimated from gaps in `ReplayProtection`,
+//! outgoing loss is reported by the peer in every probe.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Snapshot of connection quality.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    /// Smoothed round-trip time.
+    pub rtt: Duration,
+    /// Mean deviation of round-trip time.
+    pub jitter: Duration,
+    /// Fraction of incoming packets lost, in `0.0..=1.0`.
+    pub recv_loss: f32,
+    /// Fraction of outgoing packets lost, in `0.0..=1.0`.
+    pub send_loss: f32,
+    /// Incoming bytes per second.
+    pub recv_bandwidth: u64,
+    /// Outgoing bytes per second.
+    pub send_bandwidth: u64,
+}
+
+pub struct StatsTracker {
+    stats: Stats,
+    has_rtt: bool,
+
+    next_id: u16,
+    pings: VecDeque<(u16, Instant)>,
+    last_ping: Option<Instant>,
+
+    window_start: Instant,
+    recv_bytes: u64,
+    send_bytes: u64,
+}
+
+impl StatsTracker {
+    pub const PING_INTERVAL: Duration = Duration::from_millis(500);
+    pub const BANDWIDTH_WINDOW: Duration = Duration::from_secs(1);
+    pub const MAX_PINGS: usize = 16;
+
+    pub fn new(now: Instant) -> Self {
+        Self {
+            stats: Stats::default(),
+            has_rtt: false,
+
+            next_id: 0,
+            pings: VecDeque::with_capacity(Self::MAX_PINGS),
+            last_ping: None,
+
+            window_start: now,
+            recv_bytes: 0,
+            send_bytes: 0,
+        }
+    }
+
+    pub fn stats(&self) -> Stats { self.stats }
+
+    /// Returns `true` if it's time to send a ping.
+    pub fn need_ping(&self, now: Instant) -> bool {
+        self.last_ping.map_or(true, |last| last + Self::PING_INTERVAL <= now)
+    }
+
+    /// Returns time of the next ping.
+    pub fn ping_deadline(&self, now: Instant) -> Instant {
+        self.last_ping.map_or(now, |last| last + Self::PING_INTERVAL)
+    }
+
+    /// Remembers sent probe, returns its id.
+    pub fn ping(&mut self, now: Instant) -> u16 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        if self.pings.len() >= Self::MAX_PINGS {
+            self.pings.pop_front();
+        }
+        self.pings.push_back((id, now));
+        self.last_ping = Some(now);
+        id
+    }
+
+    /// Processes acknowledged probe.
+    pub fn pong(&mut self, id: u16, now: Instant) {
+        let pos = match self.pings.iter().position(|&(p, _)| p == id) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let sent = self.pings[pos].1;
+        // older pings are lost or reordered
+        self.pings.drain(..=pos);
+
+        // see RFC 6298
+        let sample = now - sent;
+        if self.has_rtt {
+            let rtt = self.stats.rtt;
+            let diff = if rtt > sample { rtt - sample } else { sample - rtt };
+            self.stats.jitter = self.stats.jitter * 3 / 4 + diff / 4;
+            self.stats.rtt = rtt * 7 / 8 + sample / 8;
+        } else {
+            self.has_rtt = true;
+            self.stats.rtt = sample;
+            self.stats.jitter = sample / 2;
+        }
+    }
+
+    pub fn on_recv(&mut self, len: usize) { self.recv_bytes += len as u64; }
+    pub fn on_send(&mut self, len: usize) { self.send_bytes += len as u64; }
+
+    /// Sets outgoing loss reported by the peer.
+    pub fn set_send_loss(&mut self, loss: u16) {
+        self.stats.send_loss = decode_loss(loss);
+    }
+
+    pub fn update(&mut self, now: Instant, recv_loss: f32) {
+        self.stats.recv_loss = recv_loss;
+
+        let elapsed = now - self.window_start;
+        if elapsed >= Self::BANDWIDTH_WINDOW {
+            let ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+            self.stats.recv_bandwidth = self.recv_bytes * 1000 / ms;
+            self.stats.send_bandwidth = self.send_bytes * 1000 / ms;
+            self.recv_bytes = 0;
+            self.send_bytes = 0;
+            self.window_start = now;
+        }
+    }
+}
+
+/// Encodes loss for probe packet.
+pub fn encode_loss(loss: f32) -> u16 {
+    (loss.max(0.0).min(1.0) * f32::from(u16::max_value())) as u16
+}
+
+fn decode_loss(loss: u16) -> f32 {
+    f32::from(loss) / f32::from(u16::max_value())
+}
+
+#[test]
+fn stats_tracker() {
+    let mut now = Instant::now();
+    let mut tracker = StatsTracker::new(now);
+    let rtt = Duration::from_millis(40);
+
+    assert!(tracker.need_ping(now));
+    let id = tracker.ping(now);
+    assert!(!tracker.need_ping(now));
+    tracker.pong(id, now + rtt);
+    assert_eq!(tracker.stats().rtt, rtt);
+    assert_eq!(tracker.stats().jitter, rtt / 2);
+
+    // unknown and duplicate acks are ignored
+    tracker.pong(id, now + rtt * 10);
+    tracker.pong(id.wrapping_add(100), now + rtt * 10);
+    assert_eq!(tracker.stats().rtt, rtt);
+
+    for _ in 0..50 {
+        now += StatsTracker::PING_INTERVAL;
+        assert!(tracker.need_ping(now));
+        let id = tracker.ping(now);
+        tracker.pong(id, now + rtt);
+    }
+    assert_eq!(tracker.stats().rtt, rtt);
+    assert!(tracker.stats().jitter < Duration::from_millis(1));
+
+    tracker.set_send_loss(encode_loss(0.25));
+    assert!((tracker.stats().send_loss - 0.25).abs() < 0.001);
+
+    let start = now;
+    tracker.update(start, 0.0);
+    tracker.on_send(3000);
+    tracker.on_recv(1500);
+    tracker.update(start + Duration::from_millis(500), 0.5);
+    assert_eq!(tracker.stats().send_bandwidth, 0);
+    assert_eq!(tracker.stats().recv_loss, 0.5);
+    tracker.update(start + Duration::from_secs(2), 0.0);
+    assert_eq!(tracker.stats().send_bandwidth, 1500);
+    assert_eq!(tracker.stats().recv_bandwidth, 750);
+}
@@ -1,15 +1,14 @@
+#![deny(unsafe_code)]
+
 use byteorder::{LE, ByteOrder};
-use std::{
-    slice::{from_raw_parts, from_raw_parts_mut},
-    mem::size_of,
-};
 use crate::{
-    protocol::{VERSION, VERSION_LEN},
+    protocol::{VERSION, VERSION_LEN, HandshakeError},
+    codec::{Reader, Writer, DecodeError, read_array},
     crypto::{
         keygen,
         nonce_from_u64,
-        seal, open,
-        xseal, xopen,
+        Aead,
+        DefaultAead,
         AutoNonce,
         KEY, HMAC, XNONCE,
     },
@@ -20,14 +19,25 @@ pub const DATA: usize = 624;
 pub const USER: usize = 256;
 
 pub const CHALLENGE_LEN: usize = 300;
+pub const RESUME_LEN: usize = 360;
+pub const TICKET_LEN: usize = 8 + KEY + KEY + 8 + RESUME_LEN;
 pub const PRIVATE_LEN: usize = 1024;
 pub const PUBLIC_LEN: usize = 2048;
 
 const CHALLENGE_RESERVED: usize = 20;
+const RESUME_RESERVED: usize = 4;
 const PRIVATE_RESERVED: usize = 52;
 const PUBLIC_RESERVED: usize = 268 - VERSION_LEN;
+const PUBLIC_PADDING: usize = PUBLIC_LEN - (VERSION_LEN + 8 + 8 + 8 + 4 + PUBLIC_RESERVED + XNONCE + KEY + KEY + PRIVATE_LEN + DATA);
+
+/// Splits sealed token into the ciphertext and the tag.
+fn split_tag(buf: &mut [u8]) -> (&mut [u8], [u8; HMAC]) {
+    let (c, t) = buf.split_at_mut(buf.len() - HMAC);
+    let mut tag = [0u8; HMAC];
+    tag.copy_from_slice(t);
+    (c, tag)
+}
 
-#[repr(C)]
 #[derive(Clone)]
 pub struct ChallengeToken {
     client_id: [u8; 8],
@@ -51,73 +61,245 @@ impl ChallengeToken {
     }
     pub fn user(&self) -> &[u8; USER] { &self.user }
 
-    pub fn encode_packet(mut self, seq: u64, k: &[u8; KEY]) -> [u8; 8+CHALLENGE_LEN] {
-        let mut buffer = [0u8; 8+CHALLENGE_LEN];
+    pub fn read(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(buf);
+        let token = Self {
+            client_id: read_array!(r, 8),
+            _reserved: read_array!(r, CHALLENGE_RESERVED),
+            user: read_array!(r, USER),
+            hmac: read_array!(r, HMAC),
+        };
+        r.finish()?;
+        Ok(token)
+    }
+
+    pub fn write(&self) -> [u8; CHALLENGE_LEN] {
+        let mut buf = [0u8; CHALLENGE_LEN];
+        Writer::new(&mut buf)
+            .put(&self.client_id)
+            .put(&self._reserved)
+            .put(&self.user)
+            .put(&self.hmac);
+        buf
+    }
+
+    pub fn encode_packet<C: Aead>(mut self, seq: u64, k: &[u8; KEY]) -> [u8; 8 + CHALLENGE_LEN] {
+        let mut buffer = [0u8; 8 + CHALLENGE_LEN];
         buffer[..8].copy_from_slice(&seq.to_le_bytes()[..]);
-        buffer[8..].copy_from_slice(self.seal(seq, k));
+        buffer[8..].copy_from_slice(&self.seal::<C>(seq, k));
         buffer
     }
 
-    pub fn decode_packet<'a>(buf: &'a mut [u8; 8 + CHALLENGE_LEN], k: &[u8; KEY]) -> Result<&'a Self, ()> {
-        let (seq, buf) = buf.split_at_mut(8);
-        let seq = LE::read_u64(seq);
-        let token = unsafe { &mut *(buf.as_mut_ptr() as *mut [u8; CHALLENGE_LEN]) };
-        ChallengeToken::open(token, seq, k)
+    pub fn decode_packet<C: Aead>(buf: &[u8; 8 + CHALLENGE_LEN], k: &[u8; KEY]) -> Result<Self, HandshakeError> {
+        let seq = LE::read_u64(&buf[..8]);
+        let mut token = [0u8; CHALLENGE_LEN];
+        token.copy_from_slice(&buf[8..]);
+        ChallengeToken::open::<C>(&mut token, seq, k)
     }
 
-    pub fn seal<'a>(&'a mut self, seq: u64, k: &[u8; KEY]) -> &'a mut [u8; CHALLENGE_LEN] {
-        assert_eq!(size_of::<Self>(), CHALLENGE_LEN);
-        let p: *mut Self = self;
-        let m = unsafe { from_raw_parts_mut(p as *mut u8, CHALLENGE_LEN-HMAC) };
-        self.hmac = seal(m, None, &nonce_from_u64(seq), k);
-        unsafe { &mut *(p as *mut [u8; CHALLENGE_LEN]) }
+    /// Returns the sealed token, `hmac` of `self` is updated.
+    pub fn seal<C: Aead>(&mut self, seq: u64, k: &[u8; KEY]) -> [u8; CHALLENGE_LEN] {
+        let mut buf = self.write();
+        let (m, _) = split_tag(&mut buf);
+        self.hmac = C::seal(m, None, &nonce_from_u64(seq), k);
+        buf[CHALLENGE_LEN - HMAC..].copy_from_slice(&self.hmac);
+        buf
     }
 
-    pub fn open<'a>(buf: &'a mut [u8; CHALLENGE_LEN], seq: u64, k: &[u8; KEY]) -> Result<&'a Self, ()> {
-        assert_eq!(size_of::<Self>(), CHALLENGE_LEN);
-        let (c, t) = &mut buf[..].split_at_mut(CHALLENGE_LEN-HMAC);
-        let t = unsafe { &*(t.as_ptr() as *const [u8; HMAC]) };
-        open(c, None, t, &nonce_from_u64(seq), k)?;
-        let buf: *mut [u8; CHALLENGE_LEN] = buf;
-        Ok(unsafe { &*(buf as *const Self) })
+    /// Opens the sealed token in place.
+    pub fn open<C: Aead>(buf: &mut [u8; CHALLENGE_LEN], seq: u64, k: &[u8; KEY]) -> Result<Self, HandshakeError> {
+        let (c, t) = split_tag(buf);
+        C::open(c, None, &t, &nonce_from_u64(seq), k).map_err(|()| HandshakeError::BadMac)?;
+        Ok(Self::read(&buf[..])?)
     }
 }
 
-#[repr(C)]
+/// Sealed by the server, the client presents it to resume the session.
 #[derive(Clone)]
-pub struct PrivateToken {
+pub struct ResumeToken {
     client_id: [u8; 8],
+    expire: [u8; 8],
     timeout: [u8; 4],
-    _reserved: [u8; PRIVATE_RESERVED],
+    _reserved: [u8; RESUME_RESERVED],
 
     client_key: [u8; KEY],
     server_key: [u8; KEY],
-    data: [u8; DATA],
     user: [u8; USER],
     hmac: [u8; HMAC],
 }
 
-#[repr(C)]
-struct PrivateAd {
-    _version: [u8; VERSION_LEN],
-    _protocol: [u8; 8],
-    _expire: [u8; 8],
+impl ResumeToken {
+    /// Generates new keys for the resumed session.
+    pub fn generate(client_id: u64, expire: u64, timeout: u32, user: [u8; USER]) -> Self {
+        Self {
+            client_id: client_id.to_le_bytes(),
+            expire: expire.to_le_bytes(),
+            timeout: timeout.to_le_bytes(),
+            _reserved: [0u8; RESUME_RESERVED],
+            client_key: keygen(),
+            server_key: keygen(),
+            user,
+            hmac: [0u8; HMAC],
+        }
+    }
+
+    pub fn hmac(&self) -> &[u8; HMAC] { &self.hmac }
+
+    pub fn client_id(&self) -> u64 {
+        u64::from_le_bytes(self.client_id)
+    }
+    pub fn expire(&self) -> u64 {
+        u64::from_le_bytes(self.expire)
+    }
+    pub fn timeout(&self) -> u32 {
+        u32::from_le_bytes(self.timeout)
+    }
+
+    pub fn client_key(&self) -> &[u8; KEY] { &self.client_key }
+    pub fn server_key(&self) -> &[u8; KEY] { &self.server_key }
+    pub fn user(&self) -> &[u8; USER] { &self.user }
+
+    pub fn read(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(buf);
+        let token = Self {
+            client_id: read_array!(r, 8),
+            expire: read_array!(r, 8),
+            timeout: read_array!(r, 4),
+            _reserved: read_array!(r, RESUME_RESERVED),
+            client_key: read_array!(r, KEY),
+            server_key: read_array!(r, KEY),
+            user: read_array!(r, USER),
+            hmac: read_array!(r, HMAC),
+        };
+        r.finish()?;
+        Ok(token)
+    }
+
+    pub fn write(&self) -> [u8; RESUME_LEN] {
+        let mut buf = [0u8; RESUME_LEN];
+        Writer::new(&mut buf)
+            .put(&self.client_id)
+            .put(&self.expire)
+            .put(&self.timeout)
+            .put(&self._reserved)
+            .put(&self.client_key)
+            .put(&self.server_key)
+            .put(&self.user)
+            .put(&self.hmac);
+        buf
+    }
+
+    pub fn encode_packet<C: Aead>(mut self, seq: u64, k: &[u8; KEY]) -> [u8; 8 + RESUME_LEN] {
+        let mut buffer = [0u8; 8 + RESUME_LEN];
+        buffer[..8].copy_from_slice(&seq.to_le_bytes()[..]);
+        buffer[8..].copy_from_slice(&self.seal::<C>(seq, k));
+        buffer
+    }
+
+    pub fn decode_packet<C: Aead>(buf: &[u8; 8 + RESUME_LEN], k: &[u8; KEY]) -> Result<Self, HandshakeError> {
+        let seq = LE::read_u64(&buf[..8]);
+        let mut token = [0u8; RESUME_LEN];
+        token.copy_from_slice(&buf[8..]);
+        ResumeToken::open::<C>(&mut token, seq, k)
+    }
+
+    /// Returns the sealed token, `hmac` of `self` is updated.
+    pub fn seal<C: Aead>(&mut self, seq: u64, k: &[u8; KEY]) -> [u8; RESUME_LEN] {
+        let mut buf = self.write();
+        let (m, _) = split_tag(&mut buf);
+        self.hmac = C::seal(m, None, &nonce_from_u64(seq), k);
+        buf[RESUME_LEN - HMAC..].copy_from_slice(&self.hmac);
+        buf
+    }
+
+    /// Opens the sealed token in place.
+    pub fn open<C: Aead>(buf: &mut [u8; RESUME_LEN], seq: u64, k: &[u8; KEY]) -> Result<Self, HandshakeError> {
+        let (c, t) = split_tag(buf);
+        C::open(c, None, &t, &nonce_from_u64(seq), k).map_err(|()| HandshakeError::BadMac)?;
+        Ok(Self::read(&buf[..])?)
+    }
 }
 
-impl PrivateAd {
-    fn new(protocol: u64, expire: u64) -> Self {
+/// Resumption ticket, the server sends it to the client over the connection.
+///
+/// Format:
+///
+/// ```txt
+/// [expire timestamp] u64
+/// [client to server key] (32 bytes)
+/// [server to client key] (32 bytes)
+/// [sequence] u64
+/// [encrypted resume token] (360 bytes)
+/// ```
+#[derive(Clone)]
+pub struct Ticket {
+    expire: [u8; 8],
+    client_key: [u8; KEY],
+    server_key: [u8; KEY],
+    token: [u8; 8 + RESUME_LEN],
+}
+
+impl Ticket {
+    pub fn new<C: Aead>(token: ResumeToken, seq: u64, k: &[u8; KEY]) -> Self {
         Self {
-            _version: VERSION,
-            _protocol: protocol.to_le_bytes(),
-            _expire: expire.to_le_bytes(),
+            expire: token.expire,
+            client_key: token.client_key,
+            server_key: token.server_key,
+            token: token.encode_packet::<C>(seq, k),
         }
     }
-    fn as_slice(&self) -> &[u8] {
-        let p: *const Self = self;
-        unsafe { from_raw_parts(p as *const u8, size_of::<Self>()) }
+
+    pub fn expire_timestamp(&self) -> u64 { u64::from_le_bytes(self.expire) }
+    pub fn client_key(&self) -> &[u8; KEY] { &self.client_key }
+    pub fn server_key(&self) -> &[u8; KEY] { &self.server_key }
+    pub fn token(&self) -> &[u8; 8 + RESUME_LEN] { &self.token }
+
+    pub fn read(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(buf);
+        let ticket = Self {
+            expire: read_array!(r, 8),
+            client_key: read_array!(r, KEY),
+            server_key: read_array!(r, KEY),
+            token: read_array!(r, 8 + RESUME_LEN),
+        };
+        r.finish()?;
+        Ok(ticket)
+    }
+
+    pub fn write(&self) -> [u8; TICKET_LEN] {
+        let mut buf = [0u8; TICKET_LEN];
+        Writer::new(&mut buf)
+            .put(&self.expire)
+            .put(&self.client_key)
+            .put(&self.server_key)
+            .put(&self.token);
+        buf
     }
 }
 
+#[derive(Clone)]
+pub struct PrivateToken {
+    client_id: [u8; 8],
+    timeout: [u8; 4],
+    _reserved: [u8; PRIVATE_RESERVED],
+
+    client_key: [u8; KEY],
+    server_key: [u8; KEY],
+    data: [u8; DATA],
+    user: [u8; USER],
+    hmac: [u8; HMAC],
+}
+
+/// Additional data of the private token.
+fn private_ad(protocol: u64, expire: u64) -> [u8; VERSION_LEN + 8 + 8] {
+    let mut ad = [0u8; VERSION_LEN + 8 + 8];
+    Writer::new(&mut ad)
+        .put(&VERSION)
+        .put(&protocol.to_le_bytes())
+        .put(&expire.to_le_bytes());
+    ad
+}
+
 impl PrivateToken {
     pub fn generate(client_id: u64, timeout: u32, data: [u8; DATA], user: [u8; USER]) -> Self {
         Self {
@@ -147,23 +329,52 @@ impl PrivateToken {
     pub fn data(&self) -> &[u8; DATA] { &self.data }
     pub fn user(&self) -> &[u8; USER] { &self.user }
 
-    pub fn seal<'a>(&'a mut self, protocol: u64, expire: u64, n: &[u8; XNONCE], k: &[u8; KEY]) -> &'a mut [u8; PRIVATE_LEN] {
-        assert_eq!(size_of::<Self>(), PRIVATE_LEN);
-        let ad = PrivateAd::new(protocol, expire);
-        let p: *mut Self = self;
-        let m = unsafe { from_raw_parts_mut(p as *mut u8, PRIVATE_LEN-HMAC) };
-        self.hmac = xseal(m, ad.as_slice(), &n, &k);
-        unsafe { &mut *(p as *mut [u8; PRIVATE_LEN]) }
+    pub fn read(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(buf);
+        let token = Self {
+            client_id: read_array!(r, 8),
+            timeout: read_array!(r, 4),
+            _reserved: read_array!(r, PRIVATE_RESERVED),
+            client_key: read_array!(r, KEY),
+            server_key: read_array!(r, KEY),
+            data: read_array!(r, DATA),
+            user: read_array!(r, USER),
+            hmac: read_array!(r, HMAC),
+        };
+        r.finish()?;
+        Ok(token)
+    }
+
+    pub fn write(&self) -> [u8; PRIVATE_LEN] {
+        let mut buf = [0u8; PRIVATE_LEN];
+        Writer::new(&mut buf)
+            .put(&self.client_id)
+            .put(&self.timeout)
+            .put(&self._reserved)
+            .put(&self.client_key)
+            .put(&self.server_key)
+            .put(&self.data)
+            .put(&self.user)
+            .put(&self.hmac);
+        buf
     }
 
-    pub fn open<'a>(buf: &'a mut [u8; PRIVATE_LEN], protocol: u64, expire: u64, n: &[u8; XNONCE], k: &[u8; KEY]) -> Result<&'a Self, ()> {
-        assert_eq!(size_of::<Self>(), PRIVATE_LEN);
-        let ad = PrivateAd::new(protocol, expire);
-        let (c, t) = &mut buf[..].split_at_mut(PRIVATE_LEN-HMAC);
-        let t = unsafe { &*(t.as_ptr() as *const [u8; HMAC]) };
-        xopen(c, ad.as_slice(), t, &n, &k)?;
-        let buf: *mut [u8; PRIVATE_LEN] = buf;
-        Ok(unsafe { &*(buf as *const Self) })
+    /// Returns the sealed token, `hmac` of `self` is updated.
+    pub fn seal<C: Aead>(&mut self, protocol: u64, expire: u64, n: &[u8; XNONCE], k: &[u8; KEY]) -> [u8; PRIVATE_LEN] {
+        let ad = private_ad(protocol, expire);
+        let mut buf = self.write();
+        let (m, _) = split_tag(&mut buf);
+        self.hmac = C::xseal(m, &ad, n, k);
+        buf[PRIVATE_LEN - HMAC..].copy_from_slice(&self.hmac);
+        buf
+    }
+
+    /// Opens the sealed token in place.
+    pub fn open<C: Aead>(buf: &mut [u8; PRIVATE_LEN], protocol: u64, expire: u64, n: &[u8; XNONCE], k: &[u8; KEY]) -> Result<Self, HandshakeError> {
+        let ad = private_ad(protocol, expire);
+        let (c, t) = split_tag(buf);
+        C::xopen(c, &ad, &t, n, k).map_err(|()| HandshakeError::BadMac)?;
+        Ok(Self::read(&buf[..])?)
     }
 }
 
@@ -180,9 +391,9 @@ impl PrivateToken {
 /// [client to server key] (32 bytes)
 /// [server to client key] (32 bytes)
 /// [encrypted private token] (1024 bytes)
-/// [open data] (640 bytes)
+/// [open data] (624 bytes)
+/// [padding] (16 bytes)
 /// ```
-#[repr(C)]
 #[derive(Clone)]
 pub struct PublicToken {
     version: [u8; VERSION_LEN],
@@ -216,15 +427,47 @@ impl PublicToken {
         self.version == VERSION
     }
 
-    pub fn as_slice(&self) -> &[u8] {
-        unsafe {
-            let p: *const Self = self;
-            from_raw_parts(p as *const u8, size_of::<Self>())
-        }
+    /// Reads token of exactly `PUBLIC_LEN` bytes, as received from the relay.
+    pub fn read(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(buf);
+        let token = Self {
+            version: read_array!(r, VERSION_LEN),
+            protocol: read_array!(r, 8),
+            create: read_array!(r, 8),
+            expire: read_array!(r, 8),
+            timeout: read_array!(r, 4),
+            _reserved: read_array!(r, PUBLIC_RESERVED),
+
+            nonce: read_array!(r, XNONCE),
+            client_key: read_array!(r, KEY),
+            server_key: read_array!(r, KEY),
+            token: read_array!(r, PRIVATE_LEN),
+            data: read_array!(r, DATA),
+        };
+        r.take(PUBLIC_PADDING)?;
+        r.finish()?;
+        Ok(token)
+    }
+
+    pub fn write(&self) -> [u8; PUBLIC_LEN] {
+        let mut buf = [0u8; PUBLIC_LEN];
+        Writer::new(&mut buf)
+            .put(&self.version)
+            .put(&self.protocol)
+            .put(&self.create)
+            .put(&self.expire)
+            .put(&self.timeout)
+            .put(&self._reserved)
+            .put(&self.nonce)
+            .put(&self.client_key)
+            .put(&self.server_key)
+            .put(&self.token)
+            .put(&self.data);
+        buf
     }
 
     pub fn into_vec(self) -> Vec<u8> {
-        self.as_slice().to_owned()
+        self.write().to_vec()
     }
 
     pub fn generate(
@@ -235,6 +478,20 @@ impl PublicToken {
         client_id: u64,
         protocol: u64,
         private_key: &[u8; KEY],
+    ) -> Self {
+        Self::generate_with::<DefaultAead>(data, user, expire, timeout, client_id, protocol, private_key)
+    }
+
+    /// Generates token with the private token sealed by `C`,
+    /// the server must use the same `Aead`.
+    pub fn generate_with<C: Aead>(
+        data: [u8; DATA],
+        user: [u8; USER],
+        expire: u32, // in seconds
+        timeout: u32, // in seconds
+        client_id: u64,
+        protocol: u64,
+        private_key: &[u8; KEY],
     ) -> Self {
         let nonce = AutoNonce::generate().0;
 
@@ -245,7 +502,7 @@ impl PublicToken {
         let client_key = *token.client_key();
         let server_key = *token.server_key();
 
-        let token = PrivateToken::seal(&mut token, protocol, expire, &nonce, private_key).clone();
+        let token = token.seal::<C>(protocol, expire, &nonce, private_key);
 
         Self {
             version: VERSION,
@@ -275,14 +532,47 @@ fn challenge_token() {
     crypto_random(&mut user[..]);
 
     let tok = &mut ChallengeToken::new(client_id, user);
-    let tok = ChallengeToken::seal(tok, seq, &key);
-    let tok = ChallengeToken::open(tok, seq, &key).unwrap();
+    let mut buf = ChallengeToken::seal::<DefaultAead>(tok, seq, &key);
+    let tok = ChallengeToken::open::<DefaultAead>(&mut buf, seq, &key).unwrap();
 
     assert_eq!(tok.client_id(), client_id);
     assert_eq!(&tok.user()[..], &user[..]);
     assert_eq!(tok._reserved, [0u8; CHALLENGE_RESERVED]);
 }
 
+#[test]
+fn resume_ticket() {
+    use crate::crypto::crypto_random;
+
+    let client_id = 0x1122334455667788;
+    let seq = 0x1122334455667799;
+    let key = keygen();
+    let mut user = [0u8; USER];
+    crypto_random(&mut user[..]);
+
+    let tok = ResumeToken::generate(client_id, 672345, 5, user);
+    let client_key = tok.client_key;
+    let server_key = tok.server_key;
+
+    let ticket = Ticket::read(&Ticket::new::<DefaultAead>(tok, seq, &key).write()).unwrap();
+    assert_eq!(ticket.expire_timestamp(), 672345);
+    assert_eq!(ticket.client_key(), &client_key);
+    assert_eq!(ticket.server_key(), &server_key);
+
+    let mut buf = *ticket.token();
+    let tok = ResumeToken::decode_packet::<DefaultAead>(&mut buf, &key).unwrap();
+    assert_eq!(tok.client_id(), client_id);
+    assert_eq!(tok.expire(), 672345);
+    assert_eq!(tok.timeout(), 5);
+    assert_eq!(tok.client_key(), &client_key);
+    assert_eq!(tok.server_key(), &server_key);
+    assert_eq!(&tok.user()[..], &user[..]);
+
+    let mut buf = *ticket.token();
+    buf[20] ^= 1;
+    assert!(ResumeToken::decode_packet::<DefaultAead>(&mut buf, &key).is_err());
+}
+
 #[test]
 fn private_token() {
     use crate::crypto::crypto_random;
@@ -304,8 +594,8 @@ fn private_token() {
     let client_key = tok.client_key;
     let server_key = tok.server_key;
 
-    let tok = PrivateToken::seal(tok, protocol, expire, &n, &k);
-    let tok = PrivateToken::open(tok, protocol, expire, &n, &k).unwrap();
+    let mut buf = PrivateToken::seal::<DefaultAead>(tok, protocol, expire, &n, &k);
+    let tok = PrivateToken::open::<DefaultAead>(&mut buf, protocol, expire, &n, &k).unwrap();
 
     assert_eq!(tok.client_id(), client_id);
     assert_eq!(tok.timeout(), timeout);
@@ -316,3 +606,23 @@ fn private_token() {
     assert_eq!(&tok.user[..], &user[..]);
     assert_eq!(&tok._reserved[..], &[0u8; PRIVATE_RESERVED][..]);
 }
+
+#[test]
+fn public_token() {
+    let key = keygen();
+    let tok = PublicToken::generate([1u8; DATA], [2u8; USER], 30, 5, 42, 0x1122, &key);
+    let buf = tok.write();
+    assert_eq!(tok.clone().into_vec(), &buf[..]);
+
+    let tok = PublicToken::read(&buf).unwrap();
+    assert!(tok.check_version());
+    assert_eq!(tok.protocol_id(), 0x1122);
+    assert_eq!(tok.timeout_seconds(), 5);
+    assert_eq!(tok.expire_timestamp(), tok.create_timestamp() + 30);
+    assert_eq!(&tok.data()[..], &[1u8; DATA][..]);
+    assert_eq!(tok.write()[..], buf[..]);
+
+    assert_eq!(PublicToken::read(&buf[..PUBLIC_LEN - 1]).err(), Some(DecodeError::TooShort));
+    assert_eq!(PublicToken::read(&[0u8; PUBLIC_LEN + 1]).err(), Some(DecodeError::TooLong));
+    assert_eq!(Ticket::read(&[0u8; TICKET_LEN - 1]).err(), Some(DecodeError::TooShort));
+}
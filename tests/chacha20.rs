@@ -276,3 +276,34 @@ b"c89ed3bfddb6b2b7594def12bd579475a64cbfe0448e1085c1e50042127e57c08fda71743f4816
 b"42424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242424242",
 b"52b3a5a693791b57124d9f5de16233868593b68571822a414660e8d881962e0c90c0260445dde84b568095479bc940e0f750de939c540cfb8992c1aae0127e0c48cac1357b95fd0cba8eeef2a869fb94df1481d6e8775fbfe7fd07dd486cddaaa563bad017bb86c4fd6325de2a7f0dde1eb0b865c4176442194488750ec4ed799efdff89c1fc27c46c97804cec1801665f28d0982f88d85729a010d5b75e655a",
 ];
+
+#[test]
+fn multi_block() {
+    // long messages go through the multi-block version, compare them with single blocks
+    let mut key = [0u8; ChaCha20::KEYBYTES];
+    let mut nonce = [0u8; ChaCha20::NONCEBYTES];
+    crypto_random(&mut key);
+    crypto_random(&mut nonce);
+
+    for &ic in &[0u64, 1, 0xffff_fff0, 0xffff_fffe] {
+        let mut m = vec![0u8; 1200];
+        crypto_random(&mut m);
+
+        for &len in &[255, 256, 257, 511, 512, 513, 767, 768, 1024, 1200] {
+            let mut out = vec![0u8; len];
+            ChaCha20::stream_xor(out.as_mut_ptr(), m.as_ptr(), len as u64, nonce, ic, &key);
+
+            let mut expect = vec![0u8; len];
+            for (i, chunk) in expect.chunks_mut(64).enumerate() {
+                let m = &m[i * 64..];
+                ChaCha20::stream_xor(chunk.as_mut_ptr(), m.as_ptr(), chunk.len() as u64, nonce, ic + i as u64, &key);
+            }
+            assert_eq!(out, expect, "Failed with length {} and counter {}", len, ic);
+
+            // inplace
+            let mut out = m[..len].to_vec();
+            ChaCha20::stream_xor(out.as_mut_ptr(), out.as_ptr(), len as u64, nonce, ic, &key);
+            assert_eq!(out, expect, "Failed inplace with length {} and counter {}", len, ic);
+        }
+    }
+}
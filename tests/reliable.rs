@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+
+use oni::{
+    protocol::MAX_PAYLOAD,
+    token::{PublicToken, USER},
+    crypto::keygen,
+    Server, ServerEvent,
+    Client, State,
+    ServerList,
+    Reliable,
+    SimulatorConfig,
+    config_socket,
+};
+
+#[test]
+fn reliable_over_lossy_connection() {
+    const PROTOCOL_ID: u64 =  0x1122334455667788;
+    const DELTA_TIME: Duration = Duration::from_millis(1000 / 60);
+    const MESSAGES: usize = 50;
+
+    let private_key = keygen();
+    let mut server = Server::simulated(PROTOCOL_ID, private_key);
+
+    let mut server_list = ServerList::new();
+    server_list.push(server.local_addr()).unwrap();
+
+    let token = PublicToken::generate(
+        server_list.serialize().unwrap(), [0u8; USER],
+        30, 5, 777, PROTOCOL_ID, &private_key,
+    );
+
+    let mut client = Client::simulated(PROTOCOL_ID, &token);
+    client.connect(server.local_addr()).unwrap();
+
+    config_socket(client.local_addr().unwrap(), server.local_addr(), Some(SimulatorConfig {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(10),
+        loss: 25.0,
+    }));
+
+    let mut client_channel = Reliable::new(Duration::from_millis(50));
+    let mut server_channel = Reliable::new(Duration::from_millis(50));
+
+    for i in 0..MESSAGES {
+        client_channel.send(format!("client message {}", i).as_bytes()).unwrap();
+        server_channel.send(format!("server message {}", i).as_bytes()).unwrap();
+    }
+
+    let mut connection = None;
+    let mut client_received = Vec::new();
+    let mut server_received = Vec::new();
+    let mut buf = [0u8; MAX_PAYLOAD];
+
+    for _ in 0..60 * 20 {
+        std::thread::sleep(DELTA_TIME);
+        let now = Instant::now();
+
+        client.update();
+        match client.state() {
+            State::Connected => {
+                while let Some(payload) = client.recv() {
+                    let _ = client_channel.read(&payload);
+                }
+                while let Some(m) = client_channel.recv() {
+                    client_received.push(String::from_utf8(m).unwrap());
+                }
+                let len = client_channel.write(now, &mut buf).unwrap();
+                client.send(&mut buf[..len]).unwrap();
+            }
+            State::Failed(err) => panic!("client error state: {:?}", err),
+            _ => (),
+        }
+
+        server.update();
+        while let Some(event) = server.poll_event() {
+            match event {
+                ServerEvent::Accepted { connection: c, .. } => connection = Some(c),
+                ServerEvent::Payload { data, .. } => {
+                    let _ = server_channel.read(&data);
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(conn) = connection.as_ref() {
+            while let Some(m) = server_channel.recv() {
+                server_received.push(String::from_utf8(m).unwrap());
+            }
+            let len = server_channel.write(now, &mut buf).unwrap();
+            conn.send(&buf[..len]).unwrap();
+        }
+
+        if client_received.len() == MESSAGES && server_received.len() == MESSAGES {
+            break;
+        }
+    }
+
+    let expected = |side| (0..MESSAGES)
+        .map(|i| format!("{} message {}", side, i))
+        .collect::<Vec<_>>();
+
+    assert_eq!(client_received, expected("server"));
+    assert_eq!(server_received, expected("client"));
+}
@@ -1,55 +1,86 @@
-use std::time::Duration;
+use std::{net::SocketAddr, time::Duration};
 
 use oni::{
     protocol::MAX_PAYLOAD,
-    token::{PublicToken, USER},
-    crypto::keygen,
-    Server,
-    Client, State,
-    ServerList,
+    token::{PublicToken, USER, DATA},
+    crypto::{keygen, KEY},
+    Server, Connection, ServerEvent, DisconnectReason, DenyReason, CloseReason,
+    Client, State, Error,
+    ServerList, SimulatedSocket,
 };
 
-#[test]
-fn client_server() {
-    const CONNECT_TOKEN_EXPIRY: u32 = 30;
-    const CONNECT_TOKEN_TIMEOUT: u32 = 5;
-    const PROTOCOL_ID: u64 =  0x1122334455667788;
-    const DELTA_TIME: Duration = Duration::from_millis(1000 / 60);
+const PROTOCOL_ID: u64 =  0x1122334455667788;
+const DELTA_TIME: Duration = Duration::from_millis(1000 / 60);
 
-    println!("[client/server]");
+/// Serializes the list of server addresses for tokens.
+fn server_list(addrs: &[SocketAddr]) -> [u8; DATA] {
+    let mut list = ServerList::new();
+    for &addr in addrs {
+        list.push(addr).unwrap();
+    }
+    list.serialize().unwrap()
+}
 
-    let (connect_token, mut server) = {
-        use std::io::Write;
+/// Generates the token of client `id` without user data.
+fn token(data: [u8; DATA], id: u64, private_key: &[u8; KEY]) -> PublicToken {
+    PublicToken::generate(data, [0u8; USER], 30, 5, id, PROTOCOL_ID, private_key)
+}
 
-        let private_key = keygen();
-        let client_id = 1345643;
+/// Creates the simulated server and the client `id` with the token for it.
+fn simulated(id: u64) -> (Server<SimulatedSocket>, Client<SimulatedSocket>, [u8; KEY]) {
+    let private_key = keygen();
+    let server = Server::simulated(PROTOCOL_ID, private_key);
+    let token = token(server_list(&[server.local_addr()]), id, &private_key);
+    (server, Client::simulated(PROTOCOL_ID, &token), private_key)
+}
 
-        let server = Server::simulated(PROTOCOL_ID, private_key);
+/// Calls `step` every `DELTA_TIME` until it returns `true`, at most `frames` times.
+fn run(frames: usize, mut step: impl FnMut() -> bool) {
+    for _ in 0..frames {
+        std::thread::sleep(DELTA_TIME);
+        if step() {
+            break;
+        }
+    }
+}
 
-        let mut server_list = ServerList::new();
-        server_list.push(server.local_addr()).unwrap();
+/// Connects the client and returns the accepted connection.
+fn connect(client: &mut Client<SimulatedSocket>, server: &mut Server<SimulatedSocket>) -> Connection {
+    client.connect(server.local_addr()).unwrap();
+    let mut connected = Vec::new();
+    run(100, || {
+        client.update();
+        server.update();
+        while let Some(event) = server.poll_event() {
+            if let ServerEvent::Accepted { connection, .. } = event {
+                connected.push(connection);
+            }
+        }
+        client.is_connected() && !connected.is_empty()
+    });
+    assert!(client.is_connected());
+    assert_eq!(connected.len(), 1);
+    connected.pop().unwrap()
+}
 
-        let data = server_list.serialize().unwrap();
-        let mut user = [0u8; USER];
-        (&mut user[..]).write(b"some user data\0").unwrap();
+#[test]
+fn client_server() {
+    use std::io::Write;
 
-        let connect_token = PublicToken::generate(
-            data, user,
-            CONNECT_TOKEN_EXPIRY,
-            CONNECT_TOKEN_TIMEOUT,
-            client_id,
-            PROTOCOL_ID,
-            &private_key,
-        );
+    println!("[client/server]");
 
-        (connect_token, server)
-    };
+    let private_key = keygen();
+    let mut server = Server::simulated(PROTOCOL_ID, private_key);
 
-    let mut client = {
-        let mut client = Client::simulated(PROTOCOL_ID, &connect_token);
-        client.connect(server.local_addr()).unwrap();
-        client
-    };
+    let mut user = [0u8; USER];
+    (&mut user[..]).write(b"some user data\0").unwrap();
+    let connect_token = PublicToken::generate(
+        server_list(&[server.local_addr()]), user,
+        30, 5, 1345643, PROTOCOL_ID, &private_key,
+    );
+
+    let mut client = Client::simulated(PROTOCOL_ID, &connect_token);
+    client.connect(server.local_addr()).unwrap();
 
     let mut server_num_packets_received = 0;
     let mut client_num_packets_received = 0;
@@ -62,11 +93,10 @@ fn client_server() {
     let ref_packet = &ref_packet[..];
 
     let mut connected = Vec::new();
+    let mut disconnected = false;
 
-    let mut buf = [0u8; MAX_PAYLOAD];
     println!("[start]");
-    loop {
-        std::thread::sleep(DELTA_TIME);
+    run(1000, || {
         println!(" - - - - - - client recv: {}, server recv: {}",
                  client_num_packets_received, server_num_packets_received);
 
@@ -77,32 +107,43 @@ fn client_server() {
                 let mut packet = [0u8; MAX_PAYLOAD];
                 packet[..].copy_from_slice(ref_packet);
                 client.send(&mut packet).unwrap();
-                while let Some((len, payload)) = client.recv() {
-                    assert_eq!(&payload[..len], ref_packet, "client packet");
+                while let Some(payload) = client.recv() {
+                    assert_eq!(&payload[..], ref_packet, "client packet");
                     client_num_packets_received += 1;
                 }
             }
             State::Failed(err) => panic!("client error state: {:?}", err),
-            State::Disconnected =>  {
+            State::Disconnected(reason) =>  {
+                assert_eq!(reason, None);
                 println!("client disconnected");
-                break;
+                return true;
             }
         }
 
-        server.update(|c, user| {
-            let user = unsafe { std::ffi::CStr::from_ptr(user.as_ptr() as *const _) };
-            println!("connected[{}] {:?} with data {:?}", c.id(), c.addr(), user);
-            connected.push(c);
-        });
+        server.update();
+        while let Some(event) = server.poll_event() {
+            match event {
+                ServerEvent::Accepted { connection: c, user } => {
+                    let user = unsafe { std::ffi::CStr::from_ptr(user.as_ptr() as *const _) };
+                    println!("connected[{}] {:?} with data {:?}", c.id(), c.addr(), user);
+                    connected.push(c);
+                }
+                ServerEvent::Payload { data, .. } => {
+                    assert_eq!(&data[..], ref_packet, "server packet");
+                    server_num_packets_received += 1;
+                }
+                ServerEvent::Disconnected { reason, .. } => {
+                    assert_eq!(reason, DisconnectReason::ServerClosed);
+                    disconnected = true;
+                }
+                ServerEvent::Denied { addr, reason } => panic!("denied {:?}: {:?}", addr, reason),
+                ServerEvent::Rejected { addr, error } => panic!("rejected {:?}: {:?}", addr, error),
+                ServerEvent::Migrated { from, to, .. } => panic!("migrated {:?} -> {:?}", from, to),
+            }
+        }
 
         if let Some(conn) = connected.get(0) {
             let _ = conn.send(ref_packet);
-            while let Ok(len) = conn.recv(&mut buf) {
-                if len == 0 { break; }
-                let payload = &buf[..len as usize];
-                assert_eq!(payload, ref_packet, "server packet");
-                server_num_packets_received += 1;
-            }
         }
 
         if client_num_packets_received >= 10 && server_num_packets_received >= 10 {
@@ -112,7 +153,839 @@ fn client_server() {
                 conn.close();
             }
         }
-    }
+        false
+    });
 
+    assert!(disconnected);
+    assert_eq!(client.state(), State::Disconnected(None));
     println!("shutting down");
 }
+
+#[test]
+fn small_mtu() {
+    use oni::protocol::{MIN_MTU, max_payload};
+
+    let (mut server, mut client, _) = simulated(1);
+    client.set_mtu(MIN_MTU + 20);
+    let conn = connect(&mut client, &mut server);
+
+    assert_eq!(client.state(), State::Connected);
+    assert_eq!(conn.mtu(), MIN_MTU + 20);
+    assert_eq!(conn.max_payload(), max_payload(MIN_MTU + 20));
+    assert!(conn.send(&[0; MAX_PAYLOAD]).is_err());
+    assert!(conn.send(&vec![0; conn.max_payload()]).is_ok());
+
+    let mut buf = conn.buffer();
+    buf.set_len(conn.max_payload() + 1);
+    assert!(conn.send_buffer(buf).is_err());
+    let mut buf = conn.buffer();
+    buf.set_len(conn.max_payload());
+    assert_eq!(conn.send_buffer(buf), Ok(conn.max_payload()));
+
+    assert!(client.send(&mut [0; MAX_PAYLOAD]).is_err());
+    assert!(client.send(&mut vec![0; client.max_payload()]).is_ok());
+
+    // path MTU discovery
+    run(30, || {
+        client.update();
+        server.update();
+        while server.poll_event().is_some() {}
+        false
+    });
+
+    assert_eq!(client.path_mtu(), MIN_MTU + 20);
+    assert_eq!(client.usable_payload(), client.max_payload());
+    assert_eq!(conn.path_mtu(), MIN_MTU + 20);
+    assert_eq!(conn.usable_payload(), conn.max_payload());
+}
+
+#[test]
+fn client_close() {
+    let (mut server, mut client, _) = simulated(42);
+    client.connect(server.local_addr()).unwrap();
+
+    let mut events = Vec::new();
+    run(100, || {
+        client.update();
+        if client.state() == State::Connected {
+            client.close();
+        }
+        server.update();
+        while let Some(event) = server.poll_event() {
+            events.push(event);
+        }
+        events.len() >= 2
+    });
+
+    assert_eq!(events.len(), 2, "unexpected events: {:?}", events);
+    match &events[0] {
+        ServerEvent::Accepted { connection, .. } => assert_eq!(connection.id(), 42),
+        event => panic!("unexpected event: {:?}", event),
+    }
+    match &events[1] {
+        ServerEvent::Disconnected { id, addr, reason } => {
+            assert_eq!(*id, 42);
+            assert_eq!(*addr, client.local_addr().unwrap());
+            assert_eq!(*reason, DisconnectReason::ClientClosed);
+        }
+        event => panic!("unexpected event: {:?}", event),
+    }
+}
+
+#[test]
+fn server_full() {
+    let (mut server, first, private_key) = simulated(1);
+    server.set_max_clients(1);
+
+    let second = token(server_list(&[server.local_addr()]), 2, &private_key);
+    let mut clients = vec![first, Client::simulated(PROTOCOL_ID, &second)];
+
+    clients[0].connect(server.local_addr()).unwrap();
+
+    let mut connected = Vec::new();
+    let mut denied = Vec::new();
+    run(100, || {
+        // the second client connects after the first one
+        if clients[0].is_connected() && clients[1].state() == State::Disconnected(None) {
+            clients[1].connect(server.local_addr()).unwrap();
+        }
+        for client in &mut clients {
+            client.update();
+        }
+
+        server.update();
+        while let Some(event) = server.poll_event() {
+            match event {
+                ServerEvent::Accepted { connection, .. } => connected.push(connection),
+                ServerEvent::Denied { reason, .. } => denied.push(reason),
+                _ => (),
+            }
+        }
+
+        if let State::Failed(_) = clients[1].state() { true } else { false }
+    });
+
+    assert_eq!(clients[0].state(), State::Connected);
+    assert_eq!(clients[1].state(), State::Failed(Error::ConnectionDenied(Some(DenyReason::ServerFull))));
+    assert_eq!(connected.len(), 1);
+    assert_eq!(connected[0].id(), 1);
+    assert!(!denied.is_empty());
+    assert!(denied.iter().all(|&r| r == DenyReason::ServerFull));
+    assert_eq!(server.num_clients(), 1);
+}
+
+#[test]
+fn client_failover() {
+    let private_key = keygen();
+    let mut server = Server::simulated(PROTOCOL_ID, private_key);
+
+    // nobody answers here
+    let dead = SimulatedSocket::new();
+
+    let token = PublicToken::generate(
+        server_list(&[dead.local_addr(), server.local_addr()]), [0u8; USER],
+        30, 1, 7, PROTOCOL_ID, &private_key,
+    );
+
+    let mut client = Client::simulated(PROTOCOL_ID, &token);
+    client.connect_list().unwrap();
+    assert_eq!(client.server_addr(), Some(dead.local_addr()));
+
+    run(200, || {
+        client.update();
+        server.update();
+        while server.poll_event().is_some() {}
+        client.is_connected()
+    });
+
+    assert_eq!(client.state(), State::Connected);
+    assert_eq!(client.server_addr(), Some(server.local_addr()));
+}
+
+#[test]
+fn connection_stats() {
+    let (mut server, mut client, _) = simulated(1);
+    client.connect(server.local_addr()).unwrap();
+
+    let mut connected = Vec::new();
+    run(150, || {
+        client.update();
+        if client.is_connected() {
+            client.send(&mut [0; 100]).unwrap();
+        }
+        server.update();
+        while let Some(event) = server.poll_event() {
+            if let ServerEvent::Accepted { connection, .. } = event {
+                connected.push(connection);
+            }
+        }
+        if let Some(conn) = connected.get(0) {
+            conn.send(&[0; 100]).unwrap();
+        }
+        false
+    });
+
+    let stats = client.stats();
+    println!("client: {:?}", stats);
+    assert!(stats.rtt > Duration::from_millis(0));
+    assert!(stats.recv_loss < 0.1 && stats.send_loss < 0.1);
+    assert!(stats.recv_bandwidth > 1000 && stats.send_bandwidth > 1000);
+
+    let stats = connected[0].stats();
+    println!("server: {:?}", stats);
+    assert!(stats.rtt > Duration::from_millis(0));
+    assert!(stats.recv_loss < 0.1 && stats.send_loss < 0.1);
+    assert!(stats.recv_bandwidth > 1000 && stats.send_bandwidth > 1000);
+}
+
+#[test]
+fn close_reason() {
+    let (mut server, mut client, _) = simulated(1);
+    client.connect(server.local_addr()).unwrap();
+
+    let mut disconnected = None;
+    run(100, || {
+        client.update();
+        server.update();
+        while let Some(event) = server.poll_event() {
+            match event {
+                ServerEvent::Accepted { connection, .. } =>
+                    connection.close_with_reason(CloseReason::Kicked, "too many pings"),
+                ServerEvent::Disconnected { reason, .. } => disconnected = Some(reason),
+                _ => (),
+            }
+        }
+        if let State::Disconnected(_) = client.state() { true } else { false }
+    });
+
+    assert_eq!(client.state(), State::Disconnected(Some(CloseReason::Kicked)));
+    assert_eq!(client.close_message(), Some("too many pings"));
+    assert_eq!(disconnected, Some(DisconnectReason::ServerClosed));
+}
+
+#[test]
+fn server_shutdown() {
+    let (mut server, first, private_key) = simulated(1);
+
+    let data = server_list(&[server.local_addr()]);
+    let mut clients = vec![first];
+    clients.extend((2..=3).map(|id| Client::simulated(PROTOCOL_ID, &token(data, id, &private_key))));
+
+    clients[0].connect(server.local_addr()).unwrap();
+    clients[1].connect(server.local_addr()).unwrap();
+
+    let mut connected = Vec::new();
+    run(100, || {
+        for client in &mut clients {
+            client.update();
+        }
+        server.update();
+        while let Some(event) = server.poll_event() {
+            if let ServerEvent::Accepted { connection, .. } = event {
+                connected.push(connection);
+            }
+        }
+        connected.len() == 2 && clients[0].is_connected() && clients[1].is_connected()
+    });
+    assert_eq!(connected.len(), 2);
+
+    // queued payload is flushed before the close packets
+    connected[0].send(b"bye").unwrap();
+    server.shutdown();
+    assert!(server.is_shutdown());
+    assert_eq!(server.num_clients(), 0);
+    assert!(connected.iter().all(|c| c.is_closed()));
+
+    let mut disconnected = 0;
+    while let Some(event) = server.poll_event() {
+        if let ServerEvent::Disconnected { reason, .. } = event {
+            assert_eq!(reason, DisconnectReason::ServerClosed);
+            disconnected += 1;
+        }
+    }
+    assert_eq!(disconnected, 2);
+
+    clients[2].connect(server.local_addr()).unwrap();
+    run(100, || {
+        for client in &mut clients {
+            client.update();
+        }
+        server.update();
+        if let State::Failed(_) = clients[2].state() { true } else { false }
+    });
+
+    assert_eq!(clients[0].recv().map(|data| data.to_vec()), Some(b"bye".to_vec()));
+    assert_eq!(clients[0].state(), State::Disconnected(Some(CloseReason::ServerShutdown)));
+    assert_eq!(clients[1].state(), State::Disconnected(Some(CloseReason::ServerShutdown)));
+    assert_eq!(clients[2].state(), State::Failed(Error::ConnectionDenied(Some(DenyReason::ShuttingDown))));
+}
+
+#[test]
+fn next_deadline() {
+    use std::time::Instant;
+    use oni::protocol::PACKET_SEND_DELTA;
+
+    // simulated sockets have no readiness notification
+    const MAX_WAIT: Duration = DELTA_TIME;
+
+    let (mut server, mut client, _) = simulated(1);
+    assert_eq!(client.next_deadline(), None);
+    assert_eq!(server.next_deadline(), None);
+
+    client.connect(server.local_addr()).unwrap();
+    assert!(client.next_deadline().unwrap() <= Instant::now() + PACKET_SEND_DELTA);
+
+    let mut connected = Vec::new();
+    for _ in 0..100 {
+        let now = Instant::now();
+        let deadline = client.next_deadline().into_iter()
+            .chain(server.next_deadline())
+            .min()
+            .unwrap_or(now + MAX_WAIT)
+            .min(now + MAX_WAIT);
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        }
+
+        client.update();
+        server.update();
+        while let Some(event) = server.poll_event() {
+            if let ServerEvent::Accepted { connection, .. } = event {
+                connected.push(connection);
+            }
+        }
+        if client.is_connected() && !connected.is_empty() {
+            break;
+        }
+    }
+
+    assert!(client.is_connected());
+    let now = Instant::now();
+    assert!(client.next_deadline().unwrap() <= now + PACKET_SEND_DELTA);
+    assert!(server.next_deadline().unwrap() <= now + PACKET_SEND_DELTA);
+
+    client.close();
+    assert_eq!(client.next_deadline(), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn raw_fd() {
+    use std::os::unix::io::AsRawFd;
+    use std::net::UdpSocket;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let fd = socket.as_raw_fd();
+    let server = Server::with_socket(PROTOCOL_ID, keygen(), socket).unwrap();
+    assert_eq!(server.as_raw_fd(), fd);
+}
+
+#[test]
+fn threaded_server() {
+    use oni::ThreadedServer;
+
+    let (server, mut client, _) = simulated(1);
+    let mut server = ThreadedServer::spawn(server).unwrap();
+    client.connect(server.local_addr()).unwrap();
+
+    let mut connected = Vec::new();
+    let mut received = Vec::new();
+    run(100, || {
+        client.update();
+        if client.is_connected() {
+            client.send(&mut [1, 2, 3]).unwrap();
+        }
+        while let Some(event) = server.poll_event() {
+            match event {
+                ServerEvent::Accepted { connection, .. } => {
+                    connection.send(b"hello").unwrap();
+                    connected.push(connection);
+                }
+                ServerEvent::Payload { data, .. } => received.push(data.to_vec()),
+                _ => (),
+            }
+        }
+        !received.is_empty() && client.recv().is_some()
+    });
+
+    assert_eq!(connected.len(), 1);
+    assert_eq!(server.num_clients(), 1);
+    assert_eq!(received[0], vec![1, 2, 3]);
+
+    assert!(server.shutdown().is_some());
+    assert!(connected[0].is_closed());
+    match server.wait_event(Duration::from_secs(1)) {
+        Some(ServerEvent::Disconnected { reason, .. }) => assert_eq!(reason, DisconnectReason::ServerClosed),
+        event => panic!("unexpected event: {:?}", event),
+    }
+
+    run(100, || {
+        client.update();
+        if let State::Disconnected(_) = client.state() { true } else { false }
+    });
+    assert_eq!(client.state(), State::Disconnected(Some(CloseReason::ServerShutdown)));
+}
+
+#[test]
+fn threaded_server_udp() {
+    use oni::ThreadedServer;
+    use std::time::Instant;
+
+    let private_key = keygen();
+    let mut server = ThreadedServer::new(PROTOCOL_ID, private_key, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let token = token(server_list(&[server.local_addr()]), 1, &private_key);
+    let mut client = Client::new(PROTOCOL_ID, &token, "127.0.0.1:0".parse().unwrap()).unwrap();
+    client.connect(server.local_addr()).unwrap();
+
+    let mut connection = None;
+    run(100, || {
+        client.update();
+        if let Some(ServerEvent::Accepted { connection: c, .. }) = server.poll_event() {
+            connection = Some(c);
+        }
+        connection.is_some() && client.is_connected()
+    });
+    let connection = connection.expect("not accepted");
+    assert!(client.is_connected());
+
+    // the blocked I/O thread wakes up for the datagram and for the queued echo,
+    // not at the keep-alive deadline
+    client.send(&mut [1, 2, 3]).unwrap();
+    let start = Instant::now();
+    match server.wait_event(Duration::from_secs(1)) {
+        Some(ServerEvent::Payload { data, .. }) => connection.send(&data).unwrap(),
+        event => panic!("unexpected event: {:?}", event),
+    };
+    let echo = loop {
+        client.update();
+        if let Some(echo) = client.recv() {
+            break echo;
+        }
+        assert!(start.elapsed() < Duration::from_secs(1), "no echo");
+        std::thread::sleep(Duration::from_millis(1));
+    };
+    assert_eq!(&echo[..], &[1, 2, 3]);
+    assert!(start.elapsed() < Duration::from_millis(50), "echo took {:?}", start.elapsed());
+
+    assert!(server.shutdown().is_some());
+}
+
+#[test]
+fn connection_migration() {
+    let (mut server, mut client, _) = simulated(1);
+    let connection = connect(&mut client, &mut server);
+    let from = client.local_addr().unwrap();
+    assert_eq!(connection.addr(), from);
+
+    // unauthenticated packets from unknown addresses are ignored
+    let attacker = SimulatedSocket::new();
+    attacker.send_to(&[0x20; 64], server.local_addr()).unwrap();
+
+    let socket = SimulatedSocket::new();
+    let to = socket.local_addr();
+    client.rebind(socket).unwrap();
+
+    let mut migrated = None;
+    let mut received = Vec::new();
+    run(100, || {
+        client.update();
+        client.send(&mut [1, 2, 3]).unwrap();
+        server.update();
+        while let Some(event) = server.poll_event() {
+            match event {
+                ServerEvent::Migrated { id, from, to } => migrated = Some((id, from, to)),
+                ServerEvent::Payload { addr, .. } => received.push((addr, migrated.is_some())),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+        if migrated.is_some() {
+            connection.send(b"hello").unwrap();
+        }
+        client.recv().is_some()
+    });
+
+    assert_eq!(migrated, Some((1, from, to)));
+    assert_eq!(connection.addr(), to);
+    assert_eq!(server.num_clients(), 1);
+    assert!(client.is_connected());
+    // payloads are delivered before the new address is confirmed
+    assert!(received.contains(&(from, false)));
+    assert!(received.iter().all(|&(addr, migrated)| addr == if migrated { to } else { from }));
+
+    let mut buf = [0u8; 1500];
+    assert!(attacker.recv_from(&mut buf).is_err());
+}
+
+#[test]
+fn migration_checks() {
+    let (mut server, mut client, _) = simulated(1);
+    let connection = connect(&mut client, &mut server);
+
+    // junk from one subnet doesn't take the checks of the migrated client
+    let attacker = SimulatedSocket::bind("[::3]:0".parse().unwrap()).unwrap();
+    let socket = SimulatedSocket::bind("[2001:db8::1]:0".parse().unwrap()).unwrap();
+    let to = socket.local_addr();
+    client.rebind(socket).unwrap();
+
+    run(100, || {
+        for _ in 0..Server::<SimulatedSocket>::MAX_MIGRATION_CHECKS * 2 {
+            attacker.send_to(&[0x20; 64], server.local_addr()).unwrap();
+        }
+        client.update();
+        client.send(&mut [1, 2, 3]).unwrap();
+        server.update();
+        while server.poll_event().is_some() {}
+        connection.addr() == to
+    });
+
+    assert_eq!(connection.addr(), to);
+}
+
+#[test]
+fn session_resumption() {
+    use std::io::Write;
+
+    let private_key = keygen();
+    let mut server = Server::simulated(PROTOCOL_ID, private_key);
+
+    let mut user = [0u8; USER];
+    (&mut user[..]).write(b"some user data").unwrap();
+
+    let token = PublicToken::generate(
+        server_list(&[server.local_addr()]), user,
+        30, 5, 1, PROTOCOL_ID, &private_key,
+    );
+
+    let mut client = Client::simulated(PROTOCOL_ID, &token);
+    assert!(client.resume().is_err());
+    client.connect(server.local_addr()).unwrap();
+
+    let mut accepted = 0;
+    run(100, || {
+        client.update();
+        server.update();
+        while let Some(event) = server.poll_event() {
+            if let ServerEvent::Accepted { .. } = event {
+                accepted += 1;
+            }
+        }
+        client.can_resume()
+    });
+    assert_eq!(accepted, 1);
+    assert!(client.can_resume());
+
+    client.close();
+    let mut disconnected = None;
+    run(10, || {
+        server.update();
+        if let Some(ServerEvent::Disconnected { reason, .. }) = server.poll_event() {
+            disconnected = Some(reason);
+        }
+        disconnected.is_some()
+    });
+    assert_eq!(disconnected, Some(DisconnectReason::ClientClosed));
+
+    client.resume().unwrap();
+    assert_eq!(client.state(), State::Connecting(oni::ConnectingState::SendingResume));
+
+    let mut connected = Vec::new();
+    let mut received = Vec::new();
+    run(100, || {
+        client.update();
+        if client.is_connected() {
+            client.send(&mut [1, 2, 3]).unwrap();
+        }
+        server.update();
+        while let Some(event) = server.poll_event() {
+            match event {
+                ServerEvent::Accepted { connection, user: resumed } => {
+                    assert_eq!(connection.id(), 1);
+                    assert_eq!(&resumed[..], &user[..]);
+                    connected.push(connection);
+                }
+                ServerEvent::Payload { data, .. } => received.push(data.to_vec()),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+        !received.is_empty()
+    });
+
+    assert_eq!(connected.len(), 1);
+    assert_eq!(received[0], vec![1, 2, 3]);
+    assert!(client.is_connected());
+}
+
+#[test]
+fn session_resumption_replaces() {
+    use oni::{SimulatorConfig, config_socket};
+
+    let (mut server, mut client, _) = simulated(1);
+    let old = connect(&mut client, &mut server);
+    run(100, || {
+        client.update();
+        server.update();
+        client.can_resume()
+    });
+    assert!(client.can_resume());
+
+    // the link drops, the server doesn't know about it
+    let from = client.local_addr().unwrap();
+    let lost = SimulatorConfig { loss: 100.0, .. SimulatorConfig::default() };
+    config_socket(from, server.local_addr(), Some(lost));
+    client.close();
+    client.rebind(SimulatedSocket::new()).unwrap();
+    let to = client.local_addr().unwrap();
+
+    client.resume().unwrap();
+    let mut events = Vec::new();
+    run(100, || {
+        client.update();
+        server.update();
+        while let Some(event) = server.poll_event() {
+            events.push(event);
+        }
+        client.is_connected() && events.len() >= 2
+    });
+
+    assert!(client.is_connected());
+    assert_eq!(events.len(), 2, "unexpected events: {:?}", events);
+    match &events[0] {
+        ServerEvent::Disconnected { id, addr, reason } => {
+            assert_eq!((*id, *addr, *reason), (1, from, DisconnectReason::Resumed));
+        }
+        event => panic!("unexpected event: {:?}", event),
+    }
+    match &events[1] {
+        ServerEvent::Accepted { connection, .. } => {
+            assert_eq!(connection.id(), 1);
+            assert_eq!(connection.addr(), to);
+        }
+        event => panic!("unexpected event: {:?}", event),
+    }
+    assert!(old.is_closed());
+    assert_eq!(server.num_clients(), 1);
+}
+
+#[test]
+fn handshake_cookie() {
+    use oni::protocol::{Request, COOKIE_LEN};
+
+    let (mut server, mut client, private_key) = simulated(1);
+    server.set_cookie_threshold(0);
+    connect(&mut client, &mut server);
+
+    // requests without the cookie get only the cookie
+    let token = token(server_list(&[server.local_addr()]), 2, &private_key);
+    let request = || Request::new(PROTOCOL_ID, token.expire_timestamp(), token.nonce(), *token.token());
+    let server_addr = server.local_addr();
+    let mut recv = |socket: &SimulatedSocket| {
+        let mut buf = [0u8; 1500];
+        let mut response = None;
+        run(100, || {
+            server.update();
+            response = socket.recv_from(&mut buf).ok().map(|(len, _)| buf[..len].to_vec());
+            response.is_some()
+        });
+        response.expect("no response")
+    };
+
+    let socket = SimulatedSocket::new();
+    socket.send_to(&request().write()[..], server_addr).unwrap();
+    let response = recv(&socket);
+    assert_eq!(response.len(), 1 + COOKIE_LEN);
+    assert_eq!(response[0], 0b0000_0011);
+    let mut cookie = [0u8; COOKIE_LEN];
+    cookie.copy_from_slice(&response[1..]);
+
+    // the cookie is bound to the address
+    let other = SimulatedSocket::new();
+    let mut req = request();
+    req.set_cookie(cookie);
+    other.send_to(&req.write()[..], server_addr).unwrap();
+    let response = recv(&other);
+    assert_eq!(response.len(), 1 + COOKIE_LEN);
+    assert_ne!(&response[1..], &cookie[..]);
+
+    // the request with the cookie gets the challenge
+    let mut req = request();
+    req.set_cookie(cookie);
+    socket.send_to(&req.write()[..], server_addr).unwrap();
+    let response = recv(&socket);
+    assert_ne!(response[0], 0b0000_0011);
+    assert!(response.len() > 1 + COOKIE_LEN);
+}
+
+#[test]
+fn handshake_rejected() {
+    use oni::{HandshakeError, DecodeError, protocol::{Request, MIN_MTU}};
+
+    let private_key = keygen();
+    let mut server = Server::simulated(PROTOCOL_ID, private_key);
+
+    let data = server_list(&[server.local_addr()]);
+    let other_data = server_list(&["[::1]:1".parse().unwrap()]);
+
+    let cases = [
+        (PublicToken::generate(data, [0u8; USER], 30, 5, 1, PROTOCOL_ID + 1, &private_key), HandshakeError::BadProtocol),
+        (PublicToken::generate(data, [0u8; USER], 0, 5, 2, PROTOCOL_ID, &private_key), HandshakeError::Expired),
+        (token(data, 3, &keygen()), HandshakeError::BadMac),
+        (token(other_data, 4, &private_key), HandshakeError::NotInServerList),
+    ];
+
+    let socket = SimulatedSocket::new();
+    let mut rejected = |packet: &[u8]| {
+        socket.send_to(packet, server.local_addr()).unwrap();
+        let mut rejected = None;
+        run(100, || {
+            server.update();
+            while let Some(event) = server.poll_event() {
+                match event {
+                    ServerEvent::Rejected { addr, error } => {
+                        assert_eq!(addr, socket.local_addr());
+                        rejected = Some(error);
+                    }
+                    event => panic!("unexpected event: {:?}", event),
+                }
+            }
+            rejected.is_some()
+        });
+        rejected
+    };
+
+    for (token, expected) in cases.iter() {
+        let request = Request::new(token.protocol_id(), token.expire_timestamp(), token.nonce(), *token.token());
+        assert_eq!(rejected(&request.write()[..]), Some(*expected));
+    }
+
+    // a client of other version sends requests of other length
+    let token = token(data, 5, &private_key);
+    let request = Request::new(PROTOCOL_ID, token.expire_timestamp(), token.nonce(), *token.token()).write();
+    assert_eq!(rejected(&request[..MIN_MTU - 1]), Some(HandshakeError::Decode(DecodeError::TooShort)));
+}
+
+#[test]
+fn ingress_filter() {
+    use oni::RateLimit;
+
+    let private_key = keygen();
+    let mut server = Server::simulated(PROTOCOL_ID, private_key);
+
+    // banned client can't connect
+    let socket = SimulatedSocket::bind("[::2]:0".parse().unwrap()).unwrap();
+    let ip = socket.local_addr().ip();
+    server.filter_mut().ban(ip, 128, std::time::Instant::now(), None);
+
+    let token = token(server_list(&[server.local_addr()]), 1, &private_key);
+    let mut client = Client::with_socket(PROTOCOL_ID, &token, socket).unwrap();
+    client.connect(server.local_addr()).unwrap();
+
+    run(20, || {
+        client.update();
+        server.update();
+        assert!(server.poll_event().is_none());
+        false
+    });
+    assert!(!client.is_connected());
+    assert!(server.filter().stats().banned > 0);
+
+    // unbanned one connects
+    assert!(server.filter_mut().unban(ip, 128));
+    run(100, || {
+        client.update();
+        server.update();
+        while server.poll_event().is_some() {}
+        client.is_connected()
+    });
+    assert!(client.is_connected());
+
+    // flood of garbage is limited
+    server.filter_mut().set_addr_limit(Some(RateLimit::new(10.0, 10.0)));
+    let attacker = SimulatedSocket::bind("[::3]:0".parse().unwrap()).unwrap();
+    for _ in 0..100 {
+        attacker.send_to(&[0x20; 64], server.local_addr()).unwrap();
+    }
+    run(10, || {
+        server.update();
+        false
+    });
+    let stats = server.filter().stats();
+    assert!(stats.addr_limited >= 80, "{:?}", stats);
+    assert_eq!(stats.subnet_limited, 0);
+}
+
+#[test]
+fn ingress_filter_full() {
+    use oni::RateLimit;
+
+    let (mut server, mut client, _) = simulated(1);
+    let _connection = connect(&mut client, &mut server);
+
+    // spoofed sources fill the table, the connected client isn't limited
+    server.filter_mut().set_addr_limit(Some(RateLimit::new(1.0, 10.0)));
+    server.filter_mut().set_max_buckets(4);
+
+    let mut spoofed = 0u16;
+    let mut received = 0;
+    run(60, || {
+        for _ in 0..10 {
+            spoofed += 1;
+            let addr = format!("[2001:db8::{:x}]:0", spoofed).parse().unwrap();
+            let attacker = SimulatedSocket::bind(addr).unwrap();
+            attacker.send_to(&[0x20; 64], server.local_addr()).unwrap();
+        }
+        client.update();
+        client.send(&mut [1, 2, 3]).unwrap();
+        server.update();
+        while let Some(event) = server.poll_event() {
+            if let ServerEvent::Payload { .. } = event {
+                received += 1;
+            }
+        }
+        false
+    });
+
+    assert!(client.is_connected());
+    assert!(received >= 50, "received {}", received);
+    let stats = server.filter().stats();
+    assert!(stats.overflow > 0, "{:?}", stats);
+}
+
+#[test]
+fn null_cipher() {
+    use oni::crypto::NullCipher;
+
+    let private_key = keygen();
+    let mut server = Server::<_, NullCipher>::with_aead(PROTOCOL_ID, private_key, SimulatedSocket::new()).unwrap();
+
+    let data = server_list(&[server.local_addr()]);
+    let token = PublicToken::generate_with::<NullCipher>(data, [0u8; USER], 30, 5, 1, PROTOCOL_ID, &private_key);
+
+    // clients with the other cipher can't connect
+    let other_token = PublicToken::generate_with::<NullCipher>(data, [0u8; USER], 30, 5, 2, PROTOCOL_ID, &private_key);
+    let mut other = Client::simulated(PROTOCOL_ID, &other_token);
+    other.connect(server.local_addr()).unwrap();
+
+    let mut client = Client::<_, NullCipher>::with_aead(PROTOCOL_ID, &token, SimulatedSocket::new()).unwrap();
+    client.connect(server.local_addr()).unwrap();
+
+    let mut received = Vec::new();
+    run(100, || {
+        other.update();
+        client.update();
+        if client.is_connected() {
+            client.send(&mut [1, 2, 3]).unwrap();
+        }
+        server.update();
+        while let Some(event) = server.poll_event() {
+            if let ServerEvent::Payload { data, .. } = event {
+                received.push(data.to_vec());
+            }
+        }
+        !received.is_empty()
+    });
+
+    assert_eq!(received[0], vec![1, 2, 3]);
+    assert!(!other.is_connected());
+    assert_eq!(server.num_clients(), 1);
+}
@@ -0,0 +1,148 @@
+use oni::crypto::verify::{verify_16, verify_32, verify, select_u64, cmov};
+use rand::{Rng, thread_rng};
+use std::time::Instant;
+
+#[test]
+fn compare() {
+    let mut x = [0u8; 32];
+    thread_rng().fill(&mut x[..]);
+
+    let mut a = [0u8; 16];
+    a.copy_from_slice(&x[..16]);
+    assert!(verify_16(&a, &a.clone()));
+    assert!(verify_32(&x, &x.clone()));
+    assert!(verify(&x[..], &x[..]));
+    assert!(verify(&[], &[]));
+    assert!(!verify(&x[..], &x[..31]));
+
+    for i in 0..32 {
+        for bit in 0..8 {
+            let mut y = x;
+            y[i] ^= 1 << bit;
+            assert!(!verify_32(&x, &y));
+            assert!(!verify(&x[..], &y[..]));
+            if i < 16 {
+                let mut b = a;
+                b[i] ^= 1 << bit;
+                assert!(!verify_16(&a, &b));
+            }
+        }
+    }
+}
+
+#[test]
+fn select() {
+    assert_eq!(select_u64(1, 0xDEAD, 0xBEEF), 0xDEAD);
+    assert_eq!(select_u64(0, 0xDEAD, 0xBEEF), 0xBEEF);
+    assert_eq!(select_u64(1, !0, 0), !0);
+    assert_eq!(select_u64(0, !0, 0), 0);
+
+    let src = [0xAAu8; 16];
+    let mut dst = [0x55u8; 16];
+    cmov(0, &mut dst, &src);
+    assert_eq!(dst, [0x55; 16]);
+    cmov(1, &mut dst, &src);
+    assert_eq!(dst, src);
+}
+
+// dudect-style leakage detection, see https://eprint.iacr.org/2016/1123.pdf
+//
+// Inputs of two classes are compared in random order:
+// equal to the secret and random, which differ mostly in the first byte.
+// Welch's t-test on the timings exceeds the threshold if the comparison leaks.
+//
+// Timing depends on the machine load, so these are ignored by default.
+// Run them offline with `cargo test --release --test verify -- --ignored`.
+
+const SAMPLES: usize = 1_000_000;
+const BATCH: usize = 8;
+const THRESHOLD: f64 = 10.0;
+
+/// Returns Welch's t-statistic of timings of `f` over both classes.
+fn leakage<F: Fn(&[u8], &[u8]) -> bool>(len: usize, f: F) -> f64 {
+    let mut rng = thread_rng();
+    let mut secret = vec![0u8; len];
+    rng.fill(&mut secret[..]);
+
+    let inputs: Vec<(bool, Vec<u8>)> = (0..1024).map(|_| {
+        let class = rng.gen::<bool>();
+        let mut input = secret.clone();
+        if class {
+            rng.fill(&mut input[..]);
+        }
+        (class, input)
+    }).collect();
+
+    let mut times = [Vec::with_capacity(SAMPLES), Vec::with_capacity(SAMPLES)];
+    let mut equal = 0;
+    for i in 0..SAMPLES {
+        let (class, input) = &inputs[rng.gen_range(0, inputs.len())];
+        let start = Instant::now();
+        for _ in 0..BATCH {
+            if f(&secret, input) {
+                equal += 1;
+            }
+        }
+        let time = start.elapsed().subsec_nanos();
+        // warm up
+        if i >= SAMPLES / 100 {
+            times[*class as usize].push(f64::from(time));
+        }
+    }
+    assert!(equal > 0);
+
+    // crop the long tail of interrupts and preemption
+    let mut all: Vec<f64> = times[0].iter().chain(times[1].iter()).cloned().collect();
+    all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let cutoff = all[all.len() * 9 / 10];
+
+    let stats: Vec<(f64, f64, f64)> = times.iter().map(|t| {
+        let t: Vec<f64> = t.iter().cloned().filter(|&v| v <= cutoff).collect();
+        let n = t.len() as f64;
+        let mean = t.iter().sum::<f64>() / n;
+        let var = t.iter().map(|v| (v - mean) * (v - mean)).sum::<f64>() / (n - 1.0);
+        (n, mean, var)
+    }).collect();
+    let (n0, m0, v0) = stats[0];
+    let (n1, m1, v1) = stats[1];
+    (m0 - m1) / (v0 / n0 + v1 / n1).sqrt()
+}
+
+#[test]
+#[ignore]
+fn dudect_verify_16() {
+    let t = leakage(16, |x, y| {
+        let (mut a, mut b) = ([0u8; 16], [0u8; 16]);
+        a.copy_from_slice(x);
+        b.copy_from_slice(y);
+        verify_16(&a, &b)
+    });
+    assert!(t.abs() < THRESHOLD, "verify_16 leaks: t = {}", t);
+}
+
+#[test]
+#[ignore]
+fn dudect_verify_32() {
+    let t = leakage(32, |x, y| {
+        let (mut a, mut b) = ([0u8; 32], [0u8; 32]);
+        a.copy_from_slice(x);
+        b.copy_from_slice(y);
+        verify_32(&a, &b)
+    });
+    assert!(t.abs() < THRESHOLD, "verify_32 leaks: t = {}", t);
+}
+
+#[test]
+#[ignore]
+fn dudect_verify_slice() {
+    let t = leakage(1024, verify);
+    assert!(t.abs() < THRESHOLD, "verify leaks: t = {}", t);
+}
+
+/// The test itself must catch an early-exit comparison.
+#[test]
+#[ignore]
+fn dudect_detects_leak() {
+    let t = leakage(1024, |x, y| x == y);
+    assert!(t.abs() >= THRESHOLD, "`==` is not detected: t = {}", t);
+}
@@ -1,15 +1,15 @@
 use oni::crypto::{KEY, XNONCE as NPUB, HMAC as TAG};
-use oni::crypto::{open, seal, AutoNonce};
+use oni::crypto::{Aead, DefaultAead, AutoNonce};
 
 
 fn xopen(c: &mut [u8], ad: &[u8], t: [u8; TAG], n: &[u8; NPUB], k: &[u8; KEY]) -> Result<(), ()> {
     let (n, k) = AutoNonce(*n).split(k);
-    open(c, Some(ad), &t, &n, &k)
+    DefaultAead::open(c, Some(ad), &t, &n, &k)
 }
 
 fn xseal(m: &mut [u8], ad: &[u8], n: &[u8; NPUB], k: &[u8; KEY]) -> [u8; TAG] {
     let (n, k) = AutoNonce(*n).split(k);
-    seal(m, Some(ad), &n, &k)
+    DefaultAead::seal(m, Some(ad), &n, &k)
 }
 
 #[test]